@@ -0,0 +1,41 @@
+// `Engine::Basic` against `Engine::Threaded` on the same loops, by name and linked.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use interpreter::link::link;
+use interpreter::{lang, Engine, Vm};
+
+const PROGRAMS: [(&str, &str); 3] = [
+    ("lt_loop", "i = 0; while i < 10000 { i += 1 } return i"),
+    ("fib", "n = 2000; a = 0; b = 1; while n > 0 { t = b; b = (a + b) % 1000000; a = t; n -= 1 } return a"),
+    ("sum_of_squares", "i = 0; s = 0; while i < 5000 { s = s + i * i; i += 1 } return s"),
+];
+
+const ENGINES: [(&str, Engine); 2] = [("basic", Engine::Basic), ("threaded", Engine::Threaded)];
+
+fn engines(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engines");
+    for (name, source) in PROGRAMS {
+        let code = lang::compile(source).unwrap();
+        let linked = link(&code);
+        for (engine_name, engine) in ENGINES {
+            group.bench_with_input(BenchmarkId::new(engine_name, name), &code, |b, code| {
+                b.iter(|| {
+                    let mut vm = Vm::with_engine(engine);
+                    vm.load_program(code.clone());
+                    vm.run().unwrap()
+                })
+            });
+            group.bench_with_input(BenchmarkId::new(engine_name, format!("{name}_slots")), &linked, |b, linked| {
+                b.iter(|| {
+                    let mut vm = Vm::with_engine(engine);
+                    vm.load_linked(linked.clone());
+                    vm.run().unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, engines);
+criterion_main!(benches);
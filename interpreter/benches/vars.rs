@@ -0,0 +1,36 @@
+// Name-based `Read`/`Write` against linked `ReadSlot`/`WriteSlot` on variable heavy loops.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use interpreter::link::link;
+use interpreter::{lang, Vm};
+
+const PROGRAMS: [(&str, &str); 2] = [
+    ("lt_loop", "i = 0; while i < 10000 { i += 1 } return i"),
+    ("fib", "n = 2000; a = 0; b = 1; while n > 0 { t = b; b = (a + b) % 1000000; a = t; n -= 1 } return a"),
+];
+
+fn vars(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vars");
+    for (name, source) in PROGRAMS {
+        let code = lang::compile(source).unwrap();
+        let linked = link(&code);
+        group.bench_with_input(BenchmarkId::new("names", name), &code, |b, code| {
+            b.iter(|| {
+                let mut vm = Vm::new();
+                vm.load_program(code.clone());
+                vm.run().unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("slots", name), &linked, |b, linked| {
+            b.iter(|| {
+                let mut vm = Vm::new();
+                vm.load_linked(linked.clone());
+                vm.run().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, vars);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+// Run with `cargo fuzz run programs` from the interpreter directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    interpreter::fuzz::check(data);
+});
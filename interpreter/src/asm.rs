@@ -0,0 +1,486 @@
+// Line-oriented assembly for interpreter programs.
+//
+//         load 0
+//         write i
+// loop:   read i          ; labels name the instruction that follows them
+//         load 3
+//         cmplt
+//         jumpif done
+//         read i
+//         load 1
+//         add
+//         write i
+//         jump loop
+// done:   read i
+//         return
+//
+// One instruction per line, `;` starts a comment. `load` takes an integer, `true`, `false` or a
+// double quoted string with `\"`, `\\`, `\n` and `\t` escapes. Jump and call operands are
+// either a label or a raw numeric offset. A jump to a label resumes execution at the labelled
+// instruction, the assembler takes care of the off-by-one between that and the stored `Offset`.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::value::{unescape, write_quoted};
+use crate::{offset_for_target, Instruction, Offset};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    MissingOperand,
+    UnexpectedOperand(String),
+    InvalidInteger(String),
+    InvalidName(String),
+    UnterminatedString,
+    InvalidEscape(char),
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    // The first instruction can't be a jump target, there is no `Offset` that resumes at 0.
+    LabelAtStart(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmErrorKind::UnknownMnemonic(name) => write!(f, "unknown mnemonic `{}`", name),
+            AsmErrorKind::MissingOperand => write!(f, "missing operand"),
+            AsmErrorKind::UnexpectedOperand(token) => write!(f, "unexpected operand `{}`", token),
+            AsmErrorKind::InvalidInteger(token) => write!(f, "invalid integer `{}`", token),
+            AsmErrorKind::InvalidName(token) => write!(f, "invalid name `{}`", token),
+            AsmErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            AsmErrorKind::InvalidEscape(ch) => write!(f, "invalid escape `\\{}`", ch),
+            AsmErrorKind::DuplicateLabel(label) => write!(f, "label `{}` is already defined", label),
+            AsmErrorKind::UndefinedLabel(label) => write!(f, "undefined label `{}`", label),
+            AsmErrorKind::LabelAtStart(label) => {
+                write!(f, "label `{}` marks the first instruction and can't be jumped to", label)
+            }
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+pub(crate) fn mnemonic(instruction: &Instruction) -> &'static str {
+    match instruction {
+        Instruction::Load(_) | Instruction::LoadBool(_) | Instruction::LoadStr(_) => "load",
+        Instruction::Read(_) => "read",
+        Instruction::Write(_) => "write",
+        Instruction::ReadSlot(_) => "readslot",
+        Instruction::WriteSlot(_) => "writeslot",
+        Instruction::Jump(_) => "jump",
+        Instruction::JumpIf(_) => "jumpif",
+        Instruction::Call(_) => "call",
+        Instruction::Ret => "ret",
+        Instruction::CallHost(..) => "callhost",
+        Instruction::CompareEQ => "cmpeq",
+        Instruction::CompareNE => "cmpne",
+        Instruction::CompareGT => "cmpgt",
+        Instruction::CompareLT => "cmplt",
+        Instruction::CompareLTE => "cmplte",
+        Instruction::CompareGTE => "cmpgte",
+        Instruction::Add => "add",
+        Instruction::Sub => "sub",
+        Instruction::Mul => "mul",
+        Instruction::Div => "div",
+        Instruction::Mod => "mod",
+        Instruction::Neg => "neg",
+        Instruction::And => "and",
+        Instruction::Or => "or",
+        Instruction::Xor => "xor",
+        Instruction::Not => "not",
+        Instruction::Shl => "shl",
+        Instruction::Shr => "shr",
+        Instruction::Dup => "dup",
+        Instruction::Swap => "swap",
+        Instruction::Pop => "pop",
+        Instruction::NewArray(_) => "newarray",
+        Instruction::Concat => "concat",
+        Instruction::Index => "index",
+        Instruction::Len => "len",
+        Instruction::Return => "return",
+    }
+}
+
+// Prints the assembly form, jump operands as raw offsets.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mnemonic = mnemonic(self);
+        match self {
+            Instruction::Load(value) => write!(f, "{} {}", mnemonic, value),
+            Instruction::LoadBool(value) => write!(f, "{} {}", mnemonic, value),
+            Instruction::LoadStr(text) => {
+                write!(f, "{} ", mnemonic)?;
+                write_quoted(f, text)
+            }
+            Instruction::NewArray(len) | Instruction::ReadSlot(len) | Instruction::WriteSlot(len) => {
+                write!(f, "{} {}", mnemonic, len)
+            }
+            Instruction::CallHost(name, argc) => write!(f, "{} {} {}", mnemonic, name, argc),
+            Instruction::Read(name) | Instruction::Write(name) => write!(f, "{} {}", mnemonic, name),
+            Instruction::Jump(offset) | Instruction::JumpIf(offset) | Instruction::Call(offset) => {
+                write!(f, "{} {}", mnemonic, offset)
+            }
+            _ => f.write_str(mnemonic),
+        }
+    }
+}
+
+struct Token<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+enum JumpTarget<'a> {
+    Label(Token<'a>),
+    Offset(Offset),
+}
+
+struct PendingJump<'a> {
+    index: usize,
+    line: usize,
+    target: Token<'a>,
+}
+
+fn tokenize(line: &str, line_number: usize) -> Result<Vec<Token<'_>>, AsmError> {
+    let column = |index: usize| line[..index].chars().count() + 1;
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    while let Some((begin, ch)) = chars.next() {
+        if ch.is_whitespace() {
+            continue
+        }
+        if ch == ';' {
+            break
+        }
+        let mut end = line.len();
+        if ch == '"' {
+            // Strings run to the next unescaped quote and may contain spaces and `;`.
+            let mut closed = false;
+            while let Some((index, ch)) = chars.next() {
+                match ch {
+                    '\\' => {
+                        chars.next();
+                    }
+                    '"' => {
+                        end = index + 1;
+                        closed = true;
+                        break
+                    }
+                    _ => {}
+                }
+            }
+            if !closed {
+                return Err(AsmError { line: line_number, column: column(begin), kind: AsmErrorKind::UnterminatedString })
+            }
+        } else {
+            while let Some(&(index, ch)) = chars.peek() {
+                if ch.is_whitespace() || ch == ';' {
+                    end = index;
+                    break
+                }
+                chars.next();
+            }
+        }
+        tokens.push(Token { text: &line[begin..end], column: column(begin) });
+    }
+    Ok(tokens)
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        }
+        _ => false,
+    }
+}
+
+fn expect_operand(operand: Option<Token>, line: usize, column: usize) -> Result<Token, AsmError> {
+    operand.ok_or(AsmError { line, column, kind: AsmErrorKind::MissingOperand })
+}
+
+fn parse_name(token: Token, line: usize) -> Result<String, AsmError> {
+    if is_name(token.text) {
+        Ok(token.text.into())
+    } else {
+        Err(AsmError { line, column: token.column, kind: AsmErrorKind::InvalidName(token.text.into()) })
+    }
+}
+
+fn parse_load(token: Token, line: usize) -> Result<Instruction, AsmError> {
+    let error = |kind| AsmError { line, column: token.column, kind };
+    if let Some(quoted) = token.text.strip_prefix('"') {
+        let text = &quoted[..quoted.len() - 1];
+        return match unescape(text) {
+            Ok(text) => Ok(Instruction::LoadStr(text)),
+            Err(ch) => Err(error(AsmErrorKind::InvalidEscape(ch))),
+        }
+    }
+    match token.text {
+        "true" => Ok(Instruction::LoadBool(true)),
+        "false" => Ok(Instruction::LoadBool(false)),
+        text => text.parse().map(Instruction::Load).map_err(|_| error(AsmErrorKind::InvalidInteger(text.into()))),
+    }
+}
+
+fn parse_jump_target(token: Token, line: usize) -> Result<JumpTarget, AsmError> {
+    if is_name(token.text) {
+        return Ok(JumpTarget::Label(token))
+    }
+    match token.text.parse() {
+        Ok(offset) => Ok(JumpTarget::Offset(offset)),
+        Err(_) => Err(AsmError { line, column: token.column, kind: AsmErrorKind::InvalidInteger(token.text.into()) }),
+    }
+}
+
+pub fn parse(source: &str) -> Result<Vec<Instruction>, AsmError> {
+    let mut code = Vec::new();
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    let mut pending = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let line_number = line_index + 1;
+        let error = |column, kind| AsmError { line: line_number, column, kind };
+        let mut tokens = tokenize(line, line_number)?.into_iter().peekable();
+
+        while let Some(label) = tokens.next_if(|token| token.text.ends_with(':')) {
+            let name = &label.text[..label.text.len() - 1];
+            if !is_name(name) {
+                return Err(error(label.column, AsmErrorKind::InvalidName(name.into())));
+            }
+            if labels.insert(name, code.len()).is_some() {
+                return Err(error(label.column, AsmErrorKind::DuplicateLabel(name.into())));
+            }
+        }
+
+        let mnemonic = match tokens.next() {
+            Some(mnemonic) => mnemonic,
+            None => continue,
+        };
+        let operand = tokens.next();
+        // `callhost` is the only instruction with a second operand, the argument count.
+        let argc = if mnemonic.text.eq_ignore_ascii_case("callhost") { tokens.next() } else { None };
+        if let Some(extra) = tokens.next() {
+            return Err(error(extra.column, AsmErrorKind::UnexpectedOperand(extra.text.into())));
+        }
+
+        let end_column = mnemonic.column + mnemonic.text.chars().count();
+        let required = |operand| expect_operand(operand, line_number, end_column);
+        let no_operand = |instruction: Instruction, operand: Option<Token>| match operand {
+            Some(token) => Err(error(token.column, AsmErrorKind::UnexpectedOperand(token.text.into()))),
+            None => Ok(instruction),
+        };
+
+        let instruction = match mnemonic.text.to_ascii_lowercase().as_str() {
+            "load" => parse_load(required(operand)?, line_number)?,
+            "newarray" | "readslot" | "writeslot" => {
+                let token = required(operand)?;
+                let count = token.text.parse().map_err(|_| {
+                    error(token.column, AsmErrorKind::InvalidInteger(token.text.into()))
+                })?;
+                match mnemonic.text.to_ascii_lowercase().as_str() {
+                    "newarray" => Instruction::NewArray(count),
+                    "readslot" => Instruction::ReadSlot(count),
+                    _ => Instruction::WriteSlot(count),
+                }
+            }
+            "read" => Instruction::Read(parse_name(required(operand)?, line_number)?),
+            "write" => Instruction::Write(parse_name(required(operand)?, line_number)?),
+            "jump" | "jumpif" | "call" => {
+                let offset = match parse_jump_target(required(operand)?, line_number)? {
+                    JumpTarget::Offset(offset) => offset,
+                    JumpTarget::Label(target) => {
+                        pending.push(PendingJump { index: code.len(), line: line_number, target });
+                        0
+                    }
+                };
+                match mnemonic.text.to_ascii_lowercase().as_str() {
+                    "jump" => Instruction::Jump(offset),
+                    "jumpif" => Instruction::JumpIf(offset),
+                    _ => Instruction::Call(offset),
+                }
+            }
+            "cmpeq" => no_operand(Instruction::CompareEQ, operand)?,
+            "cmpne" => no_operand(Instruction::CompareNE, operand)?,
+            "cmpgt" => no_operand(Instruction::CompareGT, operand)?,
+            "cmplt" => no_operand(Instruction::CompareLT, operand)?,
+            "cmplte" => no_operand(Instruction::CompareLTE, operand)?,
+            "cmpgte" => no_operand(Instruction::CompareGTE, operand)?,
+            "add" => no_operand(Instruction::Add, operand)?,
+            "sub" => no_operand(Instruction::Sub, operand)?,
+            "mul" => no_operand(Instruction::Mul, operand)?,
+            "div" => no_operand(Instruction::Div, operand)?,
+            "mod" => no_operand(Instruction::Mod, operand)?,
+            "neg" => no_operand(Instruction::Neg, operand)?,
+            "and" => no_operand(Instruction::And, operand)?,
+            "or" => no_operand(Instruction::Or, operand)?,
+            "xor" => no_operand(Instruction::Xor, operand)?,
+            "not" => no_operand(Instruction::Not, operand)?,
+            "shl" => no_operand(Instruction::Shl, operand)?,
+            "shr" => no_operand(Instruction::Shr, operand)?,
+            "dup" => no_operand(Instruction::Dup, operand)?,
+            "swap" => no_operand(Instruction::Swap, operand)?,
+            "pop" => no_operand(Instruction::Pop, operand)?,
+            "concat" => no_operand(Instruction::Concat, operand)?,
+            "index" => no_operand(Instruction::Index, operand)?,
+            "len" => no_operand(Instruction::Len, operand)?,
+            "callhost" => {
+                let token = required(operand)?;
+                let name_end = token.column + token.text.chars().count();
+                let name = parse_name(token, line_number)?;
+                let token = expect_operand(argc, line_number, name_end)?;
+                let argc = token.text.parse().map_err(|_| {
+                    error(token.column, AsmErrorKind::InvalidInteger(token.text.into()))
+                })?;
+                Instruction::CallHost(name, argc)
+            }
+            "ret" => no_operand(Instruction::Ret, operand)?,
+            "return" => no_operand(Instruction::Return, operand)?,
+            _ => {
+                return Err(error(mnemonic.column, AsmErrorKind::UnknownMnemonic(mnemonic.text.into())))
+            }
+        };
+        code.push(instruction);
+    }
+
+    for jump in pending {
+        let error = |kind| AsmError { line: jump.line, column: jump.target.column, kind };
+        let target = match labels.get(jump.target.text) {
+            Some(target) => *target,
+            None => return Err(error(AsmErrorKind::UndefinedLabel(jump.target.text.into()))),
+        };
+        let offset = match offset_for_target(target) {
+            Some(offset) => offset,
+            None => return Err(error(AsmErrorKind::LabelAtStart(jump.target.text.into()))),
+        };
+        match &mut code[jump.index] {
+            Instruction::Jump(slot) | Instruction::JumpIf(slot) | Instruction::Call(slot) => *slot = offset,
+            _ => unreachable!("pending jumps always point at a jump or call"),
+        }
+    }
+
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instruction::*;
+
+    #[test]
+    fn parse_lt_loop() {
+        let source = "
+            ; i = 0; while i < 3 { i += 1 }
+                    load 0
+                    write i
+            loop:   read i
+                    load 3
+                    cmplt
+                    jumpif done
+                    read i
+                    load 1
+                    add
+                    write i
+                    jump loop
+            done:   read i
+                    return
+        ";
+        assert_eq!(parse(source).unwrap(), crate::lt_loop());
+    }
+
+    #[test]
+    fn numeric_offsets_and_case() {
+        assert_eq!(parse("LOAD -4\nJump 2\nload 5\nload 7 ; comment\nAdd\nreturn").unwrap(),
+            vec![Load(-4), Jump(2), Load(5), Load(7), Add, Return]);
+    }
+
+    #[test]
+    fn call_and_ret() {
+        assert_eq!(parse("load 2\ncall double\nreturn\ndouble: load 2\nmul\nret").unwrap(),
+            vec![Load(2), Call(2), Return, Load(2), Mul, Ret]);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let code = vec![Load(-3), Write("x".into()), Read("x".into()), JumpIf(4), Call(4), WriteSlot(0), ReadSlot(12),
+            CompareGTE, Div, Mod, Neg, And, Or, Xor, Not, Shl, Shr, Dup, Swap, Pop, Ret, Return];
+        let source: Vec<String> = code.iter().map(|instruction| instruction.to_string()).collect();
+        assert_eq!(source[3], "jumpif 4");
+        assert_eq!(parse(&source.join("\n")).unwrap(), code);
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(parse("load true\nload \"a; \\\"b\\\"\"  ; comment\nnewarray 2").unwrap(),
+            vec![LoadBool(true), LoadStr("a; \"b\"".into()), NewArray(2)]);
+        assert_eq!(parse("load FALSE").unwrap_err().kind, AsmErrorKind::InvalidInteger("FALSE".into()));
+
+        let code = vec![LoadBool(true), LoadStr("a; \"b\" \\\n".into()), LoadBool(false), NewArray(3), Load(0),
+            Index, Len, LoadStr(String::new()), Concat, Return];
+        let source: Vec<String> = code.iter().map(|instruction| instruction.to_string()).collect();
+        assert_eq!(source[1], "load \"a; \\\"b\\\" \\\\\\n\"");
+        assert_eq!(parse(&source.join("\n")).unwrap(), code);
+
+        let err = parse("load 1\n  load \"open").unwrap_err();
+        assert_eq!((err.line, err.column, err.kind), (2, 8, AsmErrorKind::UnterminatedString));
+        assert_eq!(parse("load \"\\q\"").unwrap_err().kind, AsmErrorKind::InvalidEscape('q'));
+    }
+
+    #[test]
+    fn callhost_operands() {
+        assert_eq!(parse("load 1\nCALLHOST log 1 ; comment").unwrap(), vec![Load(1), CallHost("log".into(), 1)]);
+        assert_eq!(CallHost("now".into(), 0).to_string(), "callhost now 0");
+        let err = parse("callhost log").unwrap_err();
+        assert_eq!((err.line, err.column, err.kind), (1, 13, AsmErrorKind::MissingOperand));
+        assert_eq!(parse("callhost log x").unwrap_err().kind, AsmErrorKind::InvalidInteger("x".into()));
+        assert_eq!(parse("callhost log 1 2").unwrap_err().kind, AsmErrorKind::UnexpectedOperand("2".into()));
+    }
+
+    #[test]
+    fn label_on_its_own_line() {
+        assert_eq!(parse("load 1\nend:\n\nreturn\njump end").unwrap(), vec![Load(1), Return, Jump(0)]);
+    }
+
+    #[test]
+    fn error_positions() {
+        let err = parse("load 1\n  frobnicate").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 3));
+        assert_eq!(err.kind, AsmErrorKind::UnknownMnemonic("frobnicate".into()));
+
+        let err = parse("load x").unwrap_err();
+        assert_eq!((err.line, err.column, err.kind), (1, 6, AsmErrorKind::InvalidInteger("x".into())));
+
+        let err = parse("write").unwrap_err();
+        assert_eq!((err.line, err.column, err.kind), (1, 6, AsmErrorKind::MissingOperand));
+
+        let err = parse("add 1").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 5));
+
+        let err = parse("load 1\n\njump nowhere").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 6));
+        assert_eq!(err.to_string(), "3:6: undefined label `nowhere`");
+    }
+
+    #[test]
+    fn label_errors() {
+        assert_eq!(parse("a: load 1\na: return").unwrap_err().kind, AsmErrorKind::DuplicateLabel("a".into()));
+        assert_eq!(parse("start: load 1\njump start").unwrap_err().kind,
+            AsmErrorKind::LabelAtStart("start".into()));
+        assert_eq!(parse("1x: return").unwrap_err().kind, AsmErrorKind::InvalidName("1x".into()));
+    }
+}
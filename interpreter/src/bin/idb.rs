@@ -0,0 +1,138 @@
+// Interactive debugger for interpreter programs.
+//
+//     idb program.asm     assembly, see `interpreter::asm`
+//     idb program.src     anything else is compiled with `interpreter::lang`
+
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+
+use interpreter::debugger::{Debugger, StopReason};
+use interpreter::{load_file, Value};
+
+const HELP: &str = "\
+commands:
+  s, step           execute one instruction
+  c, continue       run to the next breakpoint, watch change or return
+  b, break N        set a breakpoint at offset N
+  d, delete N       remove the breakpoint at offset N
+  w, watch NAME     stop when NAME changes
+  u, unwatch NAME   stop watching NAME
+  l, list           show the program around the current instruction
+  stack             show the operand stack
+  vars              show the variables visible to the current frame
+  r, restart        rewind the program and clear all variables
+  q, quit";
+
+fn list(debugger: &Debugger) {
+    let ip = debugger.vm().instruction_ptr();
+    let breakpoints: Vec<usize> = debugger.breakpoints().collect();
+    let start = ip.saturating_sub(3);
+    for (offset, instruction) in debugger.vm().code().iter().enumerate().skip(start).take(7) {
+        let marker = if offset == ip { "=>" } else { "  " };
+        let breakpoint = if breakpoints.contains(&offset) { "*" } else { " " };
+        println!("{}{} {:4}  {}", marker, breakpoint, offset, instruction);
+    }
+}
+
+fn report(debugger: &Debugger, result: Result<StopReason, interpreter::InterpreterError>) {
+    match result {
+        Ok(StopReason::Returned(value)) => println!("returned {}", value),
+        Ok(StopReason::Breakpoint(offset)) => println!("breakpoint at {}", offset),
+        Ok(StopReason::WatchChanged { name, old, new }) => {
+            let show = |value: Option<Value>| value.map_or("unset".to_string(), |value| value.to_string());
+            println!("{} changed: {} -> {}", name, show(old), show(new))
+        }
+        Ok(StopReason::Stepped) => {}
+        Err(err) => print!("{}", debugger.vm().error_report(err)),
+    }
+    let ip = debugger.vm().instruction_ptr();
+    match debugger.current_instruction() {
+        Some(instruction) => println!("{:4}  {}", ip, instruction),
+        None => println!("{:4}  <end of program>", ip),
+    }
+}
+
+fn main() -> ExitCode {
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: idb <program>");
+            return ExitCode::FAILURE
+        }
+    };
+    let code = match load_file(&path) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE
+        }
+    };
+
+    let mut debugger = Debugger::with_program(code);
+    println!("{} instructions loaded, `help` lists commands", debugger.vm().code().len());
+
+    let stdin = io::stdin();
+    loop {
+        print!("(idb) ");
+        io::stdout().flush().ok();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            return ExitCode::SUCCESS
+        }
+
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue,
+        };
+        let argument = words.next();
+        let offset = || argument.and_then(|arg| arg.parse::<usize>().ok());
+
+        match command {
+            "s" | "step" => {
+                let result = debugger.step();
+                report(&debugger, result);
+            }
+            "c" | "continue" => {
+                let result = debugger.resume();
+                report(&debugger, result);
+            }
+            "b" | "break" => match offset() {
+                Some(offset) if offset < debugger.vm().code().len() => {
+                    debugger.add_breakpoint(offset);
+                }
+                _ => println!("expected an offset below {}", debugger.vm().code().len()),
+            },
+            "d" | "delete" => match offset() {
+                Some(offset) if debugger.remove_breakpoint(offset) => {}
+                _ => println!("no such breakpoint"),
+            },
+            "w" | "watch" => match argument {
+                Some(name) => {
+                    debugger.watch(name);
+                }
+                None => println!("expected a variable name"),
+            },
+            "u" | "unwatch" => match argument {
+                Some(name) if debugger.unwatch(name) => {}
+                _ => println!("not watching that"),
+            },
+            "l" | "list" => list(&debugger),
+            "stack" => println!("{}", Value::Array(debugger.vm().stack().to_vec())),
+            "vars" => {
+                let mut vars: Vec<_> = debugger.vm().locals().into_iter().collect();
+                vars.sort_by(|(a, _), (b, _)| a.cmp(b));
+                for (name, value) in vars {
+                    println!("{} = {}", name, value);
+                }
+            }
+            "r" | "restart" => {
+                debugger.restart();
+                report(&debugger, Ok(StopReason::Stepped));
+            }
+            "q" | "quit" => return ExitCode::SUCCESS,
+            "h" | "help" => println!("{}", HELP),
+            other => println!("unknown command `{}`, try `help`", other),
+        }
+    }
+}
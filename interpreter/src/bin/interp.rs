@@ -0,0 +1,184 @@
+// Runs interpreter programs, or an interactive session without one.
+//
+//     interp [--threaded] program.asm     assembly, see `interpreter::asm`
+//     interp [--threaded] program.src     anything else is compiled with `interpreter::lang`
+//     interp                              read-eval-print loop, `:help` lists its commands
+//
+// A program prints what it returns and exits with it when that is an int, truncated to 8
+// bits like any process status. Bad arguments exit with 64, programs that can't be read or
+// compiled with 65 and runtime errors with 70, as in sysexits.h.
+
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+
+use interpreter::asm::{self, AsmErrorKind};
+use interpreter::disasm::disassemble;
+use interpreter::{lang, load_file, Engine, Instruction, Limits, Step, Value, Vm};
+
+const BAD_USAGE: u8 = 64;
+const BAD_PROGRAM: u8 = 65;
+const RUN_FAILED: u8 = 70;
+
+// Instructions each session entry may execute, so a runaway loop hands back the prompt.
+const ENTRY_FUEL: u64 = 10_000_000;
+
+const USAGE: &str = "\
+usage: interp [--threaded] [program]
+  --threaded           run the program on the threaded engine";
+
+const HELP: &str = "\
+Lines that assemble run as assembly, anything else as source: statements, or an expression
+whose value is pushed. Variables and the stack carry over from line to line.
+commands:
+  :vars                show the variables
+  :reset               forget the variables, the stack and everything entered
+  :load FILE           run a program file in this session, .asm files as assembly
+  :disasm              show everything entered so far as assembly
+  :history             list the lines entered so far
+  :help
+  :quit";
+
+// Assembly when the line assembles, the source language otherwise.
+fn compile(line: &str) -> Result<Vec<Instruction>, String> {
+    let asm_err = match asm::parse(line) {
+        Ok(code) => return Ok(code),
+        Err(err) => err,
+    };
+    match lang::compile_entry(line) {
+        Ok(code) => Ok(code),
+        // An unknown mnemonic only means the line wasn't meant as assembly.
+        Err(err) if matches!(asm_err.kind, AsmErrorKind::UnknownMnemonic(_)) => Err(err.to_string()),
+        Err(_) => Err(asm_err.to_string()),
+    }
+}
+
+// Runs `code` after everything entered before it and shows the stack.
+fn run_entry(vm: &mut Vm, code: Vec<Instruction>) {
+    vm.set_limits(Limits { fuel: Some(vm.fuel_consumed() + ENTRY_FUEL), ..vm.limits() });
+    vm.extend_program(code);
+    let end = vm.code().len();
+    while vm.instruction_ptr() != end {
+        match vm.step() {
+            Ok(Step::Continue) => {}
+            Ok(Step::Return(value)) => {
+                println!("returned {}", value);
+                break
+            }
+            Err(kind) => {
+                print!("{}", vm.error_report(kind));
+                break
+            }
+        }
+    }
+    println!("{}", Value::Array(vm.stack().to_vec()));
+}
+
+fn repl() -> ExitCode {
+    let mut vm = Vm::new();
+    let mut history: Vec<String> = Vec::new();
+    println!("`:help` lists commands");
+
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().ok();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            println!();
+            return ExitCode::SUCCESS
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue
+        }
+        history.push(line.to_string());
+
+        let Some(command) = line.strip_prefix(':') else {
+            match compile(line) {
+                Ok(code) => run_entry(&mut vm, code),
+                Err(err) => println!("{}", err),
+            }
+            continue
+        };
+        let (command, argument) = match command.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, Some(argument.trim())),
+            None => (command, None),
+        };
+        match command {
+            "vars" => {
+                let mut vars: Vec<_> = vm.vars().iter().collect();
+                vars.sort_by_key(|(name, _)| *name);
+                for (name, value) in vars {
+                    println!("{} = {}", name, value);
+                }
+            }
+            "reset" => vm = Vm::new(),
+            "load" => match argument.map(load_file) {
+                Some(Ok(code)) => run_entry(&mut vm, code),
+                Some(Err(err)) => println!("{}", err),
+                None => println!("expected a file"),
+            },
+            "disasm" => print!("{}", disassemble(vm.code())),
+            "history" => {
+                for (number, entry) in history.iter().enumerate() {
+                    println!("{:4}  {}", number + 1, entry);
+                }
+            }
+            "help" => println!("{}", HELP),
+            "q" | "quit" => return ExitCode::SUCCESS,
+            other => println!("unknown command `:{}`, try `:help`", other),
+        }
+    }
+}
+
+fn run_file(path: &str, engine: Engine) -> ExitCode {
+    let code = match load_file(path) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(BAD_PROGRAM)
+        }
+    };
+    let mut vm = Vm::with_engine(engine);
+    vm.load_program(code);
+    match vm.run() {
+        Ok(value) => {
+            println!("{}", value);
+            match value {
+                Value::Int(status) => ExitCode::from(status as u8),
+                _ => ExitCode::SUCCESS,
+            }
+        }
+        Err(err) => {
+            eprint!("{}", err);
+            ExitCode::from(RUN_FAILED)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut engine = Engine::Basic;
+    let mut path = None;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--threaded" => engine = Engine::Threaded,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS
+            }
+            flag if flag.starts_with("--") => {
+                eprintln!("unknown option `{}`\n{}", flag, USAGE);
+                return ExitCode::from(BAD_USAGE)
+            }
+            file if path.is_none() => path = Some(file.to_string()),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(BAD_USAGE)
+            }
+        }
+    }
+    match path {
+        Some(path) => run_file(&path, engine),
+        None => repl(),
+    }
+}
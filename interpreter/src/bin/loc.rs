@@ -0,0 +1,175 @@
+// Counts lines of code under a directory, see `interpreter::loc`.
+//
+//     loc [options] [path]      path defaults to the current directory
+//
+// Skipped files and read errors are listed on stderr, the latter make the exit status 1.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use glob::Pattern;
+use interpreter::loc::{stream, stream_cached, Cache, Options, Report};
+
+// Files between progress updates.
+const PROGRESS_EVERY: usize = 64;
+
+const USAGE: &str = "\
+usage: loc [options] [path]
+  --ext EXT[,EXT..]    only count files with these extensions, may be repeated
+  --include GLOB       only count files matching GLOB, may be repeated
+  --exclude GLOB       skip files and directories matching GLOB, may be repeated
+  --no-gitignore       don't skip what .gitignore files ignore
+  --follow-symlinks    count what symlinks point to instead of skipping them
+  --threads N          count files on N threads, defaults to one per core
+  --cache PATH         remember counts in PATH, defaults to .loc-cache in the root
+  --no-cache           read and count every file and leave the cache alone
+  --diff               list files whose counts changed since the last cached run
+  --progress           show how many files are done on stderr
+  --files              list every file before the totals
+  --json               print JSON instead of a table";
+
+struct Args {
+    root: PathBuf,
+    options: Options,
+    // `None` with `--no-cache`.
+    cache: Option<PathBuf>,
+    diff: bool,
+    files: bool,
+    json: bool,
+    progress: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        root: PathBuf::from("."),
+        options: Options::default(),
+        cache: None,
+        diff: false,
+        files: false,
+        json: false,
+        progress: false,
+    };
+    let mut cache = None;
+    let mut no_cache = false;
+    let mut root = None;
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or_else(|| format!("{} needs a value", flag));
+        let pattern = |glob: String| Pattern::new(&glob).map_err(|err| format!("bad glob `{}`: {}", glob, err));
+        match arg.as_str() {
+            "--ext" => {
+                let extensions = value("--ext")?;
+                parsed.options.extensions.extend(extensions.split(',').map(|extension| extension.trim_start_matches('.').to_string()));
+            }
+            "--include" => parsed.options.include.push(pattern(value("--include")?)?),
+            "--exclude" => parsed.options.exclude.push(pattern(value("--exclude")?)?),
+            "--no-gitignore" => parsed.options.gitignore = false,
+            "--follow-symlinks" => parsed.options.follow_symlinks = true,
+            "--threads" => {
+                let threads = value("--threads")?;
+                parsed.options.threads = threads.parse().map_err(|_| format!("bad thread count `{}`", threads))?;
+            }
+            "--cache" => cache = Some(PathBuf::from(value("--cache")?)),
+            "--no-cache" => no_cache = true,
+            "--diff" => parsed.diff = true,
+            "--progress" => parsed.progress = true,
+            "--files" => parsed.files = true,
+            "--json" => parsed.json = true,
+            "-h" | "--help" => return Err(USAGE.into()),
+            flag if flag.starts_with("--") => return Err(format!("unknown option `{}`\n{}", flag, USAGE)),
+            path if root.is_none() => root = Some(PathBuf::from(path)),
+            extra => return Err(format!("unexpected argument `{}`\n{}", extra, USAGE)),
+        }
+    }
+    if let Some(root) = root {
+        parsed.root = root;
+    }
+    if !no_cache {
+        parsed.cache = Some(cache.unwrap_or_else(|| parsed.root.join(".loc-cache")));
+    }
+    if parsed.diff && parsed.cache.is_none() {
+        return Err("--diff needs the cache".into())
+    }
+    if parsed.diff && parsed.json {
+        return Err("--diff can't be combined with --json".into())
+    }
+    Ok(parsed)
+}
+
+fn print_files(out: &mut impl Write, report: &Report) -> io::Result<()> {
+    writeln!(out, "{:<48}{:>10}{:>10}{:>10}{:>10}{:>10}{:>12}", "file", "lines", "code", "comment", "doc", "blank", "bytes")?;
+    for file in &report.files {
+        let counts = &file.counts;
+        writeln!(out, "{:<48}{:>10}{:>10}{:>10}{:>10}{:>10}{:>12}",
+            file.path.display(), counts.lines, counts.code(), counts.comment, counts.doc, counts.blank, counts.bytes)?;
+    }
+    writeln!(out)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE
+        }
+    };
+    let cache = args.cache.as_deref().map(|path| Arc::new(Cache::load(path)));
+    let scan = match &cache {
+        Some(cache) => stream_cached(&args.root, &args.options, cache),
+        None => stream(&args.root, &args.options),
+    };
+    let mut entries = Vec::new();
+    for entry in scan {
+        entries.push(entry);
+        if args.progress && entries.len() % PROGRESS_EVERY == 0 {
+            eprint!("\r{} files", entries.len());
+        }
+    }
+    if args.progress {
+        match &cache {
+            Some(cache) => {
+                let stats = cache.stats();
+                eprintln!("\r{} files, {} unchanged, {} rehashed, {} counted", entries.len(), stats.unchanged, stats.rehashed, stats.counted);
+            }
+            None => eprintln!("\r{} files", entries.len()),
+        }
+    }
+    if let Some(cache) = &cache {
+        if let Err(err) = cache.save() {
+            eprintln!("loc: can't save the cache to {}: {}", cache.path().display(), err);
+        }
+    }
+    let report: Report = entries.into_iter().collect();
+    for (path, reason) in &report.skipped {
+        eprintln!("loc: skipped {}: {}", path.display(), reason);
+    }
+    for err in &report.errors {
+        eprintln!("loc: {}", err);
+    }
+    let status = if report.errors.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let written = if args.json {
+        report.write_json(&mut out)
+    } else if args.files {
+        print_files(&mut out, &report).and_then(|_| write!(out, "{}", report))
+    } else {
+        write!(out, "{}", report)
+    };
+    let written = match (written, &cache) {
+        (Ok(()), Some(cache)) if args.diff => write!(out, "\n{}", cache.diff()),
+        (written, _) => written,
+    };
+    match written {
+        Ok(()) => status,
+        // A closed pipe, e.g. `loc | head`, is not worth a message.
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => status,
+        Err(err) => {
+            eprintln!("loc: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
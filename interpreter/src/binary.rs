@@ -0,0 +1,439 @@
+// Compact on-disk encoding of programs.
+//
+//     magic "IBC\0" | version u8 | string table | instructions | crc32 (u32 little endian)
+//
+// The string table is a varint count followed by varint length prefixed UTF-8 strings, `Read`,
+// `Write`, `LoadStr` and `CallHost` refer to them by index. Instructions are a varint count
+// followed by one opcode byte each plus its immediates: zigzag varints for `Load`, a 0/1 byte
+// for `LoadBool`, plain varints for offsets, array lengths, argument counts, slots and string
+// indices. The checksum covers every byte before it.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::Instruction;
+
+pub const MAGIC: [u8; 4] = *b"IBC\0";
+pub const VERSION: u8 = 1;
+
+const LOAD: u8 = 0x01;
+const READ: u8 = 0x02;
+const WRITE: u8 = 0x03;
+const JUMP: u8 = 0x04;
+const JUMP_IF: u8 = 0x05;
+const CALL: u8 = 0x06;
+const RET: u8 = 0x07;
+const LOAD_BOOL: u8 = 0x08;
+const LOAD_STR: u8 = 0x09;
+const NEW_ARRAY: u8 = 0x0a;
+const CALL_HOST: u8 = 0x0b;
+const READ_SLOT: u8 = 0x0c;
+const WRITE_SLOT: u8 = 0x0d;
+const COMPARE_EQ: u8 = 0x10;
+const COMPARE_NE: u8 = 0x11;
+const COMPARE_GT: u8 = 0x12;
+const COMPARE_LT: u8 = 0x13;
+const COMPARE_LTE: u8 = 0x14;
+const COMPARE_GTE: u8 = 0x15;
+const ADD: u8 = 0x20;
+const SUB: u8 = 0x21;
+const MUL: u8 = 0x22;
+const DIV: u8 = 0x23;
+const CONCAT: u8 = 0x24;
+const INDEX: u8 = 0x25;
+const LEN: u8 = 0x26;
+const MOD: u8 = 0x27;
+const NEG: u8 = 0x28;
+const AND: u8 = 0x30;
+const OR: u8 = 0x31;
+const XOR: u8 = 0x32;
+const NOT: u8 = 0x33;
+const SHL: u8 = 0x34;
+const SHR: u8 = 0x35;
+const DUP: u8 = 0x50;
+const SWAP: u8 = 0x51;
+const POP: u8 = 0x52;
+const RETURN: u8 = 0xff;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    ChecksumMismatch { expected: u32, found: u32 },
+    UnexpectedEof,
+    VarintOverflow,
+    InvalidUtf8,
+    InvalidBool(u8),
+    UnknownOpcode(u8),
+    BadStringIndex(u64),
+    TrailingBytes,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not an interpreter bytecode file"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            DecodeError::ChecksumMismatch { expected, found } => {
+                write!(f, "checksum mismatch: expected {:08x}, found {:08x}", expected, found)
+            }
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::VarintOverflow => write!(f, "varint does not fit in 64 bits"),
+            DecodeError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            DecodeError::InvalidBool(byte) => write!(f, "invalid boolean {:#04x}", byte),
+            DecodeError::UnknownOpcode(opcode) => write!(f, "unknown opcode {:#04x}", opcode),
+            DecodeError::BadStringIndex(index) => write!(f, "string index {} is out of range", index),
+            DecodeError::TrailingBytes => write!(f, "trailing bytes after the last instruction"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// CRC-32 (IEEE), bit at a time. Programs are small enough not to need a table.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// 64-bit FNV-1a.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub(crate) fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub(crate) fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+pub fn encode(code: &[Instruction]) -> Vec<u8> {
+    let mut strings: Vec<&str> = Vec::new();
+    let mut indices: HashMap<&str, u64> = HashMap::new();
+    for instruction in code {
+        if let Instruction::Read(name) | Instruction::Write(name) | Instruction::LoadStr(name)
+            | Instruction::CallHost(name, _) = instruction
+        {
+            indices.entry(name).or_insert_with(|| {
+                strings.push(name);
+                strings.len() as u64 - 1
+            });
+        }
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    write_varint(&mut out, strings.len() as u64);
+    for string in &strings {
+        write_varint(&mut out, string.len() as u64);
+        out.extend_from_slice(string.as_bytes());
+    }
+
+    write_varint(&mut out, code.len() as u64);
+    for instruction in code {
+        match instruction {
+            Instruction::Load(value) => {
+                out.push(LOAD);
+                write_varint(&mut out, zigzag(*value));
+            }
+            Instruction::LoadBool(value) => {
+                out.push(LOAD_BOOL);
+                out.push(*value as u8);
+            }
+            Instruction::LoadStr(text) => {
+                out.push(LOAD_STR);
+                write_varint(&mut out, indices[text.as_str()]);
+            }
+            Instruction::Read(name) => {
+                out.push(READ);
+                write_varint(&mut out, indices[name.as_str()]);
+            }
+            Instruction::Write(name) => {
+                out.push(WRITE);
+                write_varint(&mut out, indices[name.as_str()]);
+            }
+            Instruction::ReadSlot(slot) => {
+                out.push(READ_SLOT);
+                write_varint(&mut out, *slot as u64);
+            }
+            Instruction::WriteSlot(slot) => {
+                out.push(WRITE_SLOT);
+                write_varint(&mut out, *slot as u64);
+            }
+            Instruction::Jump(offset) => {
+                out.push(JUMP);
+                write_varint(&mut out, *offset as u64);
+            }
+            Instruction::JumpIf(offset) => {
+                out.push(JUMP_IF);
+                write_varint(&mut out, *offset as u64);
+            }
+            Instruction::Call(offset) => {
+                out.push(CALL);
+                write_varint(&mut out, *offset as u64);
+            }
+            Instruction::Ret => out.push(RET),
+            Instruction::CallHost(name, argc) => {
+                out.push(CALL_HOST);
+                write_varint(&mut out, indices[name.as_str()]);
+                write_varint(&mut out, *argc as u64);
+            }
+            Instruction::CompareEQ => out.push(COMPARE_EQ),
+            Instruction::CompareNE => out.push(COMPARE_NE),
+            Instruction::CompareGT => out.push(COMPARE_GT),
+            Instruction::CompareLT => out.push(COMPARE_LT),
+            Instruction::CompareLTE => out.push(COMPARE_LTE),
+            Instruction::CompareGTE => out.push(COMPARE_GTE),
+            Instruction::Add => out.push(ADD),
+            Instruction::Sub => out.push(SUB),
+            Instruction::Mul => out.push(MUL),
+            Instruction::Div => out.push(DIV),
+            Instruction::Mod => out.push(MOD),
+            Instruction::Neg => out.push(NEG),
+            Instruction::And => out.push(AND),
+            Instruction::Or => out.push(OR),
+            Instruction::Xor => out.push(XOR),
+            Instruction::Not => out.push(NOT),
+            Instruction::Shl => out.push(SHL),
+            Instruction::Shr => out.push(SHR),
+            Instruction::Dup => out.push(DUP),
+            Instruction::Swap => out.push(SWAP),
+            Instruction::Pop => out.push(POP),
+            Instruction::NewArray(len) => {
+                out.push(NEW_ARRAY);
+                write_varint(&mut out, *len as u64);
+            }
+            Instruction::Concat => out.push(CONCAT),
+            Instruction::Index => out.push(INDEX),
+            Instruction::Len => out.push(LEN),
+            Instruction::Return => out.push(RETURN),
+        }
+    }
+
+    let checksum = crc32(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.pos).ok_or(DecodeError::UnexpectedEof)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    pub(crate) fn take(&mut self, len: u64) -> Result<&'a [u8], DecodeError> {
+        let remaining = (self.bytes.len() - self.pos) as u64;
+        if len > remaining {
+            return Err(DecodeError::UnexpectedEof)
+        }
+        let slice = &self.bytes[self.pos..self.pos + len as usize];
+        self.pos += len as usize;
+        Ok(slice)
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u64;
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::VarintOverflow)
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value)
+            }
+        }
+        Err(DecodeError::VarintOverflow)
+    }
+
+    pub(crate) fn offset(&mut self) -> Result<usize, DecodeError> {
+        usize::try_from(self.varint()?).map_err(|_| DecodeError::VarintOverflow)
+    }
+
+    // A varint length followed by that many bytes of UTF-8.
+    pub(crate) fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.varint()?;
+        let text = std::str::from_utf8(self.take(len)?).map_err(|_| DecodeError::InvalidUtf8)?;
+        Ok(text.to_string())
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::BadMagic)
+    }
+    if bytes.len() < MAGIC.len() + 1 + 4 {
+        return Err(DecodeError::UnexpectedEof)
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    let expected = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    let found = crc32(body);
+    if expected != found {
+        return Err(DecodeError::ChecksumMismatch { expected, found })
+    }
+
+    let mut reader = Reader { bytes: body, pos: MAGIC.len() };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version))
+    }
+
+    let string_count = reader.varint()?;
+    let mut strings = Vec::new();
+    for _ in 0..string_count {
+        strings.push(reader.string()?);
+    }
+    let string = |index: u64| {
+        strings.get(index as usize).cloned().ok_or(DecodeError::BadStringIndex(index))
+    };
+
+    let count = reader.varint()?;
+    let mut code = Vec::new();
+    for _ in 0..count {
+        let instruction = match reader.byte()? {
+            LOAD => Instruction::Load(unzigzag(reader.varint()?)),
+            LOAD_BOOL => match reader.byte()? {
+                0 => Instruction::LoadBool(false),
+                1 => Instruction::LoadBool(true),
+                byte => return Err(DecodeError::InvalidBool(byte)),
+            },
+            LOAD_STR => Instruction::LoadStr(string(reader.varint()?)?),
+            READ => Instruction::Read(string(reader.varint()?)?),
+            WRITE => Instruction::Write(string(reader.varint()?)?),
+            READ_SLOT => Instruction::ReadSlot(reader.offset()?),
+            WRITE_SLOT => Instruction::WriteSlot(reader.offset()?),
+            JUMP => Instruction::Jump(reader.offset()?),
+            JUMP_IF => Instruction::JumpIf(reader.offset()?),
+            CALL => Instruction::Call(reader.offset()?),
+            RET => Instruction::Ret,
+            CALL_HOST => Instruction::CallHost(string(reader.varint()?)?, reader.offset()?),
+            COMPARE_EQ => Instruction::CompareEQ,
+            COMPARE_NE => Instruction::CompareNE,
+            COMPARE_GT => Instruction::CompareGT,
+            COMPARE_LT => Instruction::CompareLT,
+            COMPARE_LTE => Instruction::CompareLTE,
+            COMPARE_GTE => Instruction::CompareGTE,
+            ADD => Instruction::Add,
+            SUB => Instruction::Sub,
+            MUL => Instruction::Mul,
+            DIV => Instruction::Div,
+            MOD => Instruction::Mod,
+            NEG => Instruction::Neg,
+            AND => Instruction::And,
+            OR => Instruction::Or,
+            XOR => Instruction::Xor,
+            NOT => Instruction::Not,
+            SHL => Instruction::Shl,
+            SHR => Instruction::Shr,
+            DUP => Instruction::Dup,
+            SWAP => Instruction::Swap,
+            POP => Instruction::Pop,
+            NEW_ARRAY => Instruction::NewArray(reader.offset()?),
+            CONCAT => Instruction::Concat,
+            INDEX => Instruction::Index,
+            LEN => Instruction::Len,
+            RETURN => Instruction::Return,
+            opcode => return Err(DecodeError::UnknownOpcode(opcode)),
+        };
+        code.push(instruction);
+    }
+
+    if reader.pos != body.len() {
+        return Err(DecodeError::TrailingBytes)
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lt_loop, Instruction::*};
+
+    #[test]
+    fn round_trip() {
+        let code = lt_loop();
+        let bytes = encode(&code);
+        assert_eq!(&bytes[..5], b"IBC\0\x01");
+        // "i" is stored once no matter how often it is used.
+        assert_eq!(bytes.windows(2).filter(|pair| pair == b"\x01i").count(), 1);
+        assert_eq!(decode(&bytes).unwrap(), code);
+
+        let extremes = vec![Load(i64::MIN), Load(i64::MAX), Load(-1), Call(usize::MAX), Ret,
+            CompareEQ, CompareNE, CompareGT, CompareGTE, CompareLTE, Sub, Mul, Div, Mod, Neg, And, Or,
+            Xor, Not, Shl, Shr, Dup, Swap, Pop, Return];
+        assert_eq!(decode(&encode(&extremes)).unwrap(), extremes);
+        assert_eq!(decode(&encode(&[])).unwrap(), vec![]);
+
+        let values = vec![LoadStr("i".into()), LoadStr("a \"b\"".into()), Concat, LoadBool(true),
+            LoadBool(false), NewArray(3), Load(0), Index, Len, CallHost("i".into(), 1), Write("i".into()), ReadSlot(300), WriteSlot(0), Return];
+        let bytes = encode(&values);
+        assert_eq!(bytes.windows(2).filter(|pair| pair == b"\x01i").count(), 1);
+        assert_eq!(decode(&bytes).unwrap(), values);
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        let bytes = encode(&lt_loop());
+
+        let mut flipped = bytes.clone();
+        flipped[8] ^= 0x40;
+        assert!(matches!(decode(&flipped), Err(DecodeError::ChecksumMismatch { .. })));
+
+        assert_eq!(decode(b"ELF\x7f\x01\x00\x00\x00\x00"), Err(DecodeError::BadMagic));
+        assert_eq!(decode(&bytes[..bytes.len() - 3]), Err(DecodeError::ChecksumMismatch {
+            expected: u32::from_le_bytes([bytes[bytes.len() - 7], bytes[bytes.len() - 6],
+                bytes[bytes.len() - 5], bytes[bytes.len() - 4]]),
+            found: crc32(&bytes[..bytes.len() - 7]),
+        }));
+    }
+
+    // Builds a file with a valid checksum around an arbitrary body.
+    fn with_checksum(body: &[u8]) -> Vec<u8> {
+        let mut bytes = body.to_vec();
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        assert_eq!(decode(&with_checksum(b"IBC\0\x02\x00\x00")), Err(DecodeError::UnsupportedVersion(2)));
+        assert_eq!(decode(&with_checksum(b"IBC\0\x01\x00\x01\x42")), Err(DecodeError::UnknownOpcode(0x42)));
+        assert_eq!(decode(&with_checksum(b"IBC\0\x01\x00\x01\x02\x00")), Err(DecodeError::BadStringIndex(0)));
+        assert_eq!(decode(&with_checksum(b"IBC\0\x01\x01\x05ab")), Err(DecodeError::UnexpectedEof));
+        assert_eq!(decode(&with_checksum(b"IBC\0\x01\x01\x01\xff\x00")), Err(DecodeError::InvalidUtf8));
+        assert_eq!(decode(&with_checksum(b"IBC\0\x01\x00\x00\x00")), Err(DecodeError::TrailingBytes));
+        assert_eq!(decode(&with_checksum(b"IBC\0\x01\x00\x01\x08\x02")), Err(DecodeError::InvalidBool(2)));
+        let overlong = b"IBC\0\x01\x00\x01\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f";
+        assert_eq!(decode(&with_checksum(overlong)), Err(DecodeError::VarintOverflow));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{Instruction, InterpreterError, Step, Value, Vm};
+
+/// Why `Debugger::step` or `Debugger::resume` handed control back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Stepped,
+    // About to execute the instruction at this offset.
+    Breakpoint(usize),
+    WatchChanged { name: String, old: Option<Value>, new: Option<Value> },
+    Returned(Value),
+}
+
+/// Drives a `Vm` one instruction at a time, stopping at breakpoints and on writes to watched
+/// variables. Watches look at the variables visible to the current frame.
+pub struct Debugger {
+    vm: Vm,
+    breakpoints: BTreeSet<usize>,
+    watches: BTreeSet<String>,
+    returned: Option<Value>,
+}
+
+impl Debugger {
+    pub fn new(vm: Vm) -> Self {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watches: BTreeSet::new(),
+            returned: None,
+        }
+    }
+
+    pub fn with_program(code: Vec<Instruction>) -> Self {
+        let mut vm = Vm::new();
+        vm.load_program(code);
+        Self::new(vm)
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn into_vm(self) -> Vm {
+        self.vm
+    }
+
+    /// Rewinds the program, keeping breakpoints and watches.
+    pub fn restart(&mut self) {
+        self.vm.reset();
+        self.returned = None;
+    }
+
+    pub fn current_instruction(&self) -> Option<&Instruction> {
+        self.vm.code().get(self.vm.instruction_ptr())
+    }
+
+    /// Returns false if there already was a breakpoint at `offset`.
+    pub fn add_breakpoint(&mut self, offset: usize) -> bool {
+        self.breakpoints.insert(offset)
+    }
+
+    pub fn remove_breakpoint(&mut self, offset: usize) -> bool {
+        self.breakpoints.remove(&offset)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn watch(&mut self, name: &str) -> bool {
+        self.watches.insert(name.into())
+    }
+
+    pub fn unwatch(&mut self, name: &str) -> bool {
+        self.watches.remove(name)
+    }
+
+    pub fn watches(&self) -> impl Iterator<Item = &str> + '_ {
+        self.watches.iter().map(String::as_str)
+    }
+
+    /// Executes exactly one instruction. Once the program has returned this keeps reporting
+    /// the returned value without touching the vm.
+    pub fn step(&mut self) -> Result<StopReason, InterpreterError> {
+        if let Some(result) = &self.returned {
+            return Ok(StopReason::Returned(result.clone()))
+        }
+
+        let depth = self.vm.call_depth();
+        let before: BTreeMap<&str, Option<Value>> = self.watches.iter()
+            .map(|name| (name.as_str(), self.vm.local(name).cloned()))
+            .collect();
+
+        if let Step::Return(result) = self.vm.step()? {
+            self.returned = Some(result.clone());
+            return Ok(StopReason::Returned(result))
+        }
+
+        // A call or return swaps the visible variables, that is not a change to report.
+        if self.vm.call_depth() == depth {
+            for (name, old) in before {
+                let new = self.vm.local(name).cloned();
+                if new != old {
+                    return Ok(StopReason::WatchChanged { name: name.into(), old, new })
+                }
+            }
+        }
+        Ok(StopReason::Stepped)
+    }
+
+    /// Runs until a breakpoint, a watched variable changes, the program returns or fails. A
+    /// breakpoint on the current instruction is stepped over so repeated calls make progress.
+    pub fn resume(&mut self) -> Result<StopReason, InterpreterError> {
+        let mut first = true;
+        loop {
+            let ip = self.vm.instruction_ptr();
+            if !first && self.returned.is_none() && self.breakpoints.contains(&ip) {
+                return Ok(StopReason::Breakpoint(ip))
+            }
+            first = false;
+            match self.step()? {
+                StopReason::Stepped => {}
+                reason => return Ok(reason),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, lt_loop};
+
+    #[test]
+    fn breakpoints_stop_each_iteration() {
+        let mut debugger = Debugger::with_program(lt_loop());
+        debugger.add_breakpoint(6);
+        for i in 0..3 {
+            assert_eq!(debugger.resume().unwrap(), StopReason::Breakpoint(6));
+            assert_eq!(debugger.vm().vars().get("i"), Some(&i.into()));
+            assert_eq!(debugger.current_instruction(), Some(&Instruction::Read("i".into())));
+        }
+        assert_eq!(debugger.resume().unwrap(), StopReason::Returned(3.into()));
+        assert_eq!(debugger.step().unwrap(), StopReason::Returned(3.into()));
+    }
+
+    #[test]
+    fn watch_reports_changes() {
+        let mut debugger = Debugger::with_program(lt_loop());
+        debugger.watch("i");
+        assert_eq!(debugger.resume().unwrap(),
+            StopReason::WatchChanged { name: "i".into(), old: None, new: Some(0.into()) });
+        assert_eq!(debugger.vm().instruction_ptr(), 2);
+        assert_eq!(debugger.resume().unwrap(),
+            StopReason::WatchChanged { name: "i".into(), old: Some(0.into()), new: Some(1.into()) });
+        debugger.unwatch("i");
+        assert_eq!(debugger.resume().unwrap(), StopReason::Returned(3.into()));
+    }
+
+    #[test]
+    fn linked_variables_are_visible() {
+        let mut vm = Vm::new();
+        vm.load_linked(crate::link::link(&lt_loop()));
+        let mut debugger = Debugger::new(vm);
+        debugger.watch("i");
+        assert_eq!(debugger.resume().unwrap(),
+            StopReason::WatchChanged { name: "i".into(), old: None, new: Some(0.into()) });
+        assert_eq!(debugger.resume().unwrap(),
+            StopReason::WatchChanged { name: "i".into(), old: Some(0.into()), new: Some(1.into()) });
+        assert_eq!(debugger.vm().locals(), [("i".to_string(), 1.into())].into_iter().collect());
+        assert_eq!(debugger.vm().local("i"), Some(&1.into()));
+    }
+
+    #[test]
+    fn single_step_and_restart() {
+        let mut debugger = Debugger::with_program(lt_loop());
+        assert_eq!(debugger.step().unwrap(), StopReason::Stepped);
+        assert_eq!(debugger.vm().stack(), &[0]);
+        debugger.restart();
+        assert_eq!(debugger.vm().instruction_ptr(), 0);
+        assert!(debugger.vm().stack().is_empty());
+    }
+
+    #[test]
+    fn errors_leave_state_for_inspection() {
+        let mut debugger = Debugger::with_program(asm::parse("load 1\nload 0\ndiv\nreturn").unwrap());
+        assert_eq!(debugger.resume(), Err(InterpreterError::DivideByZero));
+        assert_eq!(debugger.vm().instruction_ptr(), 2);
+    }
+}
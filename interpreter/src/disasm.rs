@@ -0,0 +1,228 @@
+// Human readable listings of programs.
+//
+// `disassemble` prints one instruction per line in the `asm` syntax with the offset in a
+// trailing comment, so a listing can be fed back to `asm::parse`. Jump targets get `L<n>`
+// labels and call targets `sub_<n>`, where `n` is the offset of the labelled instruction.
+// Every basic block starts with a `; block` comment.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::asm::mnemonic;
+use crate::{jump_target, Instruction};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub start: usize,
+    // One past the last instruction of the block.
+    pub end: usize,
+    pub successors: Vec<Edge>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    // `JumpIf` goes to its target when the condition is 0 and falls through otherwise.
+    IfFalse,
+    IfTrue,
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    // Start of the block the edge leads to.
+    pub target: usize,
+}
+
+fn leaders(code: &[Instruction]) -> BTreeSet<usize> {
+    let mut leaders = BTreeSet::new();
+    if !code.is_empty() {
+        leaders.insert(0);
+    }
+    for (offset, instruction) in code.iter().enumerate() {
+        match instruction {
+            Instruction::Jump(jump) | Instruction::JumpIf(jump) | Instruction::Call(jump) => {
+                leaders.extend(jump_target(code, *jump));
+                if !matches!(instruction, Instruction::Call(_)) && offset + 1 < code.len() {
+                    leaders.insert(offset + 1);
+                }
+            }
+            Instruction::Ret | Instruction::Return if offset + 1 < code.len() => {
+                leaders.insert(offset + 1);
+            }
+            _ => {}
+        }
+    }
+    leaders
+}
+
+/// Splits the program at jump targets and after every jump, `Ret` and `Return`. Calls stay
+/// inside their block and add a `Call` edge next to the fallthrough.
+pub fn basic_blocks(code: &[Instruction]) -> Vec<BasicBlock> {
+    let leaders: Vec<usize> = leaders(code).into_iter().collect();
+    let mut blocks = Vec::new();
+    for (index, &start) in leaders.iter().enumerate() {
+        let end = leaders.get(index + 1).copied().unwrap_or(code.len());
+        let mut successors = Vec::new();
+        for instruction in &code[start..end] {
+            if let Instruction::Call(offset) = instruction {
+                if let Some(target) = jump_target(code, *offset) {
+                    successors.push(Edge { kind: EdgeKind::Call, target });
+                }
+            }
+        }
+        let falls_through = end < code.len();
+        match &code[end - 1] {
+            Instruction::Jump(offset) => {
+                if let Some(target) = jump_target(code, *offset) {
+                    successors.push(Edge { kind: EdgeKind::Jump, target });
+                }
+            }
+            Instruction::JumpIf(offset) => {
+                if falls_through {
+                    successors.push(Edge { kind: EdgeKind::IfTrue, target: end });
+                }
+                if let Some(target) = jump_target(code, *offset) {
+                    successors.push(Edge { kind: EdgeKind::IfFalse, target });
+                }
+            }
+            Instruction::Ret | Instruction::Return => {}
+            _ if falls_through => successors.push(Edge { kind: EdgeKind::Fallthrough, target: end }),
+            _ => {}
+        }
+        blocks.push(BasicBlock { start, end, successors });
+    }
+    blocks
+}
+
+fn label_names(code: &[Instruction]) -> Vec<Option<String>> {
+    let mut names = vec![None; code.len()];
+    for instruction in code {
+        if let Instruction::Jump(offset) | Instruction::JumpIf(offset) = instruction {
+            if let Some(target) = jump_target(code, *offset) {
+                names[target].get_or_insert_with(|| format!("L{}", target));
+            }
+        }
+    }
+    // Routine names win over plain labels when an offset is both.
+    for instruction in code {
+        if let Instruction::Call(offset) = instruction {
+            if let Some(target) = jump_target(code, *offset) {
+                names[target] = Some(format!("sub_{}", target));
+            }
+        }
+    }
+    names
+}
+
+pub fn disassemble(code: &[Instruction]) -> String {
+    let names = label_names(code);
+    let leaders = leaders(code);
+    let mut out = String::new();
+    for (offset, instruction) in code.iter().enumerate() {
+        if leaders.contains(&offset) {
+            if offset > 0 {
+                out.push('\n');
+            }
+            let _ = writeln!(out, "; block {}", offset);
+        }
+
+        let label = match &names[offset] {
+            Some(name) => format!("{}:", name),
+            None => String::new(),
+        };
+        let text = match instruction {
+            Instruction::Jump(jump) | Instruction::JumpIf(jump) | Instruction::Call(jump) => {
+                match jump_target(code, *jump).and_then(|target| names[target].as_ref()) {
+                    Some(name) => format!("{} {}", mnemonic(instruction), name),
+                    None => instruction.to_string(),
+                }
+            }
+            _ => instruction.to_string(),
+        };
+        // Long labels and instructions push the columns out but stay apart from what follows.
+        let _ = writeln!(out, "{:<9} {:<23} ; {}", label, text, offset);
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Graphviz DOT rendering of `basic_blocks`, one node per block.
+pub fn to_dot(code: &[Instruction]) -> String {
+    let mut out = String::from("digraph program {\n    node [shape=box, fontname=\"monospace\"];\n");
+    for block in basic_blocks(code) {
+        let mut label = String::new();
+        for (offset, instruction) in code[block.start..block.end].iter().enumerate() {
+            let _ = write!(label, "{}: {}\\l", block.start + offset, escape(&instruction.to_string()));
+        }
+        let _ = writeln!(out, "    b{} [label=\"{}\"];", block.start, label);
+        for edge in &block.successors {
+            let attributes = match edge.kind {
+                EdgeKind::Fallthrough | EdgeKind::Jump => "",
+                EdgeKind::IfTrue => " [label=\"true\"]",
+                EdgeKind::IfFalse => " [label=\"false\"]",
+                EdgeKind::Call => " [style=dashed, label=\"call\"]",
+            };
+            let _ = writeln!(out, "    b{} -> b{}{};", block.start, edge.target, attributes);
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, lt_loop, Instruction::*};
+
+    #[test]
+    fn splits_blocks() {
+        let blocks = basic_blocks(&lt_loop());
+        let ranges: Vec<(usize, usize)> = blocks.iter().map(|block| (block.start, block.end)).collect();
+        assert_eq!(ranges, vec![(0, 2), (2, 6), (6, 11), (11, 13)]);
+        assert_eq!(blocks[1].successors, vec![Edge { kind: EdgeKind::IfTrue, target: 6 },
+            Edge { kind: EdgeKind::IfFalse, target: 11 }]);
+        assert_eq!(blocks[2].successors, vec![Edge { kind: EdgeKind::Jump, target: 2 }]);
+        assert!(blocks[3].successors.is_empty());
+    }
+
+    #[test]
+    fn listing_reassembles() {
+        let listing = disassemble(&lt_loop());
+        assert!(listing.starts_with("; block 0\n          load 0                  ; 0\n"));
+        assert!(listing.contains("L2:       read i                  ; 2\n"));
+        assert!(listing.contains("jumpif L11"));
+        assert_eq!(asm::parse(&listing).unwrap(), lt_loop());
+
+        let calls = vec![Load(2), Call(2), Return, Load(2), Mul, Ret, Jump(99)];
+        let listing = disassemble(&calls);
+        assert!(listing.contains("call sub_3"));
+        assert!(listing.contains("sub_3:    load 2"));
+        assert!(listing.contains("jump 99"));
+        assert_eq!(asm::parse(&listing).unwrap(), calls);
+
+        // Labels and instructions wider than their columns.
+        let mut far = vec![Call(9999), Return];
+        far.resize(10000, CallHost("a_rather_long_host_name".into(), 2));
+        far.push(Ret);
+        let listing = disassemble(&far);
+        assert!(listing.contains("sub_10000: ret"));
+        assert!(listing.contains("callhost a_rather_long_host_name 2 ; 2\n"));
+        assert_eq!(asm::parse(&listing).unwrap(), far);
+    }
+
+    #[test]
+    fn dot_output() {
+        let dot = to_dot(&lt_loop());
+        assert!(dot.starts_with("digraph program {\n"));
+        assert!(dot.contains("    b0 [label=\"0: load 0\\l1: write i\\l\"];\n"));
+        assert!(dot.contains("    b0 -> b2;\n"));
+        assert!(dot.contains("    b2 -> b11 [label=\"false\"];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+}
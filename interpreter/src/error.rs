@@ -0,0 +1,178 @@
+// Runtime errors with enough context to find the failing instruction.
+//
+//     error: type mismatch
+//       --> offset 12
+//        |
+//     12 | add
+//        | ^^^
+//        = stack (3 values, top last): 7, 1, "a"
+//        = jumps (oldest first): 3 -> 7, 10 -> 4
+
+use std::fmt;
+
+use crate::{Instruction, InterpreterError, Value};
+
+// How many jumps and stack values a report keeps.
+pub(crate) const TRAIL_LEN: usize = 8;
+pub(crate) const STACK_SNAPSHOT: usize = 8;
+
+/// A taken jump, call or return: the instruction it was at and the one execution resumed at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transfer {
+    pub from: usize,
+    pub to: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: InterpreterError,
+    pub offset: usize,
+    // `None` when `offset` is outside of the program.
+    pub instruction: Option<Instruction>,
+    // Up to `STACK_SNAPSHOT` values from the top of the stack, deepest first.
+    pub stack: Vec<Value>,
+    pub stack_depth: usize,
+    // The most recent transfers of control, oldest first.
+    pub trail: Vec<Transfer>,
+}
+
+impl fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpreterError::UndefinedVariable(name) => write!(f, "undefined variable `{}`", name),
+            InterpreterError::DivideByZero => write!(f, "division by zero"),
+            InterpreterError::StackEmpty => write!(f, "not enough values on the stack"),
+            InterpreterError::BadInstructionOffset => write!(f, "instruction offset out of range"),
+            InterpreterError::CallStackOverflow => write!(f, "call stack overflow"),
+            InterpreterError::CallStackEmpty => write!(f, "`ret` outside of a call"),
+            InterpreterError::OutOfFuel => write!(f, "out of fuel"),
+            InterpreterError::StackOverflow => write!(f, "stack limit exceeded"),
+            InterpreterError::TooManyVariables => write!(f, "variable limit exceeded"),
+            InterpreterError::BadSlot => write!(f, "variable slot out of range"),
+            InterpreterError::Overflow => write!(f, "arithmetic overflow"),
+            InterpreterError::TypeMismatch => write!(f, "type mismatch"),
+            InterpreterError::IndexOutOfBounds => write!(f, "index out of bounds"),
+            InterpreterError::ShiftOutOfRange => write!(f, "shift amount out of range"),
+            InterpreterError::UnknownHostFunction => write!(f, "unknown host function"),
+            InterpreterError::ArityMismatch => write!(f, "wrong number of arguments for host function"),
+            InterpreterError::HostFailure => write!(f, "host function failed"),
+        }
+    }
+}
+
+impl std::error::Error for InterpreterError {}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let number = self.offset.to_string();
+        let gutter = " ".repeat(number.len());
+        writeln!(f, "error: {}", self.kind)?;
+        writeln!(f, "{} --> offset {}", gutter, self.offset)?;
+        writeln!(f, "{} |", gutter)?;
+        match &self.instruction {
+            Some(instruction) => {
+                let text = instruction.to_string();
+                writeln!(f, "{} | {}", number, text)?;
+                writeln!(f, "{} | {}", gutter, "^".repeat(text.chars().count()))?;
+            }
+            None => writeln!(f, "{} = note: this is past the end of the program", gutter)?,
+        }
+
+        write!(f, "{} = stack ({} values, top last): ", gutter, self.stack_depth)?;
+        if self.stack_depth > self.stack.len() {
+            f.write_str("..., ")?;
+        }
+        for (index, value) in self.stack.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", value)?;
+        }
+        writeln!(f)?;
+
+        if !self.trail.is_empty() {
+            write!(f, "{} = jumps (oldest first): ", gutter)?;
+            for (index, transfer) in self.trail.iter().enumerate() {
+                if index > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{} -> {}", transfer.from, transfer.to)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{asm, interpret_both as interpret, InterpreterError, Value};
+
+    #[test]
+    fn reports_offset_stack_and_jumps() {
+        let program = asm::parse("
+                    load 7
+                    load 0
+                    write i
+            loop:   read i
+                    load 2
+                    cmplt
+                    jumpif done
+                    read i
+                    load 1
+                    add
+                    write i
+                    jump loop
+            done:   load \"a\"
+                    add
+                    return
+        ").unwrap();
+        let err = interpret(program).unwrap_err();
+        assert_eq!(err.kind, InterpreterError::TypeMismatch);
+        assert_eq!((err.offset, err.instruction.as_ref().unwrap().to_string()), (13, "add".to_string()));
+        assert_eq!(err.stack, vec![Value::Int(7), Value::from("a")]);
+        let trail: Vec<(usize, usize)> = err.trail.iter().map(|transfer| (transfer.from, transfer.to)).collect();
+        assert_eq!(trail, vec![(11, 3), (11, 3), (6, 12)]);
+        assert_eq!(err.to_string(), "\
+error: type mismatch
+   --> offset 13
+   |
+13 | add
+   | ^^^
+   = stack (2 values, top last): 7, \"a\"
+   = jumps (oldest first): 11 -> 3, 11 -> 3, 6 -> 12
+");
+    }
+
+    #[test]
+    fn undefined_variables_are_named() {
+        let err = interpret(asm::parse("load 1\nread missing\nreturn").unwrap()).unwrap_err();
+        assert_eq!(err.kind, InterpreterError::UndefinedVariable("missing".into()));
+        assert!(err.to_string().starts_with("error: undefined variable `missing`\n  --> offset 1\n"));
+    }
+
+    #[test]
+    fn long_stacks_and_trails_are_cut() {
+        let mut source = String::new();
+        for value in 0..20 {
+            source.push_str(&format!("load {}\n", value));
+        }
+        source.push_str("load 0\nload 30\ncount: load 1\nsub\ndup\njumpif end\njump count\nend: load 0\ndiv\nreturn");
+        let err = interpret(asm::parse(&source).unwrap()).unwrap_err();
+        assert_eq!(err.kind, InterpreterError::DivideByZero);
+        assert_eq!(err.stack_depth, 23);
+        assert_eq!(err.stack.len(), super::STACK_SNAPSHOT);
+        assert_eq!(err.trail.len(), super::TRAIL_LEN);
+        assert!(err.to_string().contains("= stack (23 values, top last): ..., 15, 16"));
+
+        let err = interpret(vec![]).unwrap_err();
+        assert_eq!(err.instruction, None);
+        assert!(err.to_string().contains("= note: this is past the end of the program\n"));
+    }
+}
@@ -0,0 +1,303 @@
+// Random programs for property tests and fuzzing.
+//
+// `program` builds well-formed programs: every jump and call lands inside the program, the last
+// instruction is `Return` and variables come from a handful of names. They still fail in most
+// of the ways the vm can, which is the point. `raw_program` drops those guarantees. `check`
+// runs one case against `reference::evaluate`, a deliberately naive second implementation, and
+// panics on any disagreement. The cargo-fuzz target in `fuzz/` feeds it arbitrary bytes.
+
+pub mod reference;
+
+use crate::link::link;
+use crate::verify::stack_effect;
+use crate::{Engine, Instruction, InterpreterError, Limits, RuntimeError, Step, Value, Vm};
+
+/// Where the generators get their decisions from.
+pub trait Entropy {
+    /// A number in `0..bound`, `bound` must not be 0.
+    fn below(&mut self, bound: u64) -> u64;
+}
+
+/// Small deterministic generator for tests.
+pub struct Lcg(pub u64);
+
+impl Entropy for Lcg {
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % bound
+    }
+}
+
+/// Reads decisions from fuzzer input, as zeros once it runs out.
+pub struct Bytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bytes<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Bytes { data, pos: 0 }
+    }
+}
+
+impl Entropy for Bytes<'_> {
+    fn below(&mut self, bound: u64) -> u64 {
+        let (mut value, mut range) = (0u64, 1u64);
+        while range < bound {
+            let byte = self.data.get(self.pos).copied().unwrap_or(0);
+            self.pos += 1;
+            value = value << 8 | byte as u64;
+            range = range.saturating_mul(256);
+        }
+        value % bound
+    }
+}
+
+// Instructions can run this many steps before they count as not terminating.
+pub const FUEL: u64 = 2_000;
+
+// Strings and arrays double with `dup` and `concat`, runs are abandoned once a value gets
+// this big so a loop can't exhaust memory.
+const MAX_WEIGHT: usize = 1 << 12;
+
+const NAMES: [&str; 3] = ["a", "b", "c"];
+const INTS: [i64; 10] = [0, 1, 2, 3, -1, 7, 63, 64, i64::MAX, i64::MIN];
+const STRS: [&str; 4] = ["", "a", "bc", "h\u{e9}llo"];
+const OPS: [Instruction; 25] = [
+    Instruction::CompareEQ, Instruction::CompareNE, Instruction::CompareGT, Instruction::CompareLT,
+    Instruction::CompareLTE, Instruction::CompareGTE, Instruction::Add, Instruction::Sub,
+    Instruction::Mul, Instruction::Div, Instruction::Mod, Instruction::Neg, Instruction::And,
+    Instruction::Or, Instruction::Xor, Instruction::Not, Instruction::Shl, Instruction::Shr,
+    Instruction::Dup, Instruction::Swap, Instruction::Pop, Instruction::Concat, Instruction::Index,
+    Instruction::Len, Instruction::Ret,
+];
+
+fn pick<'a, T>(entropy: &mut impl Entropy, items: &'a [T]) -> &'a T {
+    &items[entropy.below(items.len() as u64) as usize]
+}
+
+fn load(entropy: &mut impl Entropy) -> Instruction {
+    match entropy.below(8) {
+        0..=3 => Instruction::Load(*pick(entropy, &INTS)),
+        4 | 5 => Instruction::Load(entropy.below(21) as i64 - 10),
+        6 => Instruction::LoadBool(entropy.below(2) == 1),
+        _ => Instruction::LoadStr(pick(entropy, &STRS).to_string()),
+    }
+}
+
+/// A program whose jumps and calls stay inside it and that ends in `Return`.
+pub fn program(entropy: &mut impl Entropy) -> Vec<Instruction> {
+    // Binding every name up front keeps most reads from failing straight away.
+    let mut code = Vec::new();
+    for name in NAMES {
+        code.push(load(entropy));
+        code.push(Instruction::Write(name.into()));
+    }
+    let len = code.len() + 2 + entropy.below(32) as usize;
+    // Stack depth of the straight line code so far, mostly topped up before it runs dry.
+    let mut depth = 0;
+    while code.len() < len - 1 {
+        // Any target below the final `Return` resumes inside the program.
+        let target = entropy.below(len as u64 - 1) as usize;
+        let choice = if depth < 2 && entropy.below(4) > 0 { entropy.below(10) } else { entropy.below(40) };
+        let instruction = match choice {
+            0..=7 => load(entropy),
+            8 | 9 => Instruction::Read(pick(entropy, &NAMES).to_string()),
+            10 | 11 => Instruction::Write(pick(entropy, &NAMES).to_string()),
+            12 => Instruction::Jump(target),
+            13..=15 => Instruction::JumpIf(target),
+            16 => Instruction::Call(target),
+            17 => Instruction::NewArray(entropy.below(4) as usize),
+            18 => Instruction::CallHost("missing".into(), entropy.below(2) as usize),
+            _ => pick(entropy, &OPS).clone(),
+        };
+        let (pops, pushes) = stack_effect(&instruction);
+        depth = (depth - pops).max(0) + pushes;
+        code.push(instruction);
+    }
+    code.push(Instruction::Return);
+    code
+}
+
+fn operand(entropy: &mut impl Entropy) -> usize {
+    match entropy.below(4) {
+        0 => entropy.below(u64::MAX) as usize,
+        _ => entropy.below(48) as usize,
+    }
+}
+
+/// Any sequence of instructions, operands included.
+pub fn raw_program(entropy: &mut impl Entropy) -> Vec<Instruction> {
+    (0..entropy.below(48)).map(|_| match entropy.below(16) {
+        0..=3 => load(entropy),
+        4 => Instruction::Read(pick(entropy, &NAMES).to_string()),
+        5 => Instruction::Write(pick(entropy, &NAMES).to_string()),
+        6 => Instruction::ReadSlot(operand(entropy)),
+        7 => Instruction::WriteSlot(operand(entropy)),
+        8 => Instruction::Jump(operand(entropy)),
+        9 => Instruction::JumpIf(operand(entropy)),
+        10 => Instruction::Call(operand(entropy)),
+        11 => Instruction::NewArray(operand(entropy)),
+        12 => Instruction::CallHost(pick(entropy, &NAMES).to_string(), operand(entropy)),
+        13 => Instruction::Return,
+        _ => pick(entropy, &OPS).clone(),
+    }).collect()
+}
+
+// Size of a value in characters and elements, counting stops past `MAX_WEIGHT`.
+fn weight(value: &Value) -> usize {
+    match value {
+        Value::Int(_) | Value::Bool(_) => 1,
+        Value::Str(text) => text.len(),
+        Value::Array(values) => {
+            let mut total = 1;
+            for value in values {
+                total += weight(value);
+                if total > MAX_WEIGHT {
+                    break
+                }
+            }
+            total
+        }
+    }
+}
+
+// Like `Vm::run`, `None` if a value grew past `MAX_WEIGHT`.
+fn run_bounded(vm: &mut Vm) -> Option<Result<Value, RuntimeError>> {
+    loop {
+        match vm.step() {
+            Ok(Step::Return(result)) => return Some(Ok(result)),
+            Ok(Step::Continue) => {}
+            Err(kind) => return Some(Err(vm.error_report(kind))),
+        }
+        if vm.stack().last().is_some_and(|top| weight(top) > MAX_WEIGHT) {
+            return None
+        }
+    }
+}
+
+fn fueled(fuel: u64) -> Vm {
+    Vm::with_limits(Limits { fuel: Some(fuel), ..Limits::default() })
+}
+
+/// Runs `code` on the vm, linked, resumed from a snapshot and on the reference evaluator and
+/// panics unless all of them agree and any error is one `code` can actually cause.
+pub fn check_program(code: &[Instruction]) {
+    let mut vm = fueled(FUEL);
+    vm.load_program(code.to_vec());
+    let Some(result) = run_bounded(&mut vm) else { return };
+    let fuel = vm.fuel_consumed();
+
+    if let Err(err) = &result {
+        // Without limits or host functions these can't happen.
+        assert!(!matches!(err.kind, InterpreterError::TooManyVariables | InterpreterError::StackOverflow
+            | InterpreterError::ArityMismatch | InterpreterError::HostFailure), "{}\n{:?}", err, code);
+        let uses_slots = code.iter().any(|instruction| matches!(instruction, Instruction::ReadSlot(_) | Instruction::WriteSlot(_)));
+        assert!(uses_slots || err.kind != InterpreterError::BadSlot, "{:?}", code);
+        assert_eq!(err.offset, vm.instruction_ptr());
+        assert_eq!(err.instruction.as_ref(), code.get(err.offset));
+        assert!(err.stack.len() <= err.stack_depth && err.stack_depth == vm.stack().len());
+        assert!(err.to_string().starts_with("error: "));
+    }
+
+    // The threaded engine fuses instructions but must not be told apart from `step`.
+    let mut threaded = fueled(FUEL);
+    threaded.set_engine(Engine::Threaded);
+    threaded.load_program(code.to_vec());
+    assert_eq!(threaded.run(), result, "{:?}", code);
+    assert_eq!((threaded.fuel_consumed(), threaded.stack(), threaded.vars()), (fuel, vm.stack(), vm.vars()), "{:?}", code);
+
+    let result = result.map_err(|err| err.kind);
+    assert_eq!(result, reference::evaluate(code, FUEL), "{:?}", code);
+
+    // Linking would give existing slot instructions a meaning.
+    if !code.iter().any(|instruction| matches!(instruction, Instruction::ReadSlot(_) | Instruction::WriteSlot(_))) {
+        let mut linked = fueled(FUEL);
+        linked.load_linked(link(code));
+        assert_eq!(run_bounded(&mut linked).map(|result| result.map_err(|err| err.kind)), Some(result.clone()), "{:?}", code);
+        let mut linked = fueled(FUEL);
+        linked.set_engine(Engine::Threaded);
+        linked.load_linked(link(code));
+        assert_eq!(linked.run().map_err(|err| err.kind), result, "{:?}", code);
+    }
+
+    // Stopping halfway and resuming in a fresh vm changes nothing.
+    let mut first = fueled(fuel / 2);
+    first.load_program(code.to_vec());
+    if run_bounded(&mut first).is_some_and(|result| result.is_err_and(|err| err.kind == InterpreterError::OutOfFuel)) {
+        let mut resumed = Vm::new();
+        resumed.restore(code.to_vec(), &first.snapshot()).unwrap();
+        resumed.set_limits(Limits { fuel: Some(FUEL), ..Limits::default() });
+        let found = run_bounded(&mut resumed).unwrap().map_err(|err| err.kind);
+        assert_eq!(found, result, "{:?}", code);
+        assert_eq!(resumed.fuel_consumed(), fuel);
+    }
+}
+
+/// The fuzz target: decodes `data` into a well-formed or a raw program and checks it.
+pub fn check(data: &[u8]) {
+    let mut entropy = Bytes::new(data);
+    let code = match entropy.below(2) {
+        0 => program(&mut entropy),
+        _ => raw_program(&mut entropy),
+    };
+    check_program(&code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::{verify, DiagnosticKind};
+
+    #[test]
+    fn generated_programs_agree_with_the_reference() {
+        let mut rng = Lcg(0x5eed);
+        for _ in 0..3000 {
+            check_program(&program(&mut rng));
+        }
+    }
+
+    #[test]
+    fn raw_programs_agree_with_the_reference() {
+        let mut rng = Lcg(7);
+        for _ in 0..3000 {
+            check_program(&raw_program(&mut rng));
+        }
+    }
+
+    #[test]
+    fn generated_programs_are_well_formed() {
+        let mut rng = Lcg(1);
+        for _ in 0..500 {
+            let code = program(&mut rng);
+            assert_eq!(code.last(), Some(&Instruction::Return));
+            assert!(!verify(&code).iter().any(|diagnostic| matches!(diagnostic.kind,
+                DiagnosticKind::JumpOutOfRange(_) | DiagnosticKind::EmptyProgram)), "{:?}", code);
+            // Nothing can run off the end or jump outside.
+            let mut vm = fueled(FUEL);
+            vm.load_program(code.clone());
+            if let Some(Err(err)) = run_bounded(&mut vm) {
+                assert_ne!(err.kind, InterpreterError::BadInstructionOffset, "{:?}", code);
+            }
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes() {
+        let mut rng = Lcg(99);
+        check(&[]);
+        for len in 0..2000 {
+            let data: Vec<u8> = (0..len % 200).map(|_| rng.below(256) as u8).collect();
+            check(&data);
+        }
+    }
+
+    #[test]
+    fn runaway_concatenation_is_abandoned() {
+        let code = crate::asm::parse("load \"ab\"\nloop: dup\nconcat\njump loop").unwrap();
+        let mut vm = fueled(FUEL);
+        vm.load_program(code.clone());
+        assert!(run_bounded(&mut vm).is_none());
+        check_program(&code);
+    }
+}
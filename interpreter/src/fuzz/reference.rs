@@ -0,0 +1,233 @@
+// A second, deliberately plain implementation of the instruction set to check the vm against.
+//
+// Operands are popped as soon as they are needed and every call gets its own variable map, no
+// macros and no error reports. Only what a fresh `Vm` with a fuel limit does is covered:
+// checked arithmetic, no host functions and no slots.
+
+use std::collections::HashMap;
+use std::mem::discriminant;
+
+use crate::{Instruction, InterpreterError, Value, MAX_CALL_DEPTH};
+
+struct Machine {
+    stack: Vec<Value>,
+    globals: HashMap<String, Value>,
+    // Offset of each active `Call` and its variables.
+    calls: Vec<(usize, HashMap<String, Value>)>,
+}
+
+impl Machine {
+    fn pop(&mut self) -> Result<Value, InterpreterError> {
+        self.stack.pop().ok_or(InterpreterError::StackEmpty)
+    }
+
+    fn pop_pair(&mut self) -> Result<(Value, Value), InterpreterError> {
+        if self.stack.len() < 2 {
+            return Err(InterpreterError::StackEmpty)
+        }
+        let rhs = self.pop()?;
+        Ok((self.pop()?, rhs))
+    }
+
+    fn pop_ints(&mut self) -> Result<(i64, i64), InterpreterError> {
+        match self.pop_pair()? {
+            (Value::Int(lhs), Value::Int(rhs)) => Ok((lhs, rhs)),
+            _ => Err(InterpreterError::TypeMismatch),
+        }
+    }
+
+    fn vars(&mut self) -> &mut HashMap<String, Value> {
+        match self.calls.last_mut() {
+            Some((_, vars)) => vars,
+            None => &mut self.globals,
+        }
+    }
+}
+
+fn arithmetic(lhs: i64, rhs: i64, op: &Instruction) -> Result<i64, InterpreterError> {
+    let result = match op {
+        Instruction::Add => lhs.checked_add(rhs),
+        Instruction::Sub => lhs.checked_sub(rhs),
+        Instruction::Mul => lhs.checked_mul(rhs),
+        Instruction::Div | Instruction::Mod if rhs == 0 => return Err(InterpreterError::DivideByZero),
+        Instruction::Div => lhs.checked_div(rhs),
+        Instruction::Mod => lhs.checked_rem(rhs),
+        Instruction::Shl | Instruction::Shr if !(0..64).contains(&rhs) => {
+            return Err(InterpreterError::ShiftOutOfRange)
+        }
+        Instruction::Shl => Some(lhs << rhs),
+        Instruction::Shr => Some(lhs >> rhs),
+        _ => unreachable!("not an arithmetic instruction"),
+    };
+    result.ok_or(InterpreterError::Overflow)
+}
+
+fn execute(machine: &mut Machine, code: &[Instruction], offset: usize) -> Result<Option<usize>, InterpreterError> {
+    let mut next = offset + 1;
+    match &code[offset] {
+        Instruction::Load(value) => machine.stack.push(Value::Int(*value)),
+        Instruction::LoadBool(value) => machine.stack.push(Value::Bool(*value)),
+        Instruction::LoadStr(text) => machine.stack.push(Value::Str(text.clone())),
+        Instruction::Read(name) => {
+            let value = machine.vars().get(name).cloned();
+            machine.stack.push(value.ok_or_else(|| InterpreterError::UndefinedVariable(name.clone()))?);
+        }
+        Instruction::Write(name) => {
+            let value = machine.pop()?;
+            machine.vars().insert(name.clone(), value);
+        }
+        Instruction::ReadSlot(_) => return Err(InterpreterError::BadSlot),
+        Instruction::WriteSlot(_) => {
+            machine.pop()?;
+            return Err(InterpreterError::BadSlot)
+        }
+        Instruction::Jump(target) => {
+            if *target >= code.len() {
+                return Err(InterpreterError::BadInstructionOffset)
+            }
+            next = target + 1;
+        }
+        Instruction::JumpIf(target) => {
+            let taken = match machine.pop()? {
+                Value::Int(value) => value == 0,
+                Value::Bool(value) => !value,
+                _ => return Err(InterpreterError::TypeMismatch),
+            };
+            if taken {
+                if *target >= code.len() {
+                    return Err(InterpreterError::BadInstructionOffset)
+                }
+                next = target + 1;
+            }
+        }
+        Instruction::Call(target) => {
+            if *target >= code.len() {
+                return Err(InterpreterError::BadInstructionOffset)
+            }
+            if machine.calls.len() >= MAX_CALL_DEPTH {
+                return Err(InterpreterError::CallStackOverflow)
+            }
+            machine.calls.push((offset, HashMap::new()));
+            next = target + 1;
+        }
+        Instruction::Ret => {
+            let (call, _) = machine.calls.pop().ok_or(InterpreterError::CallStackEmpty)?;
+            next = call + 1;
+        }
+        Instruction::CallHost(..) => return Err(InterpreterError::UnknownHostFunction),
+        Instruction::CompareEQ | Instruction::CompareNE | Instruction::CompareGT
+            | Instruction::CompareLT | Instruction::CompareLTE | Instruction::CompareGTE => {
+            let (lhs, rhs) = machine.pop_pair()?;
+            if discriminant(&lhs) != discriminant(&rhs) {
+                return Err(InterpreterError::TypeMismatch)
+            }
+            machine.stack.push(Value::Bool(match &code[offset] {
+                Instruction::CompareEQ => lhs == rhs,
+                Instruction::CompareNE => lhs != rhs,
+                Instruction::CompareGT => lhs > rhs,
+                Instruction::CompareLT => lhs < rhs,
+                Instruction::CompareLTE => lhs <= rhs,
+                _ => lhs >= rhs,
+            }));
+        }
+        op @ (Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div
+            | Instruction::Mod | Instruction::Shl | Instruction::Shr) => {
+            let (lhs, rhs) = machine.pop_ints()?;
+            machine.stack.push(Value::Int(arithmetic(lhs, rhs, op)?));
+        }
+        Instruction::Neg => match machine.pop()? {
+            Value::Int(value) => machine.stack.push(Value::Int(value.checked_neg().ok_or(InterpreterError::Overflow)?)),
+            _ => return Err(InterpreterError::TypeMismatch),
+        },
+        op @ (Instruction::And | Instruction::Or | Instruction::Xor) => {
+            let result = match (machine.pop_pair()?, op) {
+                ((Value::Int(lhs), Value::Int(rhs)), Instruction::And) => Value::Int(lhs & rhs),
+                ((Value::Int(lhs), Value::Int(rhs)), Instruction::Or) => Value::Int(lhs | rhs),
+                ((Value::Int(lhs), Value::Int(rhs)), _) => Value::Int(lhs ^ rhs),
+                ((Value::Bool(lhs), Value::Bool(rhs)), Instruction::And) => Value::Bool(lhs && rhs),
+                ((Value::Bool(lhs), Value::Bool(rhs)), Instruction::Or) => Value::Bool(lhs || rhs),
+                ((Value::Bool(lhs), Value::Bool(rhs)), _) => Value::Bool(lhs != rhs),
+                _ => return Err(InterpreterError::TypeMismatch),
+            };
+            machine.stack.push(result);
+        }
+        Instruction::Not => match machine.pop()? {
+            Value::Int(value) => machine.stack.push(Value::Int(!value)),
+            Value::Bool(value) => machine.stack.push(Value::Bool(!value)),
+            _ => return Err(InterpreterError::TypeMismatch),
+        },
+        Instruction::Dup => {
+            let top = machine.stack.last().cloned().ok_or(InterpreterError::StackEmpty)?;
+            machine.stack.push(top);
+        }
+        Instruction::Swap => {
+            let (lhs, rhs) = machine.pop_pair()?;
+            machine.stack.push(rhs);
+            machine.stack.push(lhs);
+        }
+        Instruction::Pop => {
+            machine.pop()?;
+        }
+        Instruction::NewArray(len) => {
+            if *len > machine.stack.len() {
+                return Err(InterpreterError::StackEmpty)
+            }
+            let mut values = Vec::new();
+            for _ in 0..*len {
+                values.insert(0, machine.pop()?);
+            }
+            machine.stack.push(Value::Array(values));
+        }
+        Instruction::Concat => match machine.pop_pair()? {
+            (Value::Str(lhs), Value::Str(rhs)) => machine.stack.push(Value::Str(lhs + &rhs)),
+            (Value::Array(mut lhs), Value::Array(rhs)) => {
+                lhs.extend(rhs);
+                machine.stack.push(Value::Array(lhs));
+            }
+            _ => return Err(InterpreterError::TypeMismatch),
+        },
+        Instruction::Index => {
+            let element = match machine.pop_pair()? {
+                (Value::Array(values), Value::Int(index)) => {
+                    if index < 0 || index as u64 >= values.len() as u64 {
+                        return Err(InterpreterError::IndexOutOfBounds)
+                    }
+                    values[index as usize].clone()
+                }
+                (Value::Str(text), Value::Int(index)) => {
+                    let chars: Vec<char> = text.chars().collect();
+                    if index < 0 || index as u64 >= chars.len() as u64 {
+                        return Err(InterpreterError::IndexOutOfBounds)
+                    }
+                    Value::Str(chars[index as usize].to_string())
+                }
+                _ => return Err(InterpreterError::TypeMismatch),
+            };
+            machine.stack.push(element);
+        }
+        Instruction::Len => match machine.pop()? {
+            Value::Str(text) => machine.stack.push(Value::Int(text.chars().count() as i64)),
+            Value::Array(values) => machine.stack.push(Value::Int(values.len() as i64)),
+            _ => return Err(InterpreterError::TypeMismatch),
+        },
+        Instruction::Return => return Ok(None),
+    }
+    Ok(Some(next))
+}
+
+/// Runs `code` until `Return` or an error, failing with `OutOfFuel` once `fuel` instructions
+/// have executed.
+pub fn evaluate(code: &[Instruction], fuel: u64) -> Result<Value, InterpreterError> {
+    let mut machine = Machine { stack: Vec::new(), globals: HashMap::new(), calls: Vec::new() };
+    let mut offset = 0;
+    for _ in 0..fuel {
+        if offset >= code.len() {
+            return Err(InterpreterError::BadInstructionOffset)
+        }
+        match execute(&mut machine, code, offset)? {
+            Some(next) => offset = next,
+            None => return machine.pop(),
+        }
+    }
+    Err(InterpreterError::OutOfFuel)
+}
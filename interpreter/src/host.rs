@@ -0,0 +1,133 @@
+// Rust functions callable from bytecode through `CallHost(name, argc)`.
+//
+// A host function only ever sees its arguments, never the vm, so the embedder decides exactly
+// what a script can reach. Arguments arrive in the order they were pushed and the function's
+// result is pushed in their place.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{InterpreterError, Value};
+
+type Function = Box<dyn FnMut(&[Value]) -> Result<Value, InterpreterError>>;
+
+struct HostFunction {
+    arity: usize,
+    function: Function,
+}
+
+#[derive(Default)]
+pub struct HostRegistry {
+    functions: HashMap<String, HostFunction>,
+}
+
+impl HostRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `function` under `name`, replacing any earlier registration. Returns true if
+    /// there was one.
+    pub fn register<F>(&mut self, name: &str, arity: usize, function: F) -> bool
+    where
+        F: FnMut(&[Value]) -> Result<Value, InterpreterError> + 'static,
+    {
+        let function = HostFunction { arity, function: Box::new(function) };
+        self.functions.insert(name.into(), function).is_some()
+    }
+
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.functions.remove(name).is_some()
+    }
+
+    pub fn arity(&self, name: &str) -> Option<usize> {
+        self.functions.get(name).map(|function| function.arity)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.functions.keys().map(String::as_str)
+    }
+
+    pub(crate) fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, InterpreterError> {
+        let function = match self.functions.get_mut(name) {
+            Some(function) => function,
+            None => return Err(InterpreterError::UnknownHostFunction),
+        };
+        if function.arity != args.len() {
+            return Err(InterpreterError::ArityMismatch)
+        }
+        (function.function)(args)
+    }
+}
+
+impl fmt::Debug for HostRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&str> = self.names().collect();
+        names.sort_unstable();
+        f.debug_struct("HostRegistry").field("functions", &names).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::{asm, lang, Instruction::*, InterpreterError, Value, Vm};
+
+    fn run(vm: &mut Vm, code: Vec<crate::Instruction>) -> Result<Value, InterpreterError> {
+        vm.load_program(code);
+        vm.run().map_err(|err| err.kind)
+    }
+
+    #[test]
+    fn calls_registered_functions() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::new();
+        let sink = log.clone();
+        vm.host_mut().register("log", 1, move |args| {
+            sink.borrow_mut().push(args[0].to_string());
+            Ok(Value::Bool(true))
+        });
+        vm.host_mut().register("sub", 2, |args| match args {
+            [Value::Int(lhs), Value::Int(rhs)] => Ok(Value::Int(lhs - rhs)),
+            _ => Err(InterpreterError::TypeMismatch),
+        });
+
+        let code = asm::parse("
+                    load \"start\"
+                    callhost log 1
+                    write ok
+                    load 10
+                    load 3
+                    callhost sub 2
+                    return
+        ").unwrap();
+        assert_eq!(run(&mut vm, code), Ok(Value::Int(7)));
+        assert_eq!(vm.vars().get("ok"), Some(&Value::Bool(true)));
+
+        let code = lang::compile("x = sub(len(\"abcd\"), 1); done = log(\"x is \" ++ \"3\"); return x").unwrap();
+        assert_eq!(run(&mut vm, code), Ok(Value::Int(3)));
+        assert_eq!(*log.borrow(), vec!["\"start\"", "\"x is 3\""]);
+    }
+
+    #[test]
+    fn reports_unknown_functions_and_arity() {
+        let mut vm = Vm::new();
+        vm.host_mut().register("zero", 0, |_| Ok(Value::Int(0)));
+        assert_eq!(run(&mut vm, vec![CallHost("nope".into(), 0), Return]), Err(InterpreterError::UnknownHostFunction));
+        assert_eq!(run(&mut vm, vec![Load(1), CallHost("zero".into(), 1), Return]), Err(InterpreterError::ArityMismatch));
+        assert_eq!(run(&mut vm, vec![CallHost("zero".into(), 0), Return]), Ok(Value::Int(0)));
+        assert_eq!(vm.host().arity("zero"), Some(0));
+    }
+
+    #[test]
+    fn failures_leave_arguments_on_the_stack() {
+        let mut vm = Vm::new();
+        vm.host_mut().register("fail", 2, |_| Err(InterpreterError::HostFailure));
+        assert_eq!(run(&mut vm, vec![Load(1), Load(2), CallHost("fail".into(), 2), Return]),
+            Err(InterpreterError::HostFailure));
+        assert_eq!(vm.stack(), &[1, 2]);
+        assert_eq!(run(&mut vm, vec![Load(1), CallHost("fail".into(), 2), Return]), Err(InterpreterError::StackEmpty));
+    }
+}
@@ -0,0 +1,18 @@
+// Bits of JSON output shared by the trace sink and `loc`.
+
+use std::io::{self, Write};
+
+pub(crate) fn write_json_string(out: &mut impl Write, text: &str) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for ch in text.chars() {
+        match ch {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\t' => out.write_all(b"\\t")?,
+            ch if (ch as u32) < 0x20 => write!(out, "\\u{:04x}", ch as u32)?,
+            ch => write!(out, "{}", ch)?,
+        }
+    }
+    out.write_all(b"\"")
+}
@@ -0,0 +1,162 @@
+use super::ast::{BinaryOp, Expr, ExprKind, Stmt};
+use crate::{offset_for_target, Instruction};
+
+// Falling off the end of a program returns 0.
+pub fn generate(program: &[Stmt]) -> Vec<Instruction> {
+    let mut code = statements(program);
+    if !matches!(program.last(), Some(Stmt::Return(_))) {
+        code.push(Instruction::Load(0));
+        code.push(Instruction::Return);
+    }
+    code
+}
+
+// Without the implicit `return 0`.
+pub fn statements(stmts: &[Stmt]) -> Vec<Instruction> {
+    let mut gen = CodeGen { code: Vec::new() };
+    gen.stmts(stmts);
+    gen.code
+}
+
+// Leaves the value of `expr` on the stack.
+pub fn expression(expr: &Expr) -> Vec<Instruction> {
+    let mut gen = CodeGen { code: Vec::new() };
+    gen.expr(expr);
+    gen.code
+}
+
+struct CodeGen {
+    code: Vec<Instruction>,
+}
+
+impl CodeGen {
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Assign { name, op, value, .. } => {
+                if let Some(op) = op {
+                    self.code.push(Instruction::Read(name.clone()));
+                    self.expr(value);
+                    self.code.push(binary_instruction(*op));
+                } else {
+                    self.expr(value);
+                }
+                self.code.push(Instruction::Write(name.clone()));
+            }
+            Stmt::If { cond, then_body, else_body } => {
+                self.expr(cond);
+                let skip_then = self.emit_jump(Instruction::JumpIf(0));
+                self.stmts(then_body);
+                if else_body.is_empty() {
+                    self.patch_to_here(skip_then);
+                } else {
+                    let skip_else = self.emit_jump(Instruction::Jump(0));
+                    self.patch_to_here(skip_then);
+                    self.stmts(else_body);
+                    self.patch_to_here(skip_else);
+                }
+            }
+            Stmt::While { cond, body } => {
+                // No offset resumes at instruction 0, so a loop opening the program gets a
+                // `Jump(0)` in front which simply falls through to instruction 1.
+                if self.code.is_empty() {
+                    self.code.push(Instruction::Jump(0));
+                }
+                let head = self.code.len();
+                self.expr(cond);
+                let exit = self.emit_jump(Instruction::JumpIf(0));
+                self.stmts(body);
+                self.emit_jump(Instruction::Jump(0));
+                self.patch(self.code.len() - 1, head);
+                self.patch_to_here(exit);
+            }
+            Stmt::Return(value) => {
+                self.expr(value);
+                self.code.push(Instruction::Return);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Int(value) => self.code.push(Instruction::Load(*value)),
+            ExprKind::Bool(value) => self.code.push(Instruction::LoadBool(*value)),
+            ExprKind::Str(text) => self.code.push(Instruction::LoadStr(text.clone())),
+            ExprKind::Array(elements) => {
+                for element in elements {
+                    self.expr(element);
+                }
+                self.code.push(Instruction::NewArray(elements.len()));
+            }
+            ExprKind::Var(name) => self.code.push(Instruction::Read(name.clone())),
+            ExprKind::Index(value, index) => {
+                self.expr(value);
+                self.expr(index);
+                self.code.push(Instruction::Index);
+            }
+            ExprKind::Len(value) => {
+                self.expr(value);
+                self.code.push(Instruction::Len);
+            }
+            ExprKind::Call(name, args) => {
+                for arg in args {
+                    self.expr(arg);
+                }
+                self.code.push(Instruction::CallHost(name.clone(), args.len()));
+            }
+            ExprKind::Neg(operand) => {
+                self.expr(operand);
+                self.code.push(Instruction::Neg);
+            }
+            ExprKind::Not(operand) => {
+                self.expr(operand);
+                self.code.push(Instruction::Not);
+            }
+            ExprKind::Binary(op, lhs, rhs) => {
+                self.expr(lhs);
+                self.expr(rhs);
+                self.code.push(binary_instruction(*op));
+            }
+        }
+    }
+
+    fn emit_jump(&mut self, jump: Instruction) -> usize {
+        self.code.push(jump);
+        self.code.len() - 1
+    }
+
+    fn patch_to_here(&mut self, jump: usize) {
+        self.patch(jump, self.code.len());
+    }
+
+    // Every jump target follows at least one emitted instruction, so it always has an offset.
+    fn patch(&mut self, jump: usize, target: usize) {
+        let offset = offset_for_target(target).expect("jump targets are never instruction 0");
+        match &mut self.code[jump] {
+            Instruction::Jump(slot) | Instruction::JumpIf(slot) => *slot = offset,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+}
+
+fn binary_instruction(op: BinaryOp) -> Instruction {
+    match op {
+        BinaryOp::Add => Instruction::Add,
+        BinaryOp::Sub => Instruction::Sub,
+        BinaryOp::Mul => Instruction::Mul,
+        BinaryOp::Div => Instruction::Div,
+        BinaryOp::Mod => Instruction::Mod,
+        BinaryOp::Eq => Instruction::CompareEQ,
+        BinaryOp::Ne => Instruction::CompareNE,
+        BinaryOp::Lt => Instruction::CompareLT,
+        BinaryOp::Gt => Instruction::CompareGT,
+        BinaryOp::Lte => Instruction::CompareLTE,
+        BinaryOp::Gte => Instruction::CompareGTE,
+        BinaryOp::Concat => Instruction::Concat,
+    }
+}
@@ -0,0 +1,198 @@
+use super::{CompileError, ErrorKind, Span};
+use crate::value::unescape;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Int(i64),
+    Str(String),
+    Ident(String),
+    True,
+    False,
+    If,
+    Else,
+    While,
+    Return,
+    Plus,
+    PlusPlus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Bang,
+    Assign,
+    PlusAssign,
+    MinusAssign,
+    StarAssign,
+    SlashAssign,
+    PercentAssign,
+    EqEq,
+    NotEq,
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Comma,
+    Semicolon,
+    Eof,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+pub struct Lexer<'a> {
+    source: &'a str,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Lexer { source, pos: 0, line: 1, column: 1 }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.pos += ch.len_utf8();
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(ch)
+    }
+
+    fn skip_trivia(&mut self) {
+        loop {
+            match self.peek() {
+                Some(ch) if ch.is_whitespace() => {
+                    self.bump();
+                }
+                Some('/') if self.source[self.pos..].starts_with("//") => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.bump();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    pub fn tokenize(mut self) -> Result<Vec<Token>, CompileError> {
+        let mut tokens = Vec::new();
+        loop {
+            self.skip_trivia();
+            let start = Span { start: self.pos, end: self.pos, line: self.line, column: self.column };
+            let ch = match self.bump() {
+                Some(ch) => ch,
+                None => {
+                    tokens.push(Token { kind: TokenKind::Eof, span: start });
+                    return Ok(tokens)
+                }
+            };
+
+            let kind = match ch {
+                '0'..='9' => {
+                    while matches!(self.peek(), Some('0'..='9')) {
+                        self.bump();
+                    }
+                    let text = &self.source[start.start..self.pos];
+                    match text.parse() {
+                        Ok(value) => TokenKind::Int(value),
+                        Err(_) => {
+                            let span = Span { end: self.pos, ..start };
+                            return Err(CompileError { kind: ErrorKind::IntegerOutOfRange, span })
+                        }
+                    }
+                }
+                ch if ch.is_ascii_alphabetic() || ch == '_' => {
+                    while matches!(self.peek(), Some(ch) if ch.is_ascii_alphanumeric() || ch == '_') {
+                        self.bump();
+                    }
+                    match &self.source[start.start..self.pos] {
+                        "if" => TokenKind::If,
+                        "else" => TokenKind::Else,
+                        "while" => TokenKind::While,
+                        "return" => TokenKind::Return,
+                        "true" => TokenKind::True,
+                        "false" => TokenKind::False,
+                        name => TokenKind::Ident(name.into()),
+                    }
+                }
+                '"' => {
+                    let mut closed = false;
+                    while let Some(ch) = self.bump() {
+                        match ch {
+                            '\\' => {
+                                self.bump();
+                            }
+                            '"' => {
+                                closed = true;
+                                break
+                            }
+                            _ => {}
+                        }
+                    }
+                    let span = Span { end: self.pos, ..start };
+                    if !closed {
+                        return Err(CompileError { kind: ErrorKind::UnterminatedString, span })
+                    }
+                    match unescape(&self.source[start.start + 1..self.pos - 1]) {
+                        Ok(text) => TokenKind::Str(text),
+                        Err(ch) => return Err(CompileError { kind: ErrorKind::InvalidEscape(ch), span }),
+                    }
+                }
+                '+' if self.peek() == Some('+') => {
+                    self.bump();
+                    TokenKind::PlusPlus
+                }
+                '+' => self.with_assign(TokenKind::Plus, TokenKind::PlusAssign),
+                '-' => self.with_assign(TokenKind::Minus, TokenKind::MinusAssign),
+                '*' => self.with_assign(TokenKind::Star, TokenKind::StarAssign),
+                '/' => self.with_assign(TokenKind::Slash, TokenKind::SlashAssign),
+                '%' => self.with_assign(TokenKind::Percent, TokenKind::PercentAssign),
+                '=' => self.with_assign(TokenKind::Assign, TokenKind::EqEq),
+                '<' => self.with_assign(TokenKind::Lt, TokenKind::Lte),
+                '>' => self.with_assign(TokenKind::Gt, TokenKind::Gte),
+                '!' => self.with_assign(TokenKind::Bang, TokenKind::NotEq),
+                '(' => TokenKind::LParen,
+                ')' => TokenKind::RParen,
+                '{' => TokenKind::LBrace,
+                '}' => TokenKind::RBrace,
+                '[' => TokenKind::LBracket,
+                ']' => TokenKind::RBracket,
+                ',' => TokenKind::Comma,
+                ';' => TokenKind::Semicolon,
+                other => {
+                    let span = Span { end: self.pos, ..start };
+                    return Err(CompileError { kind: ErrorKind::UnexpectedChar(other), span })
+                }
+            };
+            tokens.push(Token { kind, span: Span { end: self.pos, ..start } });
+        }
+    }
+
+    // Picks `assigned` when the operator is directly followed by `=`.
+    fn with_assign(&mut self, plain: TokenKind, assigned: TokenKind) -> TokenKind {
+        if self.peek() == Some('=') {
+            self.bump();
+            assigned
+        } else {
+            plain
+        }
+    }
+}
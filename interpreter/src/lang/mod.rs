@@ -0,0 +1,182 @@
+// A small source language compiled to interpreter bytecode:
+//
+//     i = 0
+//     while i < 3 { i += 1 }
+//     if i == 3 { return i * 2 } else { return -1 }
+//
+// Statements are assignments (`=`, `+=`, `-=`, `*=`, `/=`, `%=`), `if`/`else`, `while` and
+// `return`; `;` between statements is optional and `//` starts a comment. Besides integers
+// there are `true`/`false`, double quoted strings and `[a, b]` arrays, which support `++`,
+// indexing with `x[i]` and `len(x)`. Any other `f(a, b)` calls the host function `f`.
+// Conditions are false when they evaluate to `false` or 0.
+// A program that runs past its last statement returns 0.
+
+pub mod ast;
+mod codegen;
+mod lexer;
+mod parser;
+
+use std::fmt;
+
+use crate::Instruction;
+
+/// Byte range of the source plus the line and column (both 1-based) where it starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    UnexpectedChar(char),
+    IntegerOutOfRange,
+    UnterminatedString,
+    InvalidEscape(char),
+    UnexpectedToken { expected: &'static str },
+    UnexpectedEof { expected: &'static str },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub kind: ErrorKind,
+    pub span: Span,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: ", self.span.line, self.span.column)?;
+        match &self.kind {
+            ErrorKind::UnexpectedChar(ch) => write!(f, "unexpected character `{}`", ch),
+            ErrorKind::IntegerOutOfRange => write!(f, "integer literal is out of range"),
+            ErrorKind::UnterminatedString => write!(f, "unterminated string literal"),
+            ErrorKind::InvalidEscape(ch) => write!(f, "invalid escape `\\{}`", ch),
+            ErrorKind::UnexpectedToken { expected } => write!(f, "expected {}", expected),
+            ErrorKind::UnexpectedEof { expected } => write!(f, "expected {}, found end of input", expected),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+pub fn parse(source: &str) -> Result<Vec<ast::Stmt>, CompileError> {
+    let tokens = lexer::Lexer::new(source).tokenize()?;
+    parser::Parser::new(tokens).parse_program()
+}
+
+pub fn compile(source: &str) -> Result<Vec<Instruction>, CompileError> {
+    Ok(codegen::generate(&parse(source)?))
+}
+
+/// Compiles one entry of an interactive session: statements run without the implicit
+/// `return 0`, a lone expression leaves its value on the stack.
+pub fn compile_entry(source: &str) -> Result<Vec<Instruction>, CompileError> {
+    let tokens = lexer::Lexer::new(source).tokenize()?;
+    let stmt_err = match parser::Parser::new(tokens.clone()).parse_program() {
+        Ok(stmts) => return Ok(codegen::statements(&stmts)),
+        Err(err) => err,
+    };
+    match parser::Parser::new(tokens).parse_lone_expr() {
+        Ok(expr) => Ok(codegen::expression(&expr)),
+        // Whichever got further is the more likely reading.
+        Err(err) if err.span.start > stmt_err.span.start => Err(err),
+        Err(_) => Err(stmt_err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interpret_both as interpret, Instruction::*, Value};
+
+    fn run(source: &str) -> Value {
+        let code = compile(source).unwrap();
+        assert_eq!(crate::verify::verify(&code), vec![]);
+        interpret(code).unwrap()
+    }
+
+    #[test]
+    fn compiles_lt_loop() {
+        assert_eq!(compile("i = 0; while i < 3 { i += 1 } return i").unwrap(), vec![Load(0),
+            Write("i".into()), Read("i".into()), Load(3), CompareLT, JumpIf(10), Read("i".into()),
+            Load(1), Add, Write("i".into()), Jump(1), Read("i".into()), Return]);
+    }
+
+    #[test]
+    fn arithmetic_precedence() {
+        assert_eq!(run("return 1 + 2 * 3"), 7);
+        assert_eq!(run("return (1 + 2) * 3"), 9);
+        assert_eq!(run("return 10 - 4 - 3"), 3);
+        assert_eq!(run("x = 5\nreturn -x + -2"), -7);
+        assert_eq!(run("return 2 * 3 > 5"), Value::Bool(true));
+        assert_eq!(run("x = 17; x %= 5; return x * 10 % 7"), 6);
+        assert_eq!(run("return !(1 < 2) != !!false"), Value::Bool(false));
+    }
+
+    #[test]
+    fn strings_and_arrays() {
+        assert_eq!(run("s = \"a\" ++ \"b\\\"\"; return s ++ s"), Value::from("ab\"ab\""));
+        assert_eq!(run("xs = [1, 2 + 3, \"x\"]; return xs[1] * len(xs)"), 15);
+        assert_eq!(run("xs = []; i = 0; while i < 3 { xs = xs ++ [i * i]; i += 1 } return xs"),
+            Value::Array(vec![0.into(), 1.into(), 4.into()]));
+        assert_eq!(run("return len(\"héllo\") + len([[1, 2]][0])"), 7);
+        assert_eq!(run("if true { return \"hi\"[1] }"), Value::from("i"));
+        assert_eq!(run("done = false; n = 0; while done == false { n += 1; done = n == 4 } return n"), 4);
+        assert_eq!(interpret(compile("return 1 + \"a\"").unwrap()).unwrap_err().kind, crate::InterpreterError::TypeMismatch);
+        assert_eq!(interpret(compile("return [1][1]").unwrap()).unwrap_err().kind, crate::InterpreterError::IndexOutOfBounds);
+    }
+
+    #[test]
+    fn if_else_chains() {
+        let source = "
+            x = 7
+            // classify x
+            if x < 5 { r = 1 } else if x < 10 { r = 2 } else { r = 3 }
+            return r
+        ";
+        assert_eq!(run(source), 2);
+        assert_eq!(run("if 0 { return 1 }"), 0);
+    }
+
+    #[test]
+    fn loop_at_program_start() {
+        assert_eq!(run("while 0 { }\nreturn 4"), 4);
+        assert_eq!(run("n = 5; f = 1; while n > 1 { f *= n; n -= 1 } return f"), 120);
+    }
+
+    #[test]
+    fn session_entries() {
+        assert_eq!(compile_entry("x = 1; y = x").unwrap(), vec![Load(1), Write("x".into()), Read("x".into()), Write("y".into())]);
+        assert_eq!(compile_entry("x * 2").unwrap(), vec![Read("x".into()), Load(2), Mul]);
+        assert_eq!(compile_entry("").unwrap(), vec![]);
+        // The error from the reading that got further.
+        assert_eq!(compile_entry("x = 1 +").unwrap_err().kind, ErrorKind::UnexpectedEof { expected: "an expression" });
+        assert_eq!(compile_entry("x + 1 )").unwrap_err().kind, ErrorKind::UnexpectedToken { expected: "end of input" });
+    }
+
+    #[test]
+    fn errors_carry_spans() {
+        let err = compile("x = 1\ny = 2 +\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnexpectedEof { expected: "an expression" });
+        assert_eq!((err.span.line, err.span.column), (3, 1));
+
+        let err = compile("x = 1\n  while x { x = $ }").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnexpectedChar('$'));
+        assert_eq!((err.span.start, err.span.line, err.span.column), (22, 2, 17));
+        assert_eq!(err.to_string(), "2:17: unexpected character `$`");
+
+        let err = compile("x + 1").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnexpectedToken { expected: "an assignment operator" });
+        assert_eq!((err.span.start, err.span.end), (2, 3));
+
+        let err = compile("y = 1\nx").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnexpectedEof { expected: "an assignment operator" });
+        assert_eq!((err.span.line, err.span.column), (2, 2));
+
+        assert_eq!(compile("x = 99999999999999999999").unwrap_err().kind, ErrorKind::IntegerOutOfRange);
+        assert_eq!(compile("x = \"abc").unwrap_err().kind, ErrorKind::UnterminatedString);
+        assert_eq!(compile("x = \"a\\qc\"").unwrap_err().to_string(), "1:5: invalid escape `\\q`");
+    }
+}
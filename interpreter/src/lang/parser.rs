@@ -0,0 +1,275 @@
+use super::ast::{BinaryOp, Expr, ExprKind, Stmt};
+use super::lexer::{Token, TokenKind};
+use super::{CompileError, ErrorKind, Span};
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Parser { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> &Token {
+        // The lexer always terminates the stream with `Eof`, which is never consumed.
+        &self.tokens[self.pos.min(self.tokens.len() - 1)]
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.peek().clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if &self.peek().kind == kind {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_token(&mut self, kind: &TokenKind) -> Option<Token> {
+        if &self.peek().kind == kind {
+            Some(self.advance())
+        } else {
+            None
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind, expected: &'static str) -> Result<Token, CompileError> {
+        if self.peek().kind == kind {
+            Ok(self.advance())
+        } else {
+            Err(self.unexpected(expected))
+        }
+    }
+
+    fn unexpected(&self, expected: &'static str) -> CompileError {
+        let token = self.peek();
+        let kind = match token.kind {
+            TokenKind::Eof => ErrorKind::UnexpectedEof { expected },
+            _ => ErrorKind::UnexpectedToken { expected },
+        };
+        CompileError { kind, span: token.span }
+    }
+
+    pub fn parse_program(&mut self) -> Result<Vec<Stmt>, CompileError> {
+        let mut stmts = Vec::new();
+        while self.peek().kind != TokenKind::Eof {
+            if !self.eat(&TokenKind::Semicolon) {
+                stmts.push(self.parse_stmt()?);
+            }
+        }
+        Ok(stmts)
+    }
+
+    // An expression that makes up the whole input.
+    pub fn parse_lone_expr(&mut self) -> Result<Expr, CompileError> {
+        let expr = self.parse_expr()?;
+        self.expect(TokenKind::Eof, "end of input")?;
+        Ok(expr)
+    }
+
+    fn parse_block(&mut self) -> Result<Vec<Stmt>, CompileError> {
+        self.expect(TokenKind::LBrace, "`{`")?;
+        let mut stmts = Vec::new();
+        while !self.eat(&TokenKind::RBrace) {
+            if self.peek().kind == TokenKind::Eof {
+                return Err(self.unexpected("`}`"))
+            }
+            if !self.eat(&TokenKind::Semicolon) {
+                stmts.push(self.parse_stmt()?);
+            }
+        }
+        Ok(stmts)
+    }
+
+    fn parse_stmt(&mut self) -> Result<Stmt, CompileError> {
+        let token = self.advance();
+        match token.kind {
+            TokenKind::If => self.parse_if(),
+            TokenKind::While => {
+                let cond = self.parse_expr()?;
+                let body = self.parse_block()?;
+                Ok(Stmt::While { cond, body })
+            }
+            TokenKind::Return => Ok(Stmt::Return(self.parse_expr()?)),
+            TokenKind::Ident(name) => {
+                // Peeked rather than backtracked over, `advance` never moves past `Eof`.
+                let op = match self.peek().kind {
+                    TokenKind::Assign => None,
+                    TokenKind::PlusAssign => Some(BinaryOp::Add),
+                    TokenKind::MinusAssign => Some(BinaryOp::Sub),
+                    TokenKind::StarAssign => Some(BinaryOp::Mul),
+                    TokenKind::SlashAssign => Some(BinaryOp::Div),
+                    TokenKind::PercentAssign => Some(BinaryOp::Mod),
+                    _ => return Err(self.unexpected("an assignment operator")),
+                };
+                self.advance();
+                let value = self.parse_expr()?;
+                let span = token.span.to(value.span);
+                Ok(Stmt::Assign { name, op, value, span })
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected("a statement"))
+            }
+        }
+    }
+
+    // Called with `if` already consumed, `else if` chains nest in the else branch.
+    fn parse_if(&mut self) -> Result<Stmt, CompileError> {
+        let cond = self.parse_expr()?;
+        let then_body = self.parse_block()?;
+        let else_body = if self.eat(&TokenKind::Else) {
+            if self.eat(&TokenKind::If) {
+                vec![self.parse_if()?]
+            } else {
+                self.parse_block()?
+            }
+        } else {
+            Vec::new()
+        };
+        Ok(Stmt::If { cond, then_body, else_body })
+    }
+
+    pub fn parse_expr(&mut self) -> Result<Expr, CompileError> {
+        let lhs = self.parse_additive()?;
+        let op = match self.peek().kind {
+            TokenKind::EqEq => BinaryOp::Eq,
+            TokenKind::NotEq => BinaryOp::Ne,
+            TokenKind::Lt => BinaryOp::Lt,
+            TokenKind::Gt => BinaryOp::Gt,
+            TokenKind::Lte => BinaryOp::Lte,
+            TokenKind::Gte => BinaryOp::Gte,
+            _ => return Ok(lhs),
+        };
+        self.advance();
+        let rhs = self.parse_additive()?;
+        Ok(binary(op, lhs, rhs))
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, CompileError> {
+        let mut lhs = self.parse_term()?;
+        loop {
+            let op = match self.peek().kind {
+                TokenKind::Plus => BinaryOp::Add,
+                TokenKind::Minus => BinaryOp::Sub,
+                TokenKind::PlusPlus => BinaryOp::Concat,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.parse_term()?;
+            lhs = binary(op, lhs, rhs);
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Expr, CompileError> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = match self.peek().kind {
+                TokenKind::Star => BinaryOp::Mul,
+                TokenKind::Slash => BinaryOp::Div,
+                TokenKind::Percent => BinaryOp::Mod,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.parse_unary()?;
+            lhs = binary(op, lhs, rhs);
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, CompileError> {
+        if !matches!(self.peek().kind, TokenKind::Minus | TokenKind::Bang) {
+            return self.parse_postfix()
+        }
+        let operator = self.advance();
+        let operand = self.parse_unary()?;
+        let span = operator.span.to(operand.span);
+        let kind = match (operator.kind, operand.kind) {
+            (TokenKind::Minus, ExprKind::Int(value)) => ExprKind::Int(-value),
+            (TokenKind::Minus, kind) => ExprKind::Neg(Box::new(Expr { kind, ..operand })),
+            (_, kind) => ExprKind::Not(Box::new(Expr { kind, ..operand })),
+        };
+        Ok(Expr { kind, span })
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, CompileError> {
+        let mut expr = self.parse_primary()?;
+        while self.eat(&TokenKind::LBracket) {
+            let index = self.parse_expr()?;
+            let close = self.expect(TokenKind::RBracket, "`]`")?;
+            let span = expr.span.to(close.span);
+            expr = Expr { kind: ExprKind::Index(Box::new(expr), Box::new(index)), span };
+        }
+        Ok(expr)
+    }
+
+    // Comma separated expressions up to and including `close`, which is returned as well.
+    fn parse_list(&mut self, close: TokenKind, expected: &'static str) -> Result<(Vec<Expr>, Token), CompileError> {
+        let mut exprs = Vec::new();
+        loop {
+            if let Some(token) = self.eat_token(&close) {
+                return Ok((exprs, token))
+            }
+            exprs.push(self.parse_expr()?);
+            if !self.eat(&TokenKind::Comma) {
+                let token = self.expect(close, expected)?;
+                return Ok((exprs, token))
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, CompileError> {
+        let token = self.advance();
+        let kind = match token.kind {
+            TokenKind::Int(value) => ExprKind::Int(value),
+            TokenKind::True => ExprKind::Bool(true),
+            TokenKind::False => ExprKind::Bool(false),
+            TokenKind::Str(text) => ExprKind::Str(text),
+            // Calls go to host functions, except `len` with one argument which is built in.
+            TokenKind::Ident(name) if self.eat(&TokenKind::LParen) => {
+                let (mut args, close) = self.parse_list(TokenKind::RParen, "`,` or `)`")?;
+                let span = token.span.to(close.span);
+                let kind = match args.len() {
+                    1 if name == "len" => ExprKind::Len(Box::new(args.remove(0))),
+                    _ => ExprKind::Call(name, args),
+                };
+                return Ok(Expr { kind, span })
+            }
+            TokenKind::Ident(name) => ExprKind::Var(name),
+            TokenKind::LBracket => {
+                let (elements, close) = self.parse_list(TokenKind::RBracket, "`,` or `]`")?;
+                return Ok(Expr { kind: ExprKind::Array(elements), span: token.span.to(close.span) })
+            }
+            TokenKind::LParen => {
+                let inner = self.parse_expr()?;
+                let close = self.expect(TokenKind::RParen, "`)`")?;
+                return Ok(Expr { span: token.span.to(close.span), ..inner })
+            }
+            TokenKind::Eof => return Err(self.unexpected("an expression")),
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected("an expression"))
+            }
+        };
+        Ok(Expr { kind, span: token.span })
+    }
+}
+
+fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    let span = lhs.span.to(rhs.span);
+    Expr { kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), span }
+}
+
+impl Span {
+    fn to(self, end: Span) -> Span {
+        Span { end: end.end, ..self }
+    }
+}
@@ -1,14 +1,63 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+
+pub mod asm;
+pub mod binary;
+pub mod debugger;
+pub mod disasm;
+// A test harness rather than an API, built for this crate's tests and the `fuzzing` feature.
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzz;
+mod error;
+mod host;
+mod json;
+pub mod lang;
+pub mod link;
+pub mod loc;
+pub mod verify;
+pub mod optimize;
+pub mod snapshot;
+pub mod trace;
+mod value;
+mod vm;
+
+pub use error::{RuntimeError, Transfer};
+pub use host::HostRegistry;
+pub use value::Value;
+pub use vm::{ArithmeticMode, Engine, Limits, Step, Vm, MAX_CALL_DEPTH};
 
 type Offset = usize;
 
-#[derive(Debug, Clone)]
+// `Jump(offset)` and `JumpIf(offset)` store `offset` in the instruction pointer before it is
+// advanced, so execution resumes at `offset + 1`. This gives the offset that resumes at `target`.
+pub(crate) fn offset_for_target(target: usize) -> Option<Offset> {
+    target.checked_sub(1)
+}
+
+// The other way round: the instruction an offset operand resumes at, if that is inside the
+// program.
+pub(crate) fn jump_target(code: &[Instruction], offset: Offset) -> Option<usize> {
+    offset.checked_add(1).filter(|target| *target < code.len())
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Load(i64),
+    LoadBool(bool),
+    LoadStr(String),
     Read(String),
     Write(String),
+    // `Read` and `Write` with the name resolved to a slot by `link::link`.
+    ReadSlot(usize),
+    WriteSlot(usize),
     Jump(Offset),
     JumpIf(Offset),
+    // Like `Jump`, remembering where to come back to. Arguments and results travel on the stack.
+    Call(Offset),
+    // Returns from the innermost `Call`, dropping its local variables.
+    Ret,
+    // Calls the host function registered under the name with that many arguments.
+    CallHost(String, usize),
     CompareEQ,
     CompareNE,
     CompareGT,
@@ -19,143 +68,129 @@ pub enum Instruction {
     Sub,
     Mul,
     Div,
+    // Remainder with the sign of the dividend, like `%` in Rust.
+    Mod,
+    Neg,
+    // Bitwise on ints, logical on bools.
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    // Arithmetic shift, keeps the sign.
+    Shr,
+    Dup,
+    Swap,
+    Pop,
+    // Pops that many values and pushes them as an array, the deepest one first.
+    NewArray(usize),
+    // Joins two strings or two arrays.
+    Concat,
+    // Pops an int index and the string or array below it, pushes the element.
+    Index,
+    Len,
     Return,
 }
 
 pub struct ByteCode {
     code: Vec<Instruction>,
-    stack: Vec<i64>,
+    stack: Vec<Value>,
     instruction_ptr: usize,
-    vars: HashMap<String, i64>,
+    vars: HashMap<String, Value>,
+    // The outermost frame's slots and the names `Vm::load_linked` resolved them from.
+    slots: Slots,
+    slot_names: Vec<String>,
+    frames: Vec<Frame>,
+    fuel_used: u64,
+    // Recent jumps, calls and returns for error reports, at most `error::TRAIL_LEN`.
+    trail: VecDeque<Transfer>,
+}
+
+// Pushed by `Call`. `Read` and `Write` inside a call only see the innermost frame's `vars`.
+struct Frame {
+    return_ptr: usize,
+    vars: HashMap<String, Value>,
+    slots: Slots,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+// Grows to the number of linked names on the first write, unset slots are `None`.
+#[derive(Default)]
+struct Slots {
+    values: Vec<Option<Value>>,
+    set: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum InterpreterError {
-    UndefinedBehavior,
+    UndefinedVariable(String),
     DivideByZero,
     StackEmpty,
     BadInstructionOffset,
+    CallStackOverflow,
+    CallStackEmpty,
+    OutOfFuel,
+    StackOverflow,
+    TooManyVariables,
+    // `ReadSlot` or `WriteSlot` past the names the program was linked with.
+    BadSlot,
+    Overflow,
+    // An operand had the wrong type, e.g. `Add` on a string or `JumpIf` on an array.
+    TypeMismatch,
+    IndexOutOfBounds,
+    // Shift amount outside of `0..64`.
+    ShiftOutOfRange,
+    UnknownHostFunction,
+    ArityMismatch,
+    // Raised by host functions for failures of their own.
+    HostFailure,
 }
 
-macro_rules! handleDiv {
-    {$byte_code:expr} => {
-    match $byte_code.stack.pop() {
-        Some(rhs) => {
-            match $byte_code.stack.pop() {
-                Some(lhs) => {
-                    if rhs == 0 {
-                        return Err(InterpreterError::DivideByZero)
-                    }
-                    else {
-                        $byte_code.stack.push(lhs / rhs);
-                        Ok(())
-                    }
-                },
-                _ => Err(InterpreterError::StackEmpty)
-            }
-        },
-        _ => Err(InterpreterError::StackEmpty)
-    }
-}}
-
-macro_rules! handleMath {
-    {$byte_code:expr, $operator:tt} => {
-        match $byte_code.stack.pop() {
-            Some(rhs) => {
-                match $byte_code.stack.pop() {
-                    Some(lhs) => {
-                        let result: i64 = (lhs $operator rhs) as i64;
-                        $byte_code.stack.push(result);
-                        Ok(())
-                    },
-                    _ => Err(InterpreterError::StackEmpty),
-                }
-            },
-            _ => Err(InterpreterError::StackEmpty),
-        }
-}}
-
-fn interpret(code: Vec<Instruction>) -> Result<i64, InterpreterError> {
-    let mut byte_code = ByteCode {
-        code: code,
-        stack: Vec::new(),
-        instruction_ptr: 0,
-        vars: HashMap::new(),
-    };
-
-    loop {
-        let instruction = &byte_code.code[byte_code.instruction_ptr];
-        let op = match instruction {
-            Instruction::Load(value) => {
-                byte_code.stack.push(*value);
-                Ok(())
-            }
-            Instruction::Write(var_name) => {
-                match byte_code.stack.pop() {
-                    Some(val) => {
-                        byte_code.vars.insert(var_name.clone(), val);
-                        Ok(())
-                    }
-                    _ => Err(InterpreterError::StackEmpty)
-                }
-            },
-            Instruction::Read(var_name) => {
-                match byte_code.vars.get(var_name) {
-                    Some(read_val) => {
-                        byte_code.stack.push(*read_val);
-                        Ok(())
-                    },
-                    _ => Err(InterpreterError::UndefinedBehavior),
-                }
-            },
-            Instruction::Add => handleMath!{byte_code, +},
-            Instruction::Sub => handleMath!{byte_code, -},
-            Instruction::Mul => handleMath!{byte_code, *},
-            Instruction::Div => handleDiv!{byte_code},
-            Instruction::CompareEQ => handleMath!{byte_code, ==},
-            Instruction::CompareNE => handleMath!{byte_code, !=},
-            Instruction::CompareGT => handleMath!{byte_code, >},
-            Instruction::CompareLT => handleMath!{byte_code, <},
-            Instruction::CompareGTE => handleMath!{byte_code, >=},
-            Instruction::CompareLTE => handleMath!{byte_code, <=},
-            Instruction::Jump(offset) => {
-                if *offset >= byte_code.code.len() {
-                    return Err(InterpreterError::BadInstructionOffset)
-                }
-                byte_code.instruction_ptr = *offset;
-                Ok(())
-            },
-            Instruction::JumpIf(offset) => {
-                match byte_code.stack.pop() {
-                    Some(val) => {
-                        if val == 0 {
-                            byte_code.instruction_ptr = *offset;
-                        }
-                        Ok(())
-                    },
-                    _ => Err(InterpreterError::StackEmpty),
-                }
-            },
-            Instruction::Return => break,
-        };
-
-        match op {
-            Err(error_code) => return Err(error_code),
-            Ok(()) => {},
-        }
-
-        byte_code.instruction_ptr += 1;
+/// Reads a program for the binaries: assembly from `.asm` files, `lang` source from anything
+/// else. Errors are messages starting with `path`.
+pub fn load_file(path: &str) -> Result<Vec<Instruction>, String> {
+    let source = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    if path.ends_with(".asm") {
+        asm::parse(&source).map_err(|err| format!("{}:{}", path, err))
+    } else {
+        lang::compile(&source).map_err(|err| format!("{}:{}", path, err))
     }
+}
 
-    match byte_code.stack.pop() {
-        Some(result) => Ok(result),
-        _ => Err(InterpreterError::StackEmpty),
-    }
+pub fn interpret(code: Vec<Instruction>) -> Result<Value, RuntimeError> {
+    let mut vm = Vm::new();
+    vm.load_program(code);
+    vm.run()
+}
+
+// `interpret` on both engines, which must agree on everything observable.
+#[cfg(test)]
+pub(crate) fn interpret_both(code: Vec<Instruction>) -> Result<Value, RuntimeError> {
+    let mut basic = Vm::new();
+    basic.load_program(code.clone());
+    let expected = basic.run();
+    let mut threaded = Vm::with_engine(Engine::Threaded);
+    threaded.load_program(code);
+    assert_eq!(threaded.run(), expected);
+    assert_eq!(threaded.stack(), basic.stack());
+    assert_eq!(threaded.vars(), basic.vars());
+    assert_eq!(threaded.fuel_consumed(), basic.fuel_consumed());
+    assert_eq!(threaded.instruction_ptr(), basic.instruction_ptr());
+    expected
+}
+
+// i = 0; while i < 3 { i += 1 }; return i
+#[cfg(test)]
+pub(crate) fn lt_loop() -> Vec<Instruction> {
+    use Instruction::*;
+    vec![Load(0), Write("i".into()), Read("i".into()), Load(3),
+        CompareLT, JumpIf(10), Read("i".into()), Load(1), Add, Write("i".into()),
+        Jump(1), Read("i".into()), Return]
 }
 
 #[cfg(test)]
 mod tests {
     use super::{*, Instruction::*};
+    use crate::{interpret_both as interpret, lt_loop};
 
     #[test]
     fn load_val() {
@@ -194,6 +229,64 @@ mod tests {
         assert!(interpret(vec![Load(2), Load(0), Div, Return]).is_err());
     }
 
+    #[test]
+    fn mod_val() {
+        assert_eq!(interpret(vec![Load(7), Load(3), Mod, Return]).unwrap(), 1);
+        assert_eq!(interpret(vec![Load(-7), Load(3), Mod, Return]).unwrap(), -1);
+    }
+
+    #[test]
+    fn mod_by_zero() {
+        assert_eq!(interpret(vec![Load(2), Load(0), Mod, Return]).unwrap_err().kind, InterpreterError::DivideByZero);
+        assert_eq!(interpret(vec![Load(i64::MIN), Load(-1), Mod, Return]).unwrap_err().kind, InterpreterError::Overflow);
+    }
+
+    #[test]
+    fn neg_val() {
+        assert_eq!(interpret(vec![Load(5), Neg, Return]).unwrap(), -5);
+        assert_eq!(interpret(vec![Load(i64::MIN), Neg, Return]).unwrap_err().kind, InterpreterError::Overflow);
+    }
+
+    #[test]
+    fn bitwise_val() {
+        assert_eq!(interpret(vec![Load(0b1100), Load(0b1010), And, Return]).unwrap(), 0b1000);
+        assert_eq!(interpret(vec![Load(0b1100), Load(0b1010), Or, Return]).unwrap(), 0b1110);
+        assert_eq!(interpret(vec![Load(0b1100), Load(0b1010), Xor, Return]).unwrap(), 0b0110);
+        assert_eq!(interpret(vec![Load(0), Not, Return]).unwrap(), -1);
+    }
+
+    #[test]
+    fn logical_val() {
+        assert_eq!(interpret(vec![LoadBool(true), LoadBool(false), And, Return]).unwrap(), Value::Bool(false));
+        assert_eq!(interpret(vec![LoadBool(true), LoadBool(false), Or, Return]).unwrap(), Value::Bool(true));
+        assert_eq!(interpret(vec![LoadBool(true), LoadBool(true), Xor, Return]).unwrap(), Value::Bool(false));
+        assert_eq!(interpret(vec![LoadBool(true), Not, Return]).unwrap(), Value::Bool(false));
+        assert_eq!(interpret(vec![LoadBool(true), Load(1), And, Return]).unwrap_err().kind, InterpreterError::TypeMismatch);
+    }
+
+    #[test]
+    fn shift_val() {
+        assert_eq!(interpret(vec![Load(3), Load(4), Shl, Return]).unwrap(), 48);
+        assert_eq!(interpret(vec![Load(-16), Load(2), Shr, Return]).unwrap(), -4);
+        assert_eq!(interpret(vec![Load(1), Load(63), Shl, Return]).unwrap(), i64::MIN);
+    }
+
+    #[test]
+    fn shift_out_of_range() {
+        assert_eq!(interpret(vec![Load(1), Load(64), Shl, Return]).unwrap_err().kind, InterpreterError::ShiftOutOfRange);
+        assert_eq!(interpret(vec![Load(1), Load(-1), Shr, Return]).unwrap_err().kind, InterpreterError::ShiftOutOfRange);
+    }
+
+    #[test]
+    fn stack_manipulation() {
+        assert_eq!(interpret(vec![Load(3), Dup, Mul, Return]).unwrap(), 9);
+        assert_eq!(interpret(vec![Load(1), Load(3), Swap, Sub, Return]).unwrap(), 2);
+        assert_eq!(interpret(vec![Load(1), Load(3), Pop, Return]).unwrap(), 1);
+        assert_eq!(interpret(vec![Load(1), Swap, Return]).unwrap_err().kind, InterpreterError::StackEmpty);
+        assert_eq!(interpret(vec![Dup, Return]).unwrap_err().kind, InterpreterError::StackEmpty);
+        assert_eq!(interpret(vec![Pop, Return]).unwrap_err().kind, InterpreterError::StackEmpty);
+    }
+
     #[test]
     fn test_from_assignment() {
         let assignment_byte_code = vec![Load(1), Write("x".into()), Load(3),
@@ -214,9 +307,7 @@ mod tests {
             i += 1
          done
          */
-        assert_eq!(interpret(vec![Load(0), Write("i".into()), Read("i".into()), Load(3),
-            CompareLT, JumpIf(10), Read("i".into()), Load(1), Add, Write("i".into()),
-            Jump(1), Read("i".into()), Return]).unwrap(), 3);
+        assert_eq!(interpret(lt_loop()).unwrap(), 3);
     }
 
     // Further tests for each conditional...
@@ -226,35 +317,7 @@ mod tests {
 //     extension in that directory and all sub-directories, and counts the number of lines
 //     in the file and prints it to stdout.
 
-// A file system is in a tree structure so Depth First Search (Recursive approach) which
-// traverse the tree to the furthest leaf node(i.e. the furthest down directory/file)
-// This is O(n) runtime (where n is a leaf in the tree).
-// After recursing every directory and file match on the particular file extension
-// open the file and read the lines O(Lines) runtime complexity
-// Bringin the total complexity of the algorithm to O(N * L) (Worst case everything is
-// a leaf node) for each leaf which matches your file extension you read All of its lines.
-
-// Not sure if it is ok to use these crates but runnin out of time.
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use glob::{glob};
-fn find_all_files(file_path: &str, extension: &str) -> Result<(), std::io::Error> {
-    // This tells glob to recurse all sub dirs and to grab all file extensions in those subdirs
-    const RECURSIVE_GLOB_MAGIC: &str = "/**/*.";
-    let glob_string = format!("{}{}{}", file_path, RECURSIVE_GLOB_MAGIC, extension);
-    for entry in glob(glob_string.as_str()).expect("Incorrect Glob string")
-      {
-        let mut line_count = 0;
-        let file_path = entry.expect("Incorrect PathBuf");
-        println!("file {}", file_path.to_str().unwrap());
-        let file = BufReader::new(File::open(file_path)?);
-        for _ in file.lines() {
-          line_count += 1;
-        }
-        println!("Total lines are: {}", line_count);
-  }
-    Ok(())
-}
+// See the `loc` module and the `loc` binary.
 
 // (5) explain some of the ways hashing functions enable blockchain technology
 
@@ -295,4 +358,4 @@ fn find_all_files(file_path: &str, extension: &str) -> Result<(), std::io::Error
 // seemingly good actors in a distributed network but they are indeed malicious. By implementing a PoW/PoS
 // system the majority of honest nodes in a network can find agreement on the next state of the blockchain.
 // PoS in particular can attempt to isolate bad actors and punishing them further by slashing their stake and
-// reputation.
\ No newline at end of file
+// reputation.
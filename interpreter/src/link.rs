@@ -0,0 +1,131 @@
+// Resolving variable names to slots.
+//
+// `Read` and `Write` hash the variable name on every access. `link` numbers the names a program
+// uses in order of first appearance and rewrites them to `ReadSlot`/`WriteSlot`, which index a
+// `Vec` instead. Every call gets fresh slots, so frames keep their variables to themselves just
+// like with names. `Vm::load_linked` runs the result.
+
+use std::collections::HashMap;
+
+use crate::Instruction;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Linked {
+    pub code: Vec<Instruction>,
+    // Indexed by slot.
+    pub names: Vec<String>,
+}
+
+impl Linked {
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|candidate| candidate == name)
+    }
+}
+
+/// Rewrites every `Read` and `Write` to use a slot. Instructions that already use slots are
+/// left alone.
+pub fn link(code: &[Instruction]) -> Linked {
+    let mut names = Vec::new();
+    let mut slots: HashMap<&str, usize> = HashMap::new();
+    let mut linked = Vec::with_capacity(code.len());
+    for instruction in code {
+        let (Instruction::Read(name) | Instruction::Write(name)) = instruction else {
+            linked.push(instruction.clone());
+            continue
+        };
+        let slot = *slots.entry(name).or_insert_with(|| {
+            names.push(name.clone());
+            names.len() - 1
+        });
+        linked.push(match instruction {
+            Instruction::Read(_) => Instruction::ReadSlot(slot),
+            _ => Instruction::WriteSlot(slot),
+        });
+    }
+    Linked { code: linked, names }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, lang, lt_loop, Instruction::*, InterpreterError, Limits, Value, Vm};
+
+    #[test]
+    fn numbers_names_by_first_use() {
+        let linked = link(&asm::parse("load 1\nwrite b\nread b\nwrite a\nread b\nreturn").unwrap());
+        assert_eq!(linked.code, vec![Load(1), WriteSlot(0), ReadSlot(0), WriteSlot(1), ReadSlot(0), Return]);
+        assert_eq!(linked.names, vec!["b", "a"]);
+        assert_eq!(linked.slot("a"), Some(1));
+        assert_eq!(link(&linked.code), Linked { code: linked.code.clone(), names: Vec::new() });
+    }
+
+    #[test]
+    fn linked_programs_agree_with_named_ones() {
+        let mut programs: Vec<Vec<Instruction>> = [
+            "n = 10; a = 0; b = 1; while n > 0 { t = b; b = a + b; a = t; n -= 1 } return a",
+            "xs = [1, 2, 3]; total = 0; i = 0; while i < len(xs) { total += xs[i]; i += 1 } return total",
+        ].iter().map(|source| lang::compile(source).unwrap()).collect();
+        // Every call gets its own `n`.
+        programs.push(asm::parse("
+                    load 5
+                    write n
+                    read n
+                    call fact
+                    read n
+                    add
+                    return
+            fact:   write n
+                    read n
+                    load 1
+                    cmpgt
+                    jumpif base
+                    read n
+                    read n
+                    load 1
+                    sub
+                    call fact
+                    mul
+                    ret
+            base:   load 1
+                    ret
+        ").unwrap());
+        for code in programs {
+            let mut named = Vm::new();
+            named.load_program(code.clone());
+            let mut linked = Vm::new();
+            linked.load_linked(link(&code));
+            assert_eq!(linked.run(), named.run());
+            assert_eq!(linked.vars(), named.vars());
+            assert_eq!(linked.fuel_consumed(), named.fuel_consumed());
+        }
+    }
+
+    #[test]
+    fn variables_move_between_names_and_slots() {
+        let mut vm = Vm::new();
+        vm.load_program(vec![Load(41), Write("x".into()), Load(0), Return]);
+        vm.run().unwrap();
+        vm.load_linked(link(&[Read("x".into()), Load(1), Add, Write("x".into()), Read("x".into()), Return]));
+        assert_eq!(vm.run().unwrap(), 42);
+        assert_eq!(vm.vars().get("x"), Some(&Value::Int(42)));
+
+        vm.load_linked(link(&lt_loop()));
+        assert_eq!(vm.run().unwrap(), 3);
+        vm.load_program(vec![Read("i".into()), Read("x".into()), Add, Return]);
+        assert_eq!(vm.run().unwrap(), 45);
+    }
+
+    #[test]
+    fn slot_errors() {
+        let mut vm = Vm::new();
+        vm.load_linked(link(&asm::parse("read missing\nreturn").unwrap()));
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::UndefinedVariable("missing".into()));
+        vm.load_program(vec![Load(1), WriteSlot(0), Return]);
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::BadSlot);
+
+        let mut vm = Vm::with_limits(Limits { max_vars: Some(1), ..Limits::default() });
+        vm.load_linked(link(&[Load(1), Write("x".into()), Load(2), Write("x".into()),
+            Load(3), Write("y".into()), Return]));
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::TooManyVariables);
+    }
+}
@@ -0,0 +1,392 @@
+// What every file counted to on the previous run, so unchanged files aren't read again.
+//
+//     magic "ILC\0" | version u8 | scan start | record count | records | crc32 (u32 little endian)
+//     record: path | modified | size | hash u64 (little endian) | outcome
+//
+// Numbers are varints as in `binary`. Times are nanoseconds since the Unix epoch, 0 if the
+// file system doesn't have them. The outcome is a 0 byte and the lines, blank, comment, doc
+// and byte counts, or 1 for a binary file and 2 for one that isn't UTF-8.
+//
+// A file whose size and modification time match its record isn't read at all, unless it was
+// modified shortly before the previous scan started: a write in the same clock tick after it
+// was read would leave both unchanged. Any other file is read and hashed, and only counted
+// again if its contents changed. Records of files the scan doesn't reach are dropped when the
+// cache is saved, and a cache that fails to decode, or was written by another version, is
+// treated as empty. Paths are stored as scanned, so the cache only helps runs over the same
+// root spelled the same way.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{classify, Counts, Entry, ScanError, Skip};
+use crate::binary::{crc32, fnv1a, write_varint, DecodeError, Reader};
+
+pub const MAGIC: [u8; 4] = *b"ILC\0";
+// Bumped whenever the format or the way lines are classified changes.
+pub const VERSION: u8 = 1;
+
+// Modification times closer than this to the start of the previous scan aren't trusted.
+const RACY: Duration = Duration::from_secs(2);
+
+const COUNTED: u8 = 0;
+const BINARY: u8 = 1;
+const NOT_UTF8: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    modified: u64,
+    size: u64,
+    hash: u64,
+    outcome: Result<Counts, Skip>,
+}
+
+/// How the files of a scan were counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    // Size and modification time matched, the file wasn't read.
+    pub unchanged: u64,
+    // Read, but the contents hadn't changed.
+    pub rehashed: u64,
+    // New or changed files.
+    pub counted: u64,
+}
+
+impl AddAssign for CacheStats {
+    fn add_assign(&mut self, other: CacheStats) {
+        self.unchanged += other.unchanged;
+        self.rehashed += other.rehashed;
+        self.counted += other.counted;
+    }
+}
+
+#[derive(Default)]
+struct Run {
+    records: HashMap<PathBuf, Record>,
+    stats: CacheStats,
+}
+
+pub struct Cache {
+    path: PathBuf,
+    started: u64,
+    // When the scan that wrote `previous` started.
+    previous_started: u64,
+    previous: HashMap<PathBuf, Record>,
+    run: Mutex<Run>,
+}
+
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64)
+}
+
+fn decode(bytes: &[u8]) -> Result<(u64, HashMap<PathBuf, Record>), DecodeError> {
+    if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::BadMagic)
+    }
+    if bytes.len() < MAGIC.len() + 1 + 4 {
+        return Err(DecodeError::UnexpectedEof)
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    let expected = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    let found = crc32(body);
+    if expected != found {
+        return Err(DecodeError::ChecksumMismatch { expected, found })
+    }
+
+    let mut reader = Reader { bytes: body, pos: MAGIC.len() };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version))
+    }
+    let started = reader.varint()?;
+    let mut records = HashMap::new();
+    for _ in 0..reader.varint()? {
+        let path = PathBuf::from(reader.string()?);
+        let (modified, size) = (reader.varint()?, reader.varint()?);
+        let mut hash = [0; 8];
+        hash.copy_from_slice(reader.take(8)?);
+        let outcome = match reader.byte()? {
+            COUNTED => Ok(Counts {
+                lines: reader.varint()?,
+                blank: reader.varint()?,
+                comment: reader.varint()?,
+                doc: reader.varint()?,
+                bytes: reader.varint()?,
+            }),
+            BINARY => Err(Skip::Binary),
+            NOT_UTF8 => Err(Skip::NotUtf8),
+            tag => return Err(DecodeError::UnknownOpcode(tag)),
+        };
+        records.insert(path, Record { modified, size, hash: u64::from_le_bytes(hash), outcome });
+    }
+    if reader.pos != body.len() {
+        return Err(DecodeError::TrailingBytes)
+    }
+    Ok((started, records))
+}
+
+impl Cache {
+    /// The cache saved at `path`, empty if there is none or it can't be used.
+    pub fn load(path: &Path) -> Cache {
+        let started = nanos(SystemTime::now());
+        let (previous_started, previous) = fs::read(path).ok()
+            .and_then(|bytes| decode(&bytes).ok())
+            .unwrap_or_default();
+        Cache { path: path.into(), started, previous_started, previous, run: Mutex::default() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.run.lock().unwrap().stats
+    }
+
+    // Counts the file at `path` like `count_file`, reading it only if it may have changed.
+    pub(super) fn count(&self, path: &Path) -> Entry {
+        let failed = |error| Entry::Failed(ScanError { path: path.into(), error });
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(error) => return failed(error),
+        };
+        let modified = metadata.modified().map_or(0, nanos);
+        let previous = self.previous.get(path);
+        let trusted = modified != 0 && modified.saturating_add(RACY.as_nanos() as u64) < self.previous_started;
+        let mut stats = CacheStats::default();
+        let record = match previous {
+            Some(record) if trusted && record.modified == modified && record.size == metadata.len() => {
+                stats.unchanged = 1;
+                record.clone()
+            }
+            _ => {
+                let bytes = match fs::read(path) {
+                    Ok(bytes) => bytes,
+                    Err(error) => return failed(error),
+                };
+                let (size, hash) = (bytes.len() as u64, fnv1a(&bytes));
+                match previous {
+                    Some(record) if record.size == size && record.hash == hash => {
+                        stats.rehashed = 1;
+                        Record { modified, ..record.clone() }
+                    }
+                    _ => {
+                        stats.counted = 1;
+                        Record { modified, size, hash, outcome: classify(path, bytes) }
+                    }
+                }
+            }
+        };
+
+        let entry = Entry::from_outcome(path, record.outcome);
+        let mut run = self.run.lock().unwrap();
+        run.stats += stats;
+        // Paths that aren't UTF-8 are counted every time.
+        if path.to_str().is_some() {
+            run.records.insert(path.into(), record);
+        }
+        entry
+    }
+
+    /// Writes the records of this run over the cache file.
+    pub fn save(&self) -> io::Result<()> {
+        let run = self.run.lock().unwrap();
+        let mut records: Vec<(&PathBuf, &Record)> = run.records.iter().collect();
+        records.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        write_varint(&mut out, self.started);
+        write_varint(&mut out, records.len() as u64);
+        for (path, record) in records {
+            let path = path.to_str().expect("only UTF-8 paths are recorded");
+            write_varint(&mut out, path.len() as u64);
+            out.extend_from_slice(path.as_bytes());
+            write_varint(&mut out, record.modified);
+            write_varint(&mut out, record.size);
+            out.extend_from_slice(&record.hash.to_le_bytes());
+            match record.outcome {
+                Ok(counts) => {
+                    out.push(COUNTED);
+                    for count in [counts.lines, counts.blank, counts.comment, counts.doc, counts.bytes] {
+                        write_varint(&mut out, count);
+                    }
+                }
+                Err(Skip::Binary) => out.push(BINARY),
+                Err(Skip::NotUtf8) => out.push(NOT_UTF8),
+            }
+        }
+        let checksum = crc32(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+
+        // Written next to the cache and renamed over it, an interrupted save leaves the old one.
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, out)?;
+        fs::rename(&temporary, &self.path)
+    }
+
+    /// How the counted files differ from the previous run.
+    pub fn diff(&self) -> Diff {
+        let run = self.run.lock().unwrap();
+        let counted = |records: &HashMap<PathBuf, Record>, path: &Path| records.get(path).and_then(|record| record.outcome.ok());
+        let mut diff = Diff::default();
+        for path in run.records.keys() {
+            match (counted(&self.previous, path), counted(&run.records, path)) {
+                (None, Some(counts)) => diff.added.push((path.clone(), counts)),
+                (Some(before), Some(after)) if before != after => diff.changed.push((path.clone(), before, after)),
+                (Some(counts), None) => diff.removed.push((path.clone(), counts)),
+                _ => {}
+            }
+        }
+        for (path, record) in &self.previous {
+            if let (Ok(counts), false) = (record.outcome, run.records.contains_key(path)) {
+                diff.removed.push((path.clone(), counts));
+            }
+        }
+        diff.added.sort_by(|a, b| a.0.cmp(&b.0));
+        diff.removed.sort_by(|a, b| a.0.cmp(&b.0));
+        diff.changed.sort_by(|a, b| a.0.cmp(&b.0));
+        diff
+    }
+}
+
+/// Files whose counts changed since the previous run, each list ordered by path.
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub added: Vec<(PathBuf, Counts)>,
+    pub removed: Vec<(PathBuf, Counts)>,
+    pub changed: Vec<(PathBuf, Counts, Counts)>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// One line per file, only the numbers that changed:
+//
+//     changed  src/lib.rs  lines 120 -> 124, code 100 -> 103, comment 8 -> 9
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "no changes since the last run")
+        }
+        for (path, counts) in &self.added {
+            writeln!(f, "added    {}  {} lines", path.display(), counts.lines)?;
+        }
+        for (path, counts) in &self.removed {
+            writeln!(f, "removed  {}  {} lines", path.display(), counts.lines)?;
+        }
+        for (path, before, after) in &self.changed {
+            let fields = [
+                ("lines", before.lines, after.lines),
+                ("code", before.code(), after.code()),
+                ("comment", before.comment, after.comment),
+                ("doc", before.doc, after.doc),
+                ("blank", before.blank, after.blank),
+                ("bytes", before.bytes, after.bytes),
+            ];
+            let changes: Vec<String> = fields.iter()
+                .filter(|(_, before, after)| before != after)
+                .map(|(name, before, after)| format!("{} {} -> {}", name, before, after))
+                .collect();
+            writeln!(f, "changed  {}  {}", path.display(), changes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::loc::tests::Tree;
+    use crate::loc::{stream_cached, Options, Report};
+
+    fn run(tree: &Tree, cache: &Path) -> (Report, CacheStats, Diff) {
+        let cache = Arc::new(Cache::load(cache));
+        let report: Report = stream_cached(&tree.0, &Options::default(), &cache).collect();
+        cache.save().unwrap();
+        (report, cache.stats(), cache.diff())
+    }
+
+    // Moves every file's modification time well before the next scan so it can be trusted.
+    fn age(tree: &Tree, names: &[&str]) {
+        let past = SystemTime::now() - Duration::from_secs(3600);
+        for name in names {
+            fs::File::options().write(true).open(tree.0.join(name)).unwrap().set_modified(past).unwrap();
+        }
+    }
+
+    #[test]
+    fn only_changed_files_are_counted() {
+        let tree = Tree::new("cache", &[("a.rs", "fn a() {}\n"), ("b.rs", "// b\nfn b() {}\n"), ("c.rs", "fn c() {}\n")]);
+        std::fs::write(tree.0.join("blob.bin"), b"\0\x01").unwrap();
+        let cache = tree.0.join(".loc-cache");
+        age(&tree, &["a.rs", "b.rs", "c.rs", "blob.bin"]);
+
+        let (first, stats, diff) = run(&tree, &cache);
+        assert_eq!(stats, CacheStats { unchanged: 0, rehashed: 0, counted: 4 });
+        assert_eq!(first.total.files, 3);
+        assert_eq!(diff.added.len(), 3);
+        // The cache itself is not counted on later runs.
+        assert!(cache.exists());
+
+        // Recent modification times can't be trusted, the file is hashed instead.
+        std::fs::write(tree.0.join("a.rs"), "fn a() { 1 }\n// one\n").unwrap();
+        std::fs::remove_file(tree.0.join("c.rs")).unwrap();
+        std::fs::write(tree.0.join("d.rs"), "fn d() {}\n").unwrap();
+        fs::File::options().write(true).open(tree.0.join("b.rs")).unwrap().set_modified(SystemTime::now()).unwrap();
+        let (second, stats, diff) = run(&tree, &cache);
+        assert_eq!(stats, CacheStats { unchanged: 1, rehashed: 1, counted: 2 });
+        assert_eq!(second.total.files, 3);
+        assert!(second.skipped.iter().any(|(path, _)| path.ends_with("blob.bin")));
+        assert_eq!(diff.added, vec![(tree.0.join("d.rs"), Counts { lines: 1, bytes: 10, ..Counts::default() })]);
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.changed.len(), 1);
+        let text = diff.to_string();
+        assert!(text.contains(&format!("changed  {}  lines 1 -> 2, comment 0 -> 1, bytes 10 -> 20", tree.0.join("a.rs").display())), "{}", text);
+        assert!(text.contains("removed  "));
+
+        age(&tree, &["a.rs", "b.rs", "d.rs"]);
+        let (third, stats, diff) = run(&tree, &cache);
+        assert_eq!(third.total, second.total);
+        assert_eq!(stats.counted, 0);
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "no changes since the last run\n");
+    }
+
+    #[test]
+    fn unusable_caches_start_over() {
+        let tree = Tree::new("cache-bad", &[("a.rs", "fn a() {}\n")]);
+        let cache = tree.0.join("cache");
+        run(&tree, &cache);
+        let mut bytes = fs::read(&cache).unwrap();
+        assert!(decode(&bytes).is_ok());
+        let last = bytes.len() - 5;
+        bytes[last] ^= 1;
+        fs::write(&cache, &bytes).unwrap();
+        assert!(decode(&bytes).is_err());
+
+        let (report, stats, diff) = run(&tree, &cache);
+        assert_eq!(report.total.files, 1);
+        assert_eq!(stats.counted, 1);
+        assert_eq!(diff.added.len(), 1);
+
+        // Caches from other versions are not read.
+        let mut bytes = fs::read(&cache).unwrap();
+        bytes.truncate(bytes.len() - 4);
+        bytes[MAGIC.len()] = VERSION + 1;
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(decode(&bytes).unwrap_err(), DecodeError::UnsupportedVersion(VERSION + 1));
+    }
+}
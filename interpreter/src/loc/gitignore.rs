@@ -0,0 +1,102 @@
+// Just enough of `.gitignore` to skip what git skips.
+//
+// Blank lines and `#` comments are ignored, `!` re-includes, a trailing `/` only matches
+// directories, a pattern with a `/` anywhere else is relative to the directory holding the
+// file and anything else matches a name at any depth. The last matching pattern wins, and
+// files further down the tree are consulted after those above them.
+
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+
+const OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+struct Rule {
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+pub(crate) struct Gitignore {
+    // Directory holding the file, relative to the scan root.
+    base: PathBuf,
+    rules: Vec<Rule>,
+}
+
+// Paths relative to the scan root with `/` separators on every platform.
+pub(crate) fn slashed(path: &Path) -> String {
+    let parts: Vec<_> = path.components().map(|part| part.as_os_str().to_string_lossy()).collect();
+    parts.join("/")
+}
+
+impl Gitignore {
+    pub(crate) fn parse(base: &Path, text: &str) -> Self {
+        let mut rules = Vec::new();
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let anchored = line.contains('/');
+            // Broken patterns are skipped like git does.
+            if let Ok(pattern) = Pattern::new(line.trim_start_matches('/')) {
+                rules.push(Rule { pattern, negated, dir_only, anchored });
+            }
+        }
+        Gitignore { base: base.to_path_buf(), rules }
+    }
+
+    /// `Some(true)` if the last rule matching `path` ignores it, `Some(false)` if it
+    /// re-includes it.
+    pub(crate) fn matches(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = slashed(path.strip_prefix(&self.base).ok()?);
+        let name = path.file_name()?.to_string_lossy();
+        self.rules.iter().rev()
+            .filter(|rule| is_dir || !rule.dir_only)
+            .find(|rule| match rule.anchored {
+                true => rule.pattern.matches_with(&relative, OPTIONS),
+                false => rule.pattern.matches_with(&name, OPTIONS),
+            })
+            .map(|rule| !rule.negated)
+    }
+}
+
+/// Whether the innermost rule matching `path` in any of `ignores` ignores it.
+pub(crate) fn is_ignored(ignores: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    ignores.iter().rev().find_map(|ignore| ignore.matches(path, is_dir)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_git_rules() {
+        let root = Gitignore::parse(Path::new(""), "# build output\ntarget/\n*.log\n!keep.log\n/build.rs\ndocs/**/*.html\n");
+        let ignored = |path: &str, is_dir| is_ignored(std::slice::from_ref(&root), Path::new(path), is_dir);
+        assert!(ignored("target", true) && ignored("sub/target", true));
+        assert!(!ignored("target", false));
+        assert!(ignored("a.log", false) && ignored("sub/a.log", false));
+        assert!(!ignored("keep.log", false));
+        assert!(ignored("build.rs", false) && !ignored("sub/build.rs", false));
+        assert!(ignored("docs/api/x/index.html", false) && !ignored("src/index.html", false));
+
+        let nested = [root, Gitignore::parse(Path::new("sub"), "!a.log\ngen.rs")];
+        assert!(!is_ignored(&nested, Path::new("sub/a.log"), false));
+        assert!(is_ignored(&nested, Path::new("sub/deeper/gen.rs"), false));
+        assert!(!is_ignored(&nested, Path::new("gen.rs"), false));
+    }
+}
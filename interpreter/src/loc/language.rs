@@ -0,0 +1,342 @@
+// Line classification for the languages we care most about.
+//
+// Source is scanned as one stream so that block comments and strings spanning lines are
+// tracked, nested `/* */` comments included. Each line ends up in the highest of the classes
+// it touched: code over doc comments over comments over blank. Lines inside a multi-line
+// comment count as comment even when empty, lines inside a multi-line string as code.
+// Markdown is prose, counted as documentation, with fenced code blocks as code and HTML
+// comments as comments.
+
+use super::Counts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Language {
+    Rust,
+    Toml,
+    Json,
+    Python,
+    Shell,
+    Markdown,
+}
+
+impl Language {
+    pub fn from_extension(extension: &str) -> Option<Language> {
+        Some(match extension {
+            "rs" => Language::Rust,
+            "toml" => Language::Toml,
+            "json" => Language::Json,
+            "py" | "pyi" => Language::Python,
+            "sh" | "bash" | "zsh" => Language::Shell,
+            "md" | "markdown" => Language::Markdown,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::Rust => "Rust",
+            Language::Toml => "TOML",
+            Language::Json => "JSON",
+            Language::Python => "Python",
+            Language::Shell => "Shell",
+            Language::Markdown => "Markdown",
+        }
+    }
+}
+
+// Ordered so the strongest class seen on a line wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Class {
+    Blank,
+    Comment,
+    Doc,
+    Code,
+}
+
+fn tally(counts: &mut Counts, class: Class) {
+    counts.lines += 1;
+    match class {
+        Class::Blank => counts.blank += 1,
+        Class::Comment => counts.comment += 1,
+        Class::Doc => counts.doc += 1,
+        Class::Code => {}
+    }
+}
+
+enum State {
+    Code,
+    Block { depth: usize, doc: bool },
+    Str { close: String, escapes: bool, multiline: bool, doc: bool },
+}
+
+struct Syntax {
+    line: &'static [&'static str],
+    // Checked before `line`. A doc marker followed by its own last character is a plain
+    // comment, `////` and `/***` in Rust.
+    doc_line: &'static [&'static str],
+    block: Option<(&'static str, &'static str)>,
+    doc_block: &'static [&'static str],
+}
+
+fn syntax(language: Language) -> Syntax {
+    match language {
+        Language::Rust => Syntax {
+            line: &["//"],
+            doc_line: &["///", "//!"],
+            block: Some(("/*", "*/")),
+            doc_block: &["/**", "/*!"],
+        },
+        Language::Toml | Language::Python | Language::Shell => Syntax { line: &["#"], doc_line: &[], block: None, doc_block: &[] },
+        Language::Json | Language::Markdown => Syntax { line: &[], doc_line: &[], block: None, doc_block: &[] },
+    }
+}
+
+fn is_doc(markers: &[&str], rest: &str) -> bool {
+    markers.iter().any(|marker| {
+        rest.starts_with(marker) && !rest[marker.len()..].starts_with(&marker[marker.len() - 1..])
+    })
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Length of the Rust char literal at the start of `rest`, `None` for a lifetime.
+fn char_literal(rest: &str) -> Option<usize> {
+    let mut chars = rest.char_indices().skip(1);
+    let (_, first) = chars.next()?;
+    if first == '\\' {
+        // `'\n'`, `'\''`, `'\u{1F600}'`: the escaped character, then up to the closing quote.
+        chars.next()?;
+        return chars.find(|&(_, c)| c == '\'').map(|(at, _)| at + 1)
+    }
+    match chars.next()? {
+        (at, '\'') => Some(at + 1),
+        _ => None,
+    }
+}
+
+// A string opening at the start of `rest`: its length, closing delimiter, whether backslash
+// escapes apply and whether it may span lines.
+fn string_start(language: Language, rest: &str, prev: Option<char>) -> Option<(usize, String, bool, bool)> {
+    let quoted = |quote: &str, escapes, multiline| rest.starts_with(quote).then(|| (quote.len(), quote.to_string(), escapes, multiline));
+    match language {
+        Language::Rust => {
+            if prev.is_some_and(is_ident) {
+                return None
+            }
+            // Raw strings, `r"…"`, `r#"…"#`, `br##"…"##`.
+            let after_b = rest.strip_prefix('b').unwrap_or(rest);
+            if let Some(after_r) = after_b.strip_prefix('r') {
+                let hashes = after_r.len() - after_r.trim_start_matches('#').len();
+                if after_r[hashes..].starts_with('"') {
+                    let open = rest.len() - after_r.len() + hashes + 1;
+                    return Some((open, format!("\"{}", "#".repeat(hashes)), false, true))
+                }
+            }
+            quoted("b\"", true, true).or_else(|| quoted("\"", true, true))
+        }
+        Language::Python => {
+            // String prefixes such as `r`, `b` and `f`.
+            let body = rest.trim_start_matches(|c: char| "rRbBfFuU".contains(c));
+            let prefix = rest.len() - body.len();
+            if prefix > 2 || prefix > 0 && prev.is_some_and(is_ident) {
+                return None
+            }
+            let escapes = !rest[..prefix].contains(['r', 'R']);
+            ["\"\"\"", "'''", "\"", "'"].iter()
+                .find(|quote| body.starts_with(*quote))
+                .map(|quote| (prefix + quote.len(), quote.to_string(), escapes, quote.len() == 3))
+        }
+        Language::Toml => quoted("\"\"\"", true, true)
+            .or_else(|| quoted("'''", false, true))
+            .or_else(|| quoted("\"", true, false))
+            .or_else(|| quoted("'", false, false)),
+        Language::Shell => quoted("\"", true, true).or_else(|| quoted("'", false, true)),
+        Language::Json => quoted("\"", true, false),
+        Language::Markdown => None,
+    }
+}
+
+fn count_source(text: &str, language: Language) -> Counts {
+    let syntax = syntax(language);
+    let mut counts = Counts { bytes: text.len() as u64, ..Counts::default() };
+    let mut state = State::Code;
+    for line in text.lines() {
+        let mut class = match state {
+            State::Code => Class::Blank,
+            State::Block { doc: true, .. } => Class::Doc,
+            State::Block { doc: false, .. } => Class::Comment,
+            State::Str { doc: true, .. } => Class::Doc,
+            State::Str { doc: false, .. } => Class::Code,
+        };
+        let mut i = 0;
+        let mut prev = None;
+        while let Some(c) = line[i..].chars().next() {
+            let rest = &line[i..];
+            let mut step = c.len_utf8();
+            match &mut state {
+                State::Block { depth, doc } => {
+                    let (open, close) = syntax.block.unwrap();
+                    class = class.max(if *doc { Class::Doc } else { Class::Comment });
+                    if rest.starts_with(close) {
+                        step = close.len();
+                        *depth -= 1;
+                        if *depth == 0 {
+                            state = State::Code;
+                        }
+                    } else if rest.starts_with(open) {
+                        // Rust block comments nest.
+                        step = open.len();
+                        *depth += 1;
+                    }
+                }
+                State::Str { close, escapes, doc, .. } => {
+                    class = class.max(if *doc { Class::Doc } else { Class::Code });
+                    if *escapes && c == '\\' {
+                        step += rest[1..].chars().next().map_or(0, char::len_utf8);
+                    } else if rest.starts_with(close.as_str()) {
+                        step = close.len();
+                        state = State::Code;
+                    }
+                }
+                State::Code if c.is_whitespace() => {}
+                State::Code => {
+                    // Shell only starts comments at the start of a word, `${#list}` isn't one.
+                    let comment_allowed = language != Language::Shell || prev.is_none_or(|prev: char| prev.is_whitespace() || prev == ';');
+                    if comment_allowed && is_doc(syntax.doc_line, rest) {
+                        class = class.max(Class::Doc);
+                        break
+                    }
+                    if comment_allowed && syntax.line.iter().any(|marker| rest.starts_with(marker)) {
+                        class = class.max(Class::Comment);
+                        break
+                    }
+                    if let Some((open, _)) = syntax.block.filter(|(open, _)| rest.starts_with(open)) {
+                        let doc = is_doc(syntax.doc_block, rest) && !rest.starts_with("/**/");
+                        step = open.len();
+                        state = State::Block { depth: 1, doc };
+                        class = class.max(if doc { Class::Doc } else { Class::Comment });
+                    } else if language == Language::Rust && c == '\'' {
+                        // A char literal, possibly `'"'`, or a lifetime.
+                        step = char_literal(rest).unwrap_or(1);
+                        class = Class::Code;
+                    } else if let Some((open, close, escapes, multiline)) = string_start(language, rest, prev) {
+                        // A string alone at the start of a line is a Python docstring.
+                        let doc = language == Language::Python && multiline && class == Class::Blank;
+                        step = open;
+                        state = State::Str { close, escapes, multiline, doc };
+                        class = class.max(if doc { Class::Doc } else { Class::Code });
+                    } else {
+                        class = Class::Code;
+                    }
+                }
+            }
+            prev = line[..i + step].chars().next_back();
+            i += step;
+        }
+        if matches!(state, State::Str { multiline: false, .. }) {
+            state = State::Code;
+        }
+        tally(&mut counts, class);
+    }
+    counts
+}
+
+// `<!-- -->` comments and fences of at least three backticks or tildes.
+fn count_markdown(text: &str) -> Counts {
+    let mut counts = Counts { bytes: text.len() as u64, ..Counts::default() };
+    let mut fence: Option<String> = None;
+    let mut in_comment = false;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        let indent = line.len() - line.trim_start().len();
+        let marker: String = trimmed.chars().take_while(|&c| c == '`' || c == '~').collect();
+        let is_fence = indent < 4 && marker.len() >= 3 && marker.chars().all(|c| c == marker.as_bytes()[0] as char);
+        let class = if let Some(open) = &fence {
+            // Any run of the same character at least as long as the opener closes the block.
+            if is_fence && marker.starts_with(open.as_str()) && trimmed[marker.len()..].trim().is_empty() {
+                fence = None;
+            }
+            Class::Code
+        } else if in_comment || trimmed.starts_with("<!--") {
+            in_comment = !trimmed.contains("-->");
+            Class::Comment
+        } else if trimmed.is_empty() {
+            Class::Blank
+        } else if is_fence {
+            fence = Some(marker);
+            Class::Code
+        } else {
+            Class::Doc
+        };
+        tally(&mut counts, class);
+    }
+    counts
+}
+
+/// Counts the lines of `text` written in `language`.
+pub fn count(text: &str, language: Language) -> Counts {
+    match language {
+        Language::Markdown => count_markdown(text),
+        _ => count_source(text, language),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classes(text: &str, language: Language) -> (u64, u64, u64, u64) {
+        let counts = count(text, language);
+        (counts.code(), counts.comment, counts.doc, counts.blank)
+    }
+
+    #[test]
+    fn rust() {
+        let text = r##"//! Crate docs.
+
+/// Item docs.
+//// Not docs.
+fn main() {
+    let url = "http://example.com"; // trailing
+    let open = "/*";
+    let quote = '"';
+    let raw = r#"still " a string // here"#;
+    /* outer /* nested */ still
+       comment */
+    /** doc block */
+    /***/
+    'outer: loop { break 'outer }
+}
+"##;
+        assert_eq!(classes(text, Language::Rust), (7, 4, 3, 1));
+        // A string may span lines, comment markers and all.
+        assert_eq!(classes("let s = \"a\n// b\n\";\n", Language::Rust), (3, 0, 0, 0));
+        assert_eq!(classes("let c = '\\'';\n// x\n", Language::Rust), (1, 1, 0, 0));
+    }
+
+    #[test]
+    fn toml_json_shell() {
+        let toml = "# deps\n[dependencies]\nurl = \"http://x#y\" # pinned\nnotes = '''\n# not a comment\n'''\n";
+        assert_eq!(classes(toml, Language::Toml), (5, 1, 0, 0));
+        assert_eq!(classes("{\n  \"a\": \"// b\",\n\n  \"c\": 1\n}\n", Language::Json), (4, 0, 0, 1));
+        let shell = "#!/bin/sh\n# hi\necho \"# no\" '# no' ${#list} a#b # yes\n\n";
+        assert_eq!(classes(shell, Language::Shell), (1, 2, 0, 1));
+    }
+
+    #[test]
+    fn python() {
+        let text = "\"\"\"Module docs.\n\nMore.\n\"\"\"\n\ndef f():\n    '''Docs.'''\n    s = \"\"\"\n# not a comment\n\"\"\"\n    return '#' # done\n";
+        assert_eq!(classes(text, Language::Python), (5, 0, 5, 1));
+        assert_eq!(classes("x = r'\\' # c\n", Language::Python), (1, 0, 0, 0));
+    }
+
+    #[test]
+    fn markdown() {
+        let text = "# Title\n\nSome prose.\n<!-- hidden\nstill hidden -->\n```rust\n// code\n\n```\n";
+        assert_eq!(classes(text, Language::Markdown), (4, 2, 2, 1));
+        assert_eq!(classes("~~~\n```\n~~~\nafter\n", Language::Markdown), (3, 0, 1, 0));
+        assert_eq!(classes("````\n```\n`````\nafter\n", Language::Markdown), (3, 0, 1, 0));
+    }
+}
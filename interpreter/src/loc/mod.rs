@@ -0,0 +1,695 @@
+// Counting lines of code across a directory tree.
+//
+// `stream` walks the tree depth first on one thread, skipping what `.gitignore` files and the
+// exclude globs rule out, and hands every remaining file with a matching extension to a pool
+// of workers. Each file is read once and its lines classified as blank, comment, doc comment
+// or code, by `language` for the languages it knows and by line comment markers for the rest;
+// binary files and files that aren't UTF-8 are skipped. Results arrive as `Entry`s while the
+// scan runs, `scan` collects them into a `Report` that the `loc` binary prints as a table of
+// languages or as JSON.
+
+mod cache;
+mod gitignore;
+mod language;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use glob::Pattern;
+use walkdir::{DirEntry, WalkDir};
+
+use crate::json::write_json_string;
+use gitignore::{is_ignored, slashed, Gitignore};
+pub use cache::{Cache, CacheStats, Diff};
+pub use language::Language;
+
+// Bytes looked at for a NUL before a file counts as text.
+const BINARY_PROBE: usize = 8000;
+
+// Paths found but not yet picked up by a worker, bounds how far the walk runs ahead.
+const QUEUED_FILES: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counts {
+    pub lines: u64,
+    pub blank: u64,
+    // Plain comments, doc comments are counted separately.
+    pub comment: u64,
+    pub doc: u64,
+    pub bytes: u64,
+}
+
+impl Counts {
+    pub fn code(&self) -> u64 {
+        self.lines - self.blank - self.comment - self.doc
+    }
+
+    /// Percentage of non-blank lines that are comments or docs.
+    pub fn comment_density(&self) -> Option<f64> {
+        let written = self.lines - self.blank;
+        (written > 0).then(|| (self.comment + self.doc) as f64 * 100.0 / written as f64)
+    }
+}
+
+impl AddAssign for Counts {
+    fn add_assign(&mut self, other: Counts) {
+        self.lines += other.lines;
+        self.blank += other.blank;
+        self.comment += other.comment;
+        self.doc += other.doc;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStats {
+    pub path: PathBuf,
+    pub counts: Counts,
+}
+
+impl FileStats {
+    /// Empty for files without one.
+    pub fn extension(&self) -> &str {
+        self.path.extension().and_then(|extension| extension.to_str()).unwrap_or("")
+    }
+
+    /// The name the file is summarised under, "other" for languages without a classifier.
+    pub fn language(&self) -> &'static str {
+        Language::from_extension(self.extension()).map_or("other", Language::name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Totals {
+    pub files: u64,
+    pub counts: Counts,
+}
+
+impl Totals {
+    fn add(&mut self, counts: Counts) {
+        self.files += 1;
+        self.counts += counts;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    // These three are ordered by path.
+    pub files: Vec<FileStats>,
+    pub skipped: Vec<(PathBuf, Skip)>,
+    pub errors: Vec<ScanError>,
+    pub by_extension: BTreeMap<String, Totals>,
+    pub by_language: BTreeMap<&'static str, Totals>,
+    pub total: Totals,
+}
+
+impl FromIterator<Entry> for Report {
+    fn from_iter<I: IntoIterator<Item = Entry>>(entries: I) -> Self {
+        let mut report = Report::default();
+        for entry in entries {
+            match entry {
+                Entry::Counted(stats) => {
+                    report.by_extension.entry(stats.extension().to_string()).or_default().add(stats.counts);
+                    report.by_language.entry(stats.language()).or_default().add(stats.counts);
+                    report.total.add(stats.counts);
+                    report.files.push(stats);
+                }
+                Entry::Skipped { path, reason } => report.skipped.push((path, reason)),
+                Entry::Failed(err) => report.errors.push(err),
+            }
+        }
+        report.files.sort_by(|a, b| a.path.cmp(&b.path));
+        report.skipped.sort();
+        report.errors.sort_by(|a, b| a.path.cmp(&b.path));
+        report
+    }
+}
+
+/// What `scan` looks at. Globs match paths relative to the root, `*` crosses directories.
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Only files with one of these extensions, all files if empty.
+    pub extensions: Vec<String>,
+    // If not empty, files must match one of these.
+    pub include: Vec<Pattern>,
+    // Files and directories matching one of these are skipped.
+    pub exclude: Vec<Pattern>,
+    pub gitignore: bool,
+    // Symlinked files and directories are skipped unless set. A link back to one of its own
+    // ancestors is reported as an error.
+    pub follow_symlinks: bool,
+    // Files are counted on this many threads, one per core if 0.
+    pub threads: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            extensions: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            gitignore: true,
+            follow_symlinks: false,
+            threads: 0,
+        }
+    }
+}
+
+/// Why a file wasn't counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Skip {
+    Binary,
+    NotUtf8,
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Skip::Binary => "binary",
+            Skip::NotUtf8 => "not UTF-8",
+        })
+    }
+}
+
+/// One result of a scan.
+#[derive(Debug)]
+pub enum Entry {
+    Counted(FileStats),
+    Skipped { path: PathBuf, reason: Skip },
+    Failed(ScanError),
+}
+
+impl Entry {
+    fn from_outcome(path: &Path, outcome: Result<Counts, Skip>) -> Entry {
+        match outcome {
+            Ok(counts) => Entry::Counted(FileStats { path: path.into(), counts }),
+            Err(reason) => Entry::Skipped { path: path.into(), reason },
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Entry::Counted(stats) => &stats.path,
+            Entry::Skipped { path, .. } => path,
+            Entry::Failed(err) => &err.path,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ScanError {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+impl std::error::Error for ScanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// Line comment markers for languages without a `Language`. Block comments are only recognised
+// on the line they start.
+fn comment_markers(extension: &str) -> &'static [&'static str] {
+    match extension {
+        "c" | "h" | "cc" | "cpp" | "hpp" | "cs" | "go" | "java" | "js" | "jsx" | "kt"
+            | "scala" | "swift" | "ts" | "tsx" | "src" => &["//", "/*"],
+        "yaml" | "yml" | "rb" | "pl" | "r" | "mk" => &["#"],
+        "sql" | "lua" | "hs" => &["--"],
+        "asm" | "lisp" | "clj" | "el" => &[";"],
+        _ => &[],
+    }
+}
+
+/// Counts the lines of `text` as if it were the contents of a file with `extension`.
+pub fn count_text(text: &str, extension: &str) -> Counts {
+    if let Some(language) = Language::from_extension(extension) {
+        return language::count(text, language)
+    }
+    let markers = comment_markers(extension);
+    let mut counts = Counts { bytes: text.len() as u64, ..Counts::default() };
+    for line in text.lines() {
+        let line = line.trim();
+        counts.lines += 1;
+        if line.is_empty() {
+            counts.blank += 1;
+        } else if markers.iter().any(|marker| line.starts_with(marker)) {
+            counts.comment += 1;
+        }
+    }
+    counts
+}
+
+/// Reads and counts one file. Files that look binary or aren't UTF-8 are skipped.
+pub fn count_file(path: &Path) -> Entry {
+    match std::fs::read(path) {
+        Ok(bytes) => Entry::from_outcome(path, classify(path, bytes)),
+        Err(error) => Entry::Failed(ScanError { path: path.into(), error }),
+    }
+}
+
+// Counts the contents of the file at `path`.
+fn classify(path: &Path, bytes: Vec<u8>) -> Result<Counts, Skip> {
+    // Like git, a NUL byte near the start means binary.
+    if bytes[..bytes.len().min(BINARY_PROBE)].contains(&0) {
+        return Err(Skip::Binary)
+    }
+    let text = String::from_utf8(bytes).map_err(|_| Skip::NotUtf8)?;
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+    Ok(count_text(&text, extension))
+}
+
+// Decides which entries the walk descends into or yields. Entries arrive depth first, so the
+// `.gitignore` files in effect are those of the directories on the way down to the current one.
+struct Filter<'a> {
+    root: &'a Path,
+    options: &'a Options,
+    // A file never to count, canonical.
+    skip: Option<&'a Path>,
+    ignores: Vec<Gitignore>,
+    // Depth of the directory each of `ignores` came from.
+    depths: Vec<usize>,
+}
+
+impl Filter<'_> {
+    fn keep(&mut self, entry: &DirEntry) -> bool {
+        while self.depths.last().is_some_and(|&depth| depth >= entry.depth()) {
+            self.depths.pop();
+            self.ignores.pop();
+        }
+        let relative = entry.path().strip_prefix(self.root).unwrap_or(entry.path());
+        let is_dir = entry.file_type().is_dir();
+        if entry.depth() > 0 {
+            if self.skip.is_some_and(|skip| skip.file_name() == Some(entry.file_name()) && canonical(entry.path()).as_deref() == Some(skip)) {
+                return false
+            }
+            if self.options.gitignore && (is_dir && entry.file_name() == ".git" || is_ignored(&self.ignores, relative, is_dir)) {
+                return false
+            }
+            if self.options.exclude.iter().any(|pattern| pattern.matches(&slashed(relative))) {
+                return false
+            }
+        }
+        if is_dir && self.options.gitignore {
+            if let Ok(text) = std::fs::read_to_string(entry.path().join(".gitignore")) {
+                self.ignores.push(Gitignore::parse(relative, &text));
+                self.depths.push(entry.depth());
+            }
+        }
+        true
+    }
+
+    fn wanted(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(self.root).unwrap_or(path);
+        let extension = relative.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        (self.options.extensions.is_empty() || self.options.extensions.iter().any(|wanted| wanted == extension))
+            && (self.options.include.is_empty()
+                || self.options.include.iter().any(|pattern| pattern.matches(&slashed(relative))))
+    }
+}
+
+// `path` with symlinks resolved, for files that may not exist yet as well.
+fn canonical(path: &Path) -> Option<PathBuf> {
+    if let Ok(path) = path.canonicalize() {
+        return Some(path)
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Some(parent.canonicalize().ok()?.join(path.file_name()?))
+}
+
+fn walk_error(err: walkdir::Error, root: &Path) -> ScanError {
+    let path = err.path().unwrap_or(root).to_path_buf();
+    let error = match err.loop_ancestor() {
+        Some(ancestor) => io::Error::other(format!("symlink loop back to {}", ancestor.display())),
+        None => err.into_io_error().unwrap_or_else(|| io::Error::other("unreadable entry")),
+    };
+    ScanError { path, error }
+}
+
+// Sends the files to count to the workers and walk errors straight to the results. Stops
+// early once nobody is listening.
+fn walk(root: &Path, options: &Options, skip: Option<&Path>, paths: &SyncSender<PathBuf>, entries: &Sender<Entry>) {
+    let mut filter = Filter { root, options, skip, ignores: Vec::new(), depths: Vec::new() };
+    let mut walker = WalkDir::new(root).follow_links(options.follow_symlinks).sort_by_file_name().into_iter();
+    while let Some(entry) = walker.next() {
+        let sent = match entry {
+            Ok(entry) if !filter.keep(&entry) => {
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                continue
+            }
+            // Symlinks only show up as such when they aren't followed.
+            Ok(entry) if !entry.file_type().is_file() || !filter.wanted(entry.path()) => continue,
+            Ok(entry) => paths.send(entry.into_path()).is_ok(),
+            Err(err) => entries.send(Entry::Failed(walk_error(err, root))).is_ok(),
+        };
+        if !sent {
+            return
+        }
+    }
+}
+
+/// Results of `stream` in the order the workers finish them.
+pub struct Scan {
+    entries: Receiver<Entry>,
+}
+
+impl Iterator for Scan {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        self.entries.recv().ok()
+    }
+}
+
+/// Starts counting every file under `root` that `options` selects in the background. One
+/// thread walks the tree while `options.threads` workers read and count the files.
+/// Dropping the `Scan` early stops them.
+pub fn stream(root: &Path, options: &Options) -> Scan {
+    start(root, options, None)
+}
+
+/// Like `stream`, but only files that changed since `cache` was saved are counted again. The
+/// cache file itself is never counted. Call `Cache::save` once the scan is done.
+pub fn stream_cached(root: &Path, options: &Options, cache: &Arc<Cache>) -> Scan {
+    start(root, options, Some(Arc::clone(cache)))
+}
+
+fn start(root: &Path, options: &Options, cache: Option<Arc<Cache>>) -> Scan {
+    let threads = match options.threads {
+        0 => thread::available_parallelism().map_or(1, |threads| threads.get()),
+        threads => threads,
+    };
+    let (paths_sender, paths) = mpsc::sync_channel::<PathBuf>(QUEUED_FILES);
+    let paths = Arc::new(Mutex::new(paths));
+    let (entries_sender, entries) = mpsc::channel();
+    for _ in 0..threads {
+        let (paths, entries, cache) = (Arc::clone(&paths), entries_sender.clone(), cache.clone());
+        thread::spawn(move || loop {
+            let Ok(path) = paths.lock().unwrap().recv() else { return };
+            let entry = match &cache {
+                Some(cache) => cache.count(&path),
+                None => count_file(&path),
+            };
+            if entries.send(entry).is_err() {
+                return
+            }
+        });
+    }
+    let (root, options) = (root.to_path_buf(), options.clone());
+    let skip = cache.and_then(|cache| canonical(cache.path()));
+    thread::spawn(move || walk(&root, &options, skip.as_deref(), &paths_sender, &entries_sender));
+    Scan { entries }
+}
+
+/// Counts every file under `root` that `options` selects. Files and directories that can't
+/// be read end up in `Report::errors`, the rest of the tree is still counted.
+pub fn scan(root: &Path, options: &Options) -> Report {
+    stream(root, options).collect()
+}
+
+fn write_json_counts(out: &mut impl Write, counts: &Counts) -> io::Result<()> {
+    write!(out, "\"lines\":{},\"code\":{},\"comment\":{},\"doc\":{},\"blank\":{},\"bytes\":{}",
+        counts.lines, counts.code(), counts.comment, counts.doc, counts.blank, counts.bytes)
+}
+
+fn write_json_totals(out: &mut impl Write, totals: &Totals) -> io::Result<()> {
+    write!(out, "{{\"files\":{},", totals.files)?;
+    write_json_counts(out, &totals.counts)?;
+    out.write_all(b"}")
+}
+
+impl Report {
+    /// Writes `{"files":[{"path":..,"extension":..,"lines":..,..}],"skipped":[{"path":..,"reason":..}],
+    /// "errors":[{"path":..,"error":..}],"by_extension":{..},"by_language":{..},"total":{..}}`.
+    pub fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(b"{\"files\":[")?;
+        for (index, file) in self.files.iter().enumerate() {
+            if index > 0 {
+                out.write_all(b",")?;
+            }
+            out.write_all(b"{\"path\":")?;
+            write_json_string(out, &file.path.to_string_lossy())?;
+            out.write_all(b",\"extension\":")?;
+            write_json_string(out, file.extension())?;
+            out.write_all(b",")?;
+            write_json_counts(out, &file.counts)?;
+            out.write_all(b"}")?;
+        }
+        out.write_all(b"],\"skipped\":[")?;
+        for (index, (path, reason)) in self.skipped.iter().enumerate() {
+            if index > 0 {
+                out.write_all(b",")?;
+            }
+            out.write_all(b"{\"path\":")?;
+            write_json_string(out, &path.to_string_lossy())?;
+            out.write_all(b",\"reason\":")?;
+            write_json_string(out, &reason.to_string())?;
+            out.write_all(b"}")?;
+        }
+        out.write_all(b"],\"errors\":[")?;
+        for (index, err) in self.errors.iter().enumerate() {
+            if index > 0 {
+                out.write_all(b",")?;
+            }
+            out.write_all(b"{\"path\":")?;
+            write_json_string(out, &err.path.to_string_lossy())?;
+            out.write_all(b",\"error\":")?;
+            write_json_string(out, &err.error.to_string())?;
+            out.write_all(b"}")?;
+        }
+        out.write_all(b"],\"by_extension\":{")?;
+        for (index, (extension, totals)) in self.by_extension.iter().enumerate() {
+            if index > 0 {
+                out.write_all(b",")?;
+            }
+            write_json_string(out, extension)?;
+            out.write_all(b":")?;
+            write_json_totals(out, totals)?;
+        }
+        out.write_all(b"},\"by_language\":{")?;
+        for (index, (language, totals)) in self.by_language.iter().enumerate() {
+            if index > 0 {
+                out.write_all(b",")?;
+            }
+            write_json_string(out, language)?;
+            out.write_all(b":")?;
+            write_json_totals(out, totals)?;
+        }
+        out.write_all(b"},\"total\":")?;
+        write_json_totals(out, &self.total)?;
+        out.write_all(b"}\n")
+    }
+}
+
+fn table_row(f: &mut fmt::Formatter, name: &str, files: u64, counts: &Counts) -> fmt::Result {
+    let density = counts.comment_density().map_or("-".to_string(), |density| format!("{:.1}%", density));
+    writeln!(f, "{:<12}{:>8}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}",
+        name, files, counts.lines, counts.code(), counts.comment, counts.doc, counts.blank, density)
+}
+
+// Totals per language, largest first.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<12}{:>8}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}",
+            "language", "files", "lines", "code", "comment", "doc", "blank", "density")?;
+        let rule = "-".repeat(80);
+        writeln!(f, "{}", rule)?;
+        let mut languages: Vec<(&&str, &Totals)> = self.by_language.iter().collect();
+        languages.sort_by(|a, b| b.1.counts.lines.cmp(&a.1.counts.lines).then(a.0.cmp(b.0)));
+        for (language, totals) in languages {
+            table_row(f, language, totals.files, &totals.counts)?;
+        }
+        writeln!(f, "{}", rule)?;
+        table_row(f, "total", self.total.files, &self.total.counts)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    // A scratch directory removed again when dropped.
+    pub(super) struct Tree(pub(super) PathBuf);
+
+    impl Tree {
+        pub(super) fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let root = std::env::temp_dir().join(format!("loc-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            for (path, text) in files {
+                let path = root.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, text).unwrap();
+            }
+            Tree(root)
+        }
+
+        fn scan(&self, options: &Options) -> Vec<String> {
+            let report = scan(&self.0, options);
+            assert!(report.errors.is_empty(), "{:?}", report.errors);
+            report.files.iter().map(|file| slashed(file.path.strip_prefix(&self.0).unwrap())).collect()
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn classifies_lines() {
+        let counts = count_text("fn main() {\n\n    // hi\n    let x = 1; // trailing\n  /* block */\n}\n", "rs");
+        assert_eq!(counts, Counts { lines: 6, blank: 1, comment: 2, doc: 0, bytes: 66 });
+        assert_eq!(counts.code(), 3);
+        assert_eq!(counts.comment_density(), Some(40.0));
+        assert_eq!(count_text("# title\nx = 1\n", "md").comment, 0);
+        assert_eq!(count_text("# title\nx = 1\n", "md").doc, 2);
+        assert_eq!(count_text("# x\nx = 1\n", "txt").comment, 0);
+        assert_eq!(count_text("/* a\n b */\n", "c").comment, 1);
+        assert_eq!(count_text("; loop\nload 1\n", "asm").comment, 1);
+        assert_eq!(count_text("", "rs"), Counts::default());
+    }
+
+    #[test]
+    fn honours_gitignore_and_globs() {
+        let tree = Tree::new("walk", &[
+            (".gitignore", "target/\n*.log\n!keep.log\n/build.rs\n"),
+            (".git/config", "[core]\n"),
+            ("build.rs", "fn main() {}\n"),
+            ("a.log", "noise\n"),
+            ("keep.log", "kept\n"),
+            ("notes.md", "# notes\n\ntext\n"),
+            ("src/.gitignore", "gen.rs\n"),
+            ("src/gen.rs", "// generated\n"),
+            ("src/main.rs", "fn main() {\n    // hi\n}\n"),
+            ("sub/build.rs", "fn main() {}\n"),
+            ("target/debug/x.rs", "fn x() {}\n"),
+        ]);
+        let all = Options::default();
+        assert_eq!(tree.scan(&all), vec![".gitignore", "keep.log", "notes.md", "src/.gitignore", "src/main.rs", "sub/build.rs"]);
+
+        let rust = Options { extensions: vec!["rs".into()], ..Options::default() };
+        assert_eq!(tree.scan(&rust), vec!["src/main.rs", "sub/build.rs"]);
+        let several = Options { extensions: vec!["md".into(), "log".into()], threads: 1, ..Options::default() };
+        assert_eq!(tree.scan(&several), vec!["keep.log", "notes.md"]);
+
+        let globs = Options {
+            include: vec![Pattern::new("*.rs").unwrap(), Pattern::new("*.md").unwrap()],
+            exclude: vec![Pattern::new("sub").unwrap()],
+            ..Options::default()
+        };
+        assert_eq!(tree.scan(&globs), vec!["notes.md", "src/main.rs"]);
+
+        let everything = Options { gitignore: false, extensions: vec!["rs".into()], ..Options::default() };
+        assert_eq!(tree.scan(&everything), vec!["build.rs", "src/gen.rs", "src/main.rs", "sub/build.rs", "target/debug/x.rs"]);
+    }
+
+    #[test]
+    fn totals_and_output() {
+        let tree = Tree::new("report", &[
+            ("a.rs", "fn a() {}\n\n// a\n"),
+            ("b.rs", "fn b() {}\n"),
+            ("c.py", "# c\nc = 1\n"),
+            ("LICENSE", "MIT\n"),
+        ]);
+        let report = scan(&tree.0, &Options::default());
+        assert_eq!(report.total, Totals { files: 4, counts: Counts { lines: 7, blank: 1, comment: 2, doc: 0, bytes: 40 } });
+        let rust = Totals { files: 2, counts: Counts { lines: 4, blank: 1, comment: 1, doc: 0, bytes: 26 } };
+        assert_eq!(report.by_extension["rs"], rust);
+        assert_eq!(report.by_language["Rust"], rust);
+        assert_eq!(report.by_extension[""].files, 1);
+        assert_eq!(report.by_language["other"].files, 1);
+
+        let table = report.to_string();
+        assert!(table.lines().nth(2).unwrap().starts_with("Rust "));
+        assert!(table.lines().nth(2).unwrap().ends_with("33.3%"));
+        assert!(table.contains("Python"));
+        assert!(table.lines().last().unwrap().starts_with("total"));
+
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains(&format!("{{\"path\":\"{}\",\"extension\":\"py\",\"lines\":2,\"code\":1,\"comment\":1,\"doc\":0,\"blank\":0,\"bytes\":10}}",
+            tree.0.join("c.py").display())));
+        assert!(json.ends_with("\"total\":{\"files\":4,\"lines\":7,\"code\":4,\"comment\":2,\"doc\":0,\"blank\":1,\"bytes\":40}}\n"));
+    }
+
+    #[test]
+    fn unreadable_roots_are_errors() {
+        let report = scan(Path::new("/definitely/not/here"), &Options::default());
+        assert_eq!(report.total, Totals::default());
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].path, Path::new("/definitely/not/here"));
+        assert_eq!(report.errors[0].error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn binary_files_are_reported_not_fatal() {
+        let tree = Tree::new("binary", &[("a.rs", "fn a() {}\n"), ("z.txt", "ok\n")]);
+        std::fs::write(tree.0.join("image.png"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        std::fs::write(tree.0.join("latin1.txt"), b"caf\xe9\n").unwrap();
+        let report = scan(&tree.0, &Options::default());
+        assert_eq!(report.total.files, 2);
+        assert_eq!(report.skipped, vec![(tree.0.join("image.png"), Skip::Binary), (tree.0.join("latin1.txt"), Skip::NotUtf8)]);
+        assert!(report.errors.is_empty());
+
+        let mut json = Vec::new();
+        report.write_json(&mut json).unwrap();
+        assert!(String::from_utf8(json).unwrap().contains("latin1.txt\",\"reason\":\"not UTF-8\"}]"));
+    }
+
+    #[test]
+    fn streams_every_file_once() {
+        let names: Vec<String> = (0..300).map(|index| format!("d{}/f{}.rs", index % 7, index)).collect();
+        let files: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), "x\n")).collect();
+        let tree = Tree::new("stream", &files);
+        let mut seen: Vec<PathBuf> = stream(&tree.0, &Options { threads: 4, ..Options::default() })
+            .map(|entry| match entry {
+                Entry::Counted(stats) => stats.path,
+                other => panic!("{:?}", other),
+            })
+            .collect();
+        seen.sort();
+        let mut expected: Vec<PathBuf> = names.iter().map(|name| tree.0.join(name)).collect();
+        expected.sort();
+        assert_eq!(seen, expected);
+
+        // Stopping early doesn't hang.
+        assert_eq!(stream(&tree.0, &Options::default()).take(3).count(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_followed_on_request() {
+        use std::os::unix::fs::symlink;
+        let tree = Tree::new("links", &[("real/a.rs", "fn a() {}\n")]);
+        symlink(tree.0.join("real"), tree.0.join("alias")).unwrap();
+        symlink(&tree.0, tree.0.join("real/up")).unwrap();
+
+        assert_eq!(tree.scan(&Options::default()), vec!["real/a.rs"]);
+
+        let report = scan(&tree.0, &Options { follow_symlinks: true, ..Options::default() });
+        let found: Vec<String> = report.files.iter().map(|file| slashed(file.path.strip_prefix(&tree.0).unwrap())).collect();
+        assert_eq!(found, vec!["alias/a.rs", "real/a.rs"]);
+        let loops: Vec<String> = report.errors.iter().map(|err| slashed(err.path.strip_prefix(&tree.0).unwrap())).collect();
+        assert_eq!(loops, vec!["alias/up", "real/up"]);
+        assert!(report.errors[0].to_string().contains("symlink loop back to"));
+    }
+}
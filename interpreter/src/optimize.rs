@@ -0,0 +1,387 @@
+// Peephole optimisation of programs.
+//
+// The passes below run until nothing changes:
+//
+//   - jump threading: jumps and calls landing on a `Jump` go straight to its destination
+//   - constant folding: two constant loads followed by an operator become one load of the
+//     result, a constant load followed by `JumpIf` becomes a `Jump` or disappears
+//   - store/load forwarding: a `Read` of a variable written from a constant earlier in the
+//     same basic block becomes a `Load` of that constant
+//   - removal of jumps to the next instruction and of unreachable instructions
+//
+// Folding never hides a runtime error: a division by zero, an overflowing operation or a type
+// mismatch is left for the vm to report. Programs with a jump outside of the program are returned unchanged.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{jump_target, offset_for_target, Instruction, Value};
+
+const MAX_ROUNDS: usize = 16;
+
+fn jump_offset(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Jump(offset) | Instruction::JumpIf(offset) | Instruction::Call(offset) => Some(*offset),
+        _ => None,
+    }
+}
+
+fn set_jump_offset(instruction: &mut Instruction, offset: usize) {
+    if let Instruction::Jump(slot) | Instruction::JumpIf(slot) | Instruction::Call(slot) = instruction {
+        *slot = offset;
+    }
+}
+
+fn targets(code: &[Instruction]) -> BTreeSet<usize> {
+    code.iter().filter_map(jump_offset).filter_map(|offset| jump_target(code, offset)).collect()
+}
+
+pub fn optimize(code: &[Instruction]) -> Vec<Instruction> {
+    let in_range = code.iter().filter_map(jump_offset).all(|offset| jump_target(code, offset).is_some());
+    if !in_range {
+        return code.to_vec()
+    }
+
+    let mut code = code.to_vec();
+    for _ in 0..MAX_ROUNDS {
+        let before = code.clone();
+        thread_jumps(&mut code);
+        code = rebuild(&code, fold_constants(&code));
+        code = rebuild(&code, remove_nop_jumps(&code));
+        code = rebuild(&code, remove_unreachable(&code));
+        if code == before {
+            break
+        }
+    }
+    code
+}
+
+// Applies per-instruction replacements, `None` deletes. A jump to a deleted instruction moves
+// on to the next surviving one, which is what would have run after it. Instruction 0 is never
+// deleted so every target keeps an encodable offset.
+fn rebuild(code: &[Instruction], replacements: Vec<Option<Instruction>>) -> Vec<Instruction> {
+    debug_assert!(code.is_empty() || replacements[0].is_some());
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for replacement in &replacements {
+        new_index.push(kept);
+        if replacement.is_some() {
+            kept += 1;
+        }
+    }
+    new_index.push(kept);
+
+    let mut rebuilt: Vec<Instruction> = replacements.into_iter().flatten().collect();
+    for instruction in &mut rebuilt {
+        if let Some(offset) = jump_offset(instruction) {
+            let moved = offset_for_target(new_index[offset + 1]).expect("instruction 0 is never deleted");
+            set_jump_offset(instruction, moved);
+        }
+    }
+    rebuilt
+}
+
+fn thread_jumps(code: &mut [Instruction]) {
+    for index in 0..code.len() {
+        let mut offset = match jump_offset(&code[index]) {
+            Some(offset) => offset,
+            None => continue,
+        };
+        let mut seen = BTreeSet::new();
+        while let Some(Instruction::Jump(next)) = code.get(offset + 1) {
+            // A cycle of jumps never gets anywhere, leave it alone.
+            if !seen.insert(offset) {
+                break
+            }
+            offset = *next;
+        }
+        set_jump_offset(&mut code[index], offset);
+    }
+}
+
+// The value a load pushes. Arrays are built at runtime and never constant.
+fn constant(instruction: &Instruction) -> Option<Value> {
+    match instruction {
+        Instruction::Load(value) => Some(Value::Int(*value)),
+        Instruction::LoadBool(value) => Some(Value::Bool(*value)),
+        Instruction::LoadStr(text) => Some(Value::Str(text.clone())),
+        _ => None,
+    }
+}
+
+fn load(value: Value) -> Option<Instruction> {
+    match value {
+        Value::Int(value) => Some(Instruction::Load(value)),
+        Value::Bool(value) => Some(Instruction::LoadBool(value)),
+        Value::Str(text) => Some(Instruction::LoadStr(text)),
+        Value::Array(_) => None,
+    }
+}
+
+fn fold(lhs: Value, rhs: Value, instruction: &Instruction) -> Option<Value> {
+    let compare = |result: bool| if lhs.same_type(&rhs) { Some(Value::Bool(result)) } else { None };
+    let result = match instruction {
+        Instruction::CompareEQ => return compare(lhs == rhs),
+        Instruction::CompareNE => return compare(lhs != rhs),
+        Instruction::CompareGT => return compare(lhs > rhs),
+        Instruction::CompareLT => return compare(lhs < rhs),
+        Instruction::CompareGTE => return compare(lhs >= rhs),
+        Instruction::CompareLTE => return compare(lhs <= rhs),
+        _ => None,
+    };
+    match (lhs, rhs, instruction) {
+        (Value::Int(lhs), Value::Int(rhs), Instruction::Add) => lhs.checked_add(rhs).map(Value::Int),
+        (Value::Int(lhs), Value::Int(rhs), Instruction::Sub) => lhs.checked_sub(rhs).map(Value::Int),
+        (Value::Int(lhs), Value::Int(rhs), Instruction::Mul) => lhs.checked_mul(rhs).map(Value::Int),
+        (Value::Int(lhs), Value::Int(rhs), Instruction::Div) => lhs.checked_div(rhs).map(Value::Int),
+        (Value::Int(lhs), Value::Int(rhs), Instruction::Mod) => lhs.checked_rem(rhs).map(Value::Int),
+        (Value::Int(lhs), Value::Int(rhs), Instruction::And) => Some(Value::Int(lhs & rhs)),
+        (Value::Int(lhs), Value::Int(rhs), Instruction::Or) => Some(Value::Int(lhs | rhs)),
+        (Value::Int(lhs), Value::Int(rhs), Instruction::Xor) => Some(Value::Int(lhs ^ rhs)),
+        (Value::Int(lhs), Value::Int(rhs), Instruction::Shl) => {
+            u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)).map(Value::Int)
+        }
+        (Value::Int(lhs), Value::Int(rhs), Instruction::Shr) => {
+            u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)).map(Value::Int)
+        }
+        (Value::Bool(lhs), Value::Bool(rhs), Instruction::And) => Some(Value::Bool(lhs & rhs)),
+        (Value::Bool(lhs), Value::Bool(rhs), Instruction::Or) => Some(Value::Bool(lhs | rhs)),
+        (Value::Bool(lhs), Value::Bool(rhs), Instruction::Xor) => Some(Value::Bool(lhs ^ rhs)),
+        (Value::Str(lhs), Value::Str(rhs), Instruction::Concat) => Some(Value::Str(lhs + &rhs)),
+        _ => result,
+    }
+}
+
+fn fold_constants(code: &[Instruction]) -> Vec<Option<Instruction>> {
+    let targets = targets(code);
+    let mut replacements: Vec<Option<Instruction>> = code.iter().cloned().map(Some).collect();
+    // Variables holding a known constant since the start of the current block.
+    let mut known: HashMap<&str, Value> = HashMap::new();
+
+    let mut index = 0;
+    while index < code.len() {
+        if targets.contains(&index) {
+            known.clear();
+        }
+        // Patterns spanning several instructions must not have a jump landing in their middle.
+        let straight = |len: usize| index + len <= code.len() && (index + 1..index + len).all(|i| !targets.contains(&i));
+
+        if let Some(lhs) = constant(&code[index]).filter(|_| straight(3)) {
+            let folded = constant(&code[index + 1]).and_then(|rhs| fold(lhs, rhs, &code[index + 2]));
+            if let Some(result) = folded.and_then(load) {
+                replacements[index] = Some(result);
+                replacements[index + 1] = None;
+                replacements[index + 2] = None;
+                // One fold per round keeps the bookkeeping simple, the next round
+                // picks up whatever the folded value enables.
+                index += 3;
+                continue
+            }
+        }
+
+        if let Some(value) = constant(&code[index]).filter(|_| straight(2)) {
+            match &code[index + 1] {
+                // A string condition is left for the vm to reject.
+                Instruction::JumpIf(offset) if index > 0 && !matches!(value, Value::Str(_)) => {
+                    let taken = value == Value::Int(0) || value == Value::Bool(false);
+                    replacements[index] = None;
+                    replacements[index + 1] = if taken { Some(Instruction::Jump(*offset)) } else { None };
+                    known.clear();
+                    index += 2;
+                    continue
+                }
+                Instruction::Write(name) => {
+                    known.insert(name, value);
+                    index += 2;
+                    continue
+                }
+                _ => {}
+            }
+        }
+
+        match &code[index] {
+            Instruction::Write(name) => {
+                known.remove(name.as_str());
+            }
+            Instruction::Read(name) => {
+                if let Some(value) = known.get(name.as_str()) {
+                    replacements[index] = load(value.clone());
+                }
+            }
+            Instruction::Jump(_) | Instruction::JumpIf(_) | Instruction::Ret | Instruction::Return => known.clear(),
+            _ => {}
+        }
+        index += 1;
+    }
+    replacements
+}
+
+fn remove_nop_jumps(code: &[Instruction]) -> Vec<Option<Instruction>> {
+    code.iter().enumerate().map(|(index, instruction)| match instruction {
+        Instruction::Jump(offset) if index > 0 && *offset == index => None,
+        _ => Some(instruction.clone()),
+    }).collect()
+}
+
+fn remove_unreachable(code: &[Instruction]) -> Vec<Option<Instruction>> {
+    let mut reachable = vec![false; code.len()];
+    let mut worklist = vec![0];
+    while let Some(index) = worklist.pop() {
+        if index >= code.len() || reachable[index] {
+            continue
+        }
+        reachable[index] = true;
+        match &code[index] {
+            Instruction::Jump(offset) => worklist.push(offset + 1),
+            Instruction::JumpIf(offset) | Instruction::Call(offset) => {
+                worklist.push(offset + 1);
+                worklist.push(index + 1);
+            }
+            Instruction::Ret | Instruction::Return => {}
+            _ => worklist.push(index + 1),
+        }
+    }
+    code.iter().zip(reachable).map(|(instruction, reachable)| {
+        if reachable { Some(instruction.clone()) } else { None }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzz::{Entropy, Lcg};
+    use crate::{asm, lang, Instruction::*, InterpreterError, Limits, Value, Vm};
+
+    const FUEL: u64 = 10_000;
+
+    fn run(code: Vec<Instruction>) -> (Result<Value, InterpreterError>, u64) {
+        let mut vm = Vm::with_limits(Limits { fuel: Some(FUEL), ..Limits::default() });
+        vm.load_program(code);
+        let result = vm.run().map_err(|err| err.kind);
+        (result, vm.fuel_consumed())
+    }
+
+    // The optimised program must produce the same result without doing more work.
+    fn check(code: &[Instruction]) {
+        let (expected, expected_fuel) = run(code.to_vec());
+        if expected == Err(InterpreterError::OutOfFuel) {
+            return
+        }
+        let optimized = optimize(code);
+        let (found, found_fuel) = run(optimized.clone());
+        assert_eq!(found, expected, "{:?}\noptimized to\n{:?}", code, optimized);
+        assert!(found_fuel <= expected_fuel);
+    }
+
+    #[test]
+    fn folds_constants() {
+        assert_eq!(optimize(&[Load(2), Load(3), Add, Load(4), Mul, Return]), vec![Load(20), Return]);
+        assert_eq!(optimize(&[Load(1), Load(0), Div, Return]), vec![Load(1), Load(0), Div, Return]);
+        assert_eq!(optimize(&[Load(i64::MAX), Load(1), Add, Return]), vec![Load(i64::MAX), Load(1), Add, Return]);
+        assert_eq!(optimize(&[Load(3), Load(4), CompareLT, Return]), vec![LoadBool(true), Return]);
+        assert_eq!(optimize(&[LoadStr("a".into()), LoadStr("b".into()), Concat, Return]), vec![LoadStr("ab".into()), Return]);
+        assert_eq!(optimize(&[Load(-7), Load(3), Mod, Load(6), Xor, Return]), vec![Load(-7), Return]);
+        assert_eq!(optimize(&[Load(1), Load(64), Shl, Return]), vec![Load(1), Load(64), Shl, Return]);
+        let mismatch = vec![LoadBool(true), Load(1), Add, Return];
+        assert_eq!(optimize(&mismatch), mismatch);
+    }
+
+    #[test]
+    fn forwards_stores_and_drops_dead_code() {
+        let code = asm::parse("
+                    load 7
+                    write x
+                    read x
+                    load 1
+                    add
+                    return
+                    load 99
+                    return
+        ").unwrap();
+        assert_eq!(optimize(&code), vec![Load(7), Write("x".into()), Load(8), Return]);
+    }
+
+    #[test]
+    fn threads_jumps() {
+        let code = asm::parse("
+                    read x
+                    jumpif a
+                    load 1
+                    return
+            a:      jump b
+            b:      jump c
+            c:      load 2
+                    return
+        ").unwrap();
+        assert_eq!(optimize(&code), vec![Read("x".into()), JumpIf(3), Load(1), Return, Load(2), Return]);
+    }
+
+    #[test]
+    fn keeps_loops_intact() {
+        let code = vec![Load(0), Write("i".into()), Read("i".into()), Load(3),
+            CompareLT, JumpIf(10), Read("i".into()), Load(1), Add, Write("i".into()),
+            Jump(1), Read("i".into()), Return];
+        let optimized = optimize(&code);
+        assert_eq!(run(optimized).0, Ok(Value::Int(3)));
+        check(&code);
+    }
+
+    #[test]
+    fn leaves_bad_jumps_alone() {
+        let code = vec![Load(1), Load(2), Add, Jump(40), Return];
+        assert_eq!(optimize(&code), code);
+    }
+
+    #[test]
+    fn differential_compiled_programs() {
+        let sources = [
+            "i = 0; while i < 3 { i += 1 } return i",
+            "n = 10; a = 0; b = 1; while n > 0 { t = b; b = a + b; a = t; n -= 1 } return a",
+            "x = 2 * 3 + 4; if x == 10 { return 1 } else if x > 10 { return 2 } return 3",
+            "while 0 { x = 1 } return 4 / 2",
+            "x = 1 / 0",
+            "return y",
+        ];
+        for source in sources {
+            check(&lang::compile(source).unwrap());
+        }
+    }
+
+    fn random_program(rng: &mut impl Entropy) -> Vec<Instruction> {
+        let names = ["a", "b", "c"];
+        // Binding every name up front keeps most reads from failing straight away.
+        let mut code = vec![Load(1), Write("a".into()), Load(2), Write("b".into()), Load(3), Write("c".into())];
+        let len = code.len() + 2 + rng.below(20) as usize;
+        code.extend((code.len()..len).map(|_| match rng.below(23) {
+            0..=3 => Load(rng.below(7) as i64 - 3),
+            4 => Read(names[rng.below(3) as usize].into()),
+            5 => Write(names[rng.below(3) as usize].into()),
+            6 => Jump(rng.below(len as u64 - 1) as usize),
+            7 => JumpIf(rng.below(len as u64 - 1) as usize),
+            8 => Add,
+            9 => Sub,
+            10 => Mul,
+            11 => Div,
+            12 => CompareLT,
+            13 => LoadBool(rng.below(2) == 0),
+            14 => LoadStr(names[rng.below(3) as usize].into()),
+            15 => Concat,
+            16 => Mod,
+            17 => Xor,
+            18 => Shl,
+            19 => Dup,
+            20 => Swap,
+            21 => Pop,
+            _ => CompareEQ,
+        }));
+        code.push(Return);
+        code
+    }
+
+    #[test]
+    fn differential_random_programs() {
+        let mut rng = Lcg(0x5eed);
+        for _ in 0..5000 {
+            check(&random_program(&mut rng));
+        }
+    }
+}
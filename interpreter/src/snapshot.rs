@@ -0,0 +1,442 @@
+// Saving a running program and picking it up again later.
+//
+//     magic "ISN\0" | version u8 | code hash u64 (little endian) | arithmetic u8 |
+//     instruction pointer | fuel used | remaining fuel | stack | vars | slot names | slots |
+//     frames | trail | crc32 (u32 little endian)
+//
+// Numbers are varints as in `binary`, the remaining fuel is 0 for unlimited or 1 + the fuel.
+// Values are a tag byte (0 int, 1 bool, 2 string, 3 array) followed by their contents, unset
+// slots are a 0 byte and set ones a 1 byte and the value. Variables are sorted by name so equal
+// states give equal bytes. Only a hash of the code is stored, a snapshot is restored onto the
+// program it was taken from.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::binary::{crc32, encode, fnv1a, unzigzag, write_varint, zigzag, DecodeError, Reader};
+use crate::error::TRAIL_LEN;
+use crate::{ArithmeticMode, ByteCode, Frame, Instruction, Slots, Transfer, Value};
+
+pub const MAGIC: [u8; 4] = *b"ISN\0";
+pub const VERSION: u8 = 1;
+
+// Arrays nested deeper than this are rejected instead of risking the native stack.
+const MAX_NESTING: usize = 1024;
+
+const INT: u8 = 0;
+const BOOL: u8 = 1;
+const STR: u8 = 2;
+const ARRAY: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    Decode(DecodeError),
+    // The snapshot was taken while running a different program.
+    CodeMismatch { expected: u64, found: u64 },
+    InvalidTag(u8),
+    NestingTooDeep,
+    // An instruction pointer, return address or jump that lies outside the program.
+    OffsetOutOfRange(usize),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Decode(DecodeError::BadMagic) => write!(f, "not an interpreter snapshot"),
+            SnapshotError::Decode(err) => write!(f, "{}", err),
+            SnapshotError::CodeMismatch { expected, found } => {
+                write!(f, "snapshot is of program {:016x}, not {:016x}", expected, found)
+            }
+            SnapshotError::InvalidTag(tag) => write!(f, "invalid tag {:#04x}", tag),
+            SnapshotError::NestingTooDeep => write!(f, "arrays nested too deeply"),
+            SnapshotError::OffsetOutOfRange(offset) => write!(f, "offset {} is outside the program", offset),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<DecodeError> for SnapshotError {
+    fn from(err: DecodeError) -> Self {
+        SnapshotError::Decode(err)
+    }
+}
+
+/// 64-bit FNV-1a of the program's binary encoding, the identity a snapshot is checked against.
+pub fn code_hash(code: &[Instruction]) -> u64 {
+    fnv1a(&encode(code))
+}
+
+fn write_string(out: &mut Vec<u8>, text: &str) {
+    write_varint(out, text.len() as u64);
+    out.extend_from_slice(text.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Int(value) => {
+            out.push(INT);
+            write_varint(out, zigzag(*value));
+        }
+        Value::Bool(value) => {
+            out.push(BOOL);
+            out.push(*value as u8);
+        }
+        Value::Str(text) => {
+            out.push(STR);
+            write_string(out, text);
+        }
+        Value::Array(values) => {
+            out.push(ARRAY);
+            write_varint(out, values.len() as u64);
+            for value in values {
+                write_value(out, value);
+            }
+        }
+    }
+}
+
+fn write_vars(out: &mut Vec<u8>, vars: &HashMap<String, Value>) {
+    let mut sorted: Vec<(&String, &Value)> = vars.iter().collect();
+    sorted.sort_unstable_by_key(|(name, _)| *name);
+    write_varint(out, sorted.len() as u64);
+    for (name, value) in sorted {
+        write_string(out, name);
+        write_value(out, value);
+    }
+}
+
+fn write_slots(out: &mut Vec<u8>, slots: &Slots) {
+    write_varint(out, slots.values.len() as u64);
+    for value in &slots.values {
+        match value {
+            Some(value) => {
+                out.push(1);
+                write_value(out, value);
+            }
+            None => out.push(0),
+        }
+    }
+}
+
+pub(crate) fn save(byte_code: &ByteCode, fuel: Option<u64>, arithmetic: ArithmeticMode) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.extend_from_slice(&code_hash(&byte_code.code).to_le_bytes());
+    out.push(match arithmetic {
+        ArithmeticMode::Checked => 0,
+        ArithmeticMode::Wrapping => 1,
+        ArithmeticMode::Saturating => 2,
+    });
+    write_varint(&mut out, byte_code.instruction_ptr as u64);
+    write_varint(&mut out, byte_code.fuel_used);
+    match fuel {
+        Some(fuel) => write_varint(&mut out, fuel.saturating_sub(byte_code.fuel_used).saturating_add(1)),
+        None => write_varint(&mut out, 0),
+    }
+
+    write_varint(&mut out, byte_code.stack.len() as u64);
+    for value in &byte_code.stack {
+        write_value(&mut out, value);
+    }
+    write_vars(&mut out, &byte_code.vars);
+    write_varint(&mut out, byte_code.slot_names.len() as u64);
+    for name in &byte_code.slot_names {
+        write_string(&mut out, name);
+    }
+    write_slots(&mut out, &byte_code.slots);
+    write_varint(&mut out, byte_code.frames.len() as u64);
+    for frame in &byte_code.frames {
+        write_varint(&mut out, frame.return_ptr as u64);
+        write_vars(&mut out, &frame.vars);
+        write_slots(&mut out, &frame.slots);
+    }
+    write_varint(&mut out, byte_code.trail.len() as u64);
+    for transfer in &byte_code.trail {
+        write_varint(&mut out, transfer.from as u64);
+        write_varint(&mut out, transfer.to as u64);
+    }
+
+    let checksum = crc32(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+fn read_value(reader: &mut Reader, depth: usize) -> Result<Value, SnapshotError> {
+    match reader.byte()? {
+        INT => Ok(Value::Int(unzigzag(reader.varint()?))),
+        BOOL => match reader.byte()? {
+            0 => Ok(Value::Bool(false)),
+            1 => Ok(Value::Bool(true)),
+            byte => Err(DecodeError::InvalidBool(byte).into()),
+        },
+        STR => Ok(Value::Str(reader.string()?)),
+        ARRAY => {
+            if depth >= MAX_NESTING {
+                return Err(SnapshotError::NestingTooDeep)
+            }
+            let count = reader.varint()?;
+            let mut values = Vec::new();
+            for _ in 0..count {
+                values.push(read_value(reader, depth + 1)?);
+            }
+            Ok(Value::Array(values))
+        }
+        tag => Err(SnapshotError::InvalidTag(tag)),
+    }
+}
+
+fn read_vars(reader: &mut Reader) -> Result<HashMap<String, Value>, SnapshotError> {
+    let count = reader.varint()?;
+    let mut vars = HashMap::new();
+    for _ in 0..count {
+        let name = reader.string()?;
+        vars.insert(name, read_value(reader, 0)?);
+    }
+    Ok(vars)
+}
+
+fn read_slots(reader: &mut Reader) -> Result<Slots, SnapshotError> {
+    let count = reader.varint()?;
+    let mut slots = Slots::default();
+    for _ in 0..count {
+        let value = match reader.byte()? {
+            0 => None,
+            1 => Some(read_value(reader, 0)?),
+            tag => return Err(SnapshotError::InvalidTag(tag)),
+        };
+        slots.set += usize::from(value.is_some());
+        slots.values.push(value);
+    }
+    Ok(slots)
+}
+
+pub(crate) struct Restored {
+    pub(crate) byte_code: ByteCode,
+    pub(crate) fuel_remaining: Option<u64>,
+    pub(crate) arithmetic: ArithmeticMode,
+}
+
+pub(crate) fn load(bytes: &[u8], code: Vec<Instruction>) -> Result<Restored, SnapshotError> {
+    if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::BadMagic.into())
+    }
+    if bytes.len() < MAGIC.len() + 1 + 8 + 4 {
+        return Err(DecodeError::UnexpectedEof.into())
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    let expected = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    let found = crc32(body);
+    if expected != found {
+        return Err(DecodeError::ChecksumMismatch { expected, found }.into())
+    }
+
+    let mut reader = Reader { bytes: body, pos: MAGIC.len() };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version).into())
+    }
+    let mut hash = [0; 8];
+    hash.copy_from_slice(reader.take(8)?);
+    let (expected, found) = (u64::from_le_bytes(hash), code_hash(&code));
+    if expected != found {
+        return Err(SnapshotError::CodeMismatch { expected, found })
+    }
+    let arithmetic = match reader.byte()? {
+        0 => ArithmeticMode::Checked,
+        1 => ArithmeticMode::Wrapping,
+        2 => ArithmeticMode::Saturating,
+        tag => return Err(SnapshotError::InvalidTag(tag)),
+    };
+    // Return addresses and jump sources are instructions, the instruction pointer and jump
+    // destinations may also be just past the last one.
+    let check = |offset: usize, past_end: bool| match offset < code.len() + usize::from(past_end) {
+        true => Ok(offset),
+        false => Err(SnapshotError::OffsetOutOfRange(offset)),
+    };
+    let instruction_ptr = check(reader.offset()?, true)?;
+    let fuel_used = reader.varint()?;
+    let fuel_remaining = reader.varint()?.checked_sub(1);
+
+    let mut stack = Vec::new();
+    for _ in 0..reader.varint()? {
+        stack.push(read_value(&mut reader, 0)?);
+    }
+    let vars = read_vars(&mut reader)?;
+    let mut slot_names = Vec::new();
+    for _ in 0..reader.varint()? {
+        slot_names.push(reader.string()?);
+    }
+    let slots = read_slots(&mut reader)?;
+    let mut frames = Vec::new();
+    for _ in 0..reader.varint()? {
+        let return_ptr = check(reader.offset()?, false)?;
+        let vars = read_vars(&mut reader)?;
+        frames.push(Frame { return_ptr, vars, slots: read_slots(&mut reader)? });
+    }
+    let mut trail = VecDeque::new();
+    for _ in 0..reader.varint()? {
+        if trail.len() == TRAIL_LEN {
+            trail.pop_front();
+        }
+        let from = check(reader.offset()?, false)?;
+        trail.push_back(Transfer { from, to: check(reader.offset()?, true)? });
+    }
+    if reader.pos != body.len() {
+        return Err(DecodeError::TrailingBytes.into())
+    }
+
+    let byte_code = ByteCode { code, stack, instruction_ptr, vars, slots, slot_names, frames, fuel_used, trail };
+    Ok(Restored { byte_code, fuel_remaining, arithmetic })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::link;
+    use crate::{asm, lang, InterpreterError, Limits, Vm};
+
+    const FIB: &str = "n = 30; a = 0; b = 1; xs = []; while n > 0 { t = b; b = a + b; a = t; n -= 1; xs = xs ++ [a] } return len(xs) + a";
+
+    fn fuel(fuel: u64) -> Limits {
+        Limits { fuel: Some(fuel), ..Limits::default() }
+    }
+
+    #[test]
+    fn resumes_where_it_stopped() {
+        let code = lang::compile(FIB).unwrap();
+        let expected = crate::interpret_both(code.clone()).unwrap();
+
+        let mut vm = Vm::with_limits(fuel(100));
+        vm.load_program(code.clone());
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::OutOfFuel);
+        let bytes = vm.snapshot();
+
+        let mut fresh = Vm::new();
+        fresh.restore(code.clone(), &bytes).unwrap();
+        assert_eq!((fresh.stack(), fresh.vars(), fresh.instruction_ptr()), (vm.stack(), vm.vars(), vm.instruction_ptr()));
+        assert_eq!(fresh.limits().fuel, Some(100));
+        assert_eq!(fresh.snapshot(), bytes);
+        fresh.set_limits(Limits::default());
+        assert_eq!(fresh.run().unwrap(), expected);
+    }
+
+    #[test]
+    fn time_slices_many_programs() {
+        // Each program gets 25 instructions at a time and is parked as bytes in between.
+        let sources = [FIB, "i = 0; while i < 20 { i += 1 } return i", "return 6 * 7"];
+        let programs: Vec<_> = sources.iter().map(|source| link(&lang::compile(source).unwrap())).collect();
+        let mut parked: Vec<Option<Vec<u8>>> = vec![None; programs.len()];
+        let mut results = vec![None; programs.len()];
+        while results.iter().any(Option::is_none) {
+            for (index, linked) in programs.iter().enumerate() {
+                if results[index].is_some() {
+                    continue
+                }
+                let mut vm = Vm::new();
+                match &parked[index] {
+                    Some(bytes) => vm.restore(linked.code.clone(), bytes).unwrap(),
+                    None => vm.load_linked(linked.clone()),
+                }
+                vm.set_limits(fuel(vm.fuel_consumed() + 25));
+                match vm.run() {
+                    Ok(result) => results[index] = Some(result),
+                    Err(err) if err.kind == InterpreterError::OutOfFuel => parked[index] = Some(vm.snapshot()),
+                    Err(err) => panic!("{}", err),
+                }
+            }
+        }
+        let expected: Vec<_> = sources.iter().map(|source| Some(crate::interpret_both(lang::compile(source).unwrap()).unwrap())).collect();
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn keeps_calls_and_arithmetic() {
+        let code = asm::parse("
+                    load 9223372036854775807
+                    write big
+                    load 5
+                    call fact
+                    read big
+                    add
+                    return
+            fact:   write n
+                    read n
+                    load 1
+                    cmpgt
+                    jumpif base
+                    read n
+                    read n
+                    load 1
+                    sub
+                    call fact
+                    mul
+                    ret
+            base:   load 1
+                    ret
+        ").unwrap();
+        let mut vm = Vm::with_arithmetic(ArithmeticMode::Wrapping);
+        vm.set_limits(fuel(30));
+        vm.load_program(code.clone());
+        assert!(vm.run().is_err());
+        assert!(vm.call_depth() > 1);
+
+        let mut fresh = Vm::new();
+        fresh.restore(code, &vm.snapshot()).unwrap();
+        assert_eq!(fresh.call_depth(), vm.call_depth());
+        assert_eq!(fresh.arithmetic(), ArithmeticMode::Wrapping);
+        fresh.set_limits(Limits::default());
+        assert_eq!(fresh.run().unwrap(), i64::MAX.wrapping_add(120));
+    }
+
+    #[test]
+    fn rejects_other_programs_and_corruption() {
+        let code = lang::compile(FIB).unwrap();
+        let mut vm = Vm::with_limits(fuel(50));
+        vm.load_program(code.clone());
+        assert!(vm.run().is_err());
+        let bytes = vm.snapshot();
+
+        let mut fresh = Vm::new();
+        let other = lang::compile("return 1").unwrap();
+        assert!(matches!(fresh.restore(other, &bytes), Err(SnapshotError::CodeMismatch { .. })));
+        let mut flipped = bytes.clone();
+        flipped[20] ^= 1;
+        assert!(matches!(fresh.restore(code.clone(), &flipped),
+            Err(SnapshotError::Decode(DecodeError::ChecksumMismatch { .. }))));
+        assert_eq!(fresh.restore(code.clone(), b"IBC\0"), Err(SnapshotError::Decode(DecodeError::BadMagic)));
+
+        // A hand made body with a bad value tag.
+        let mut body = MAGIC.to_vec();
+        body.push(VERSION);
+        body.extend_from_slice(&code_hash(&code).to_le_bytes());
+        body.extend_from_slice(&[0, 0, 0, 0, 1, 7]);
+        let checksum = crc32(&body);
+        body.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(fresh.restore(code, &body), Err(SnapshotError::InvalidTag(7)));
+    }
+
+    #[test]
+    fn rejects_offsets_outside_the_program() {
+        let code = asm::parse("load 1\ncall f\nreturn\nf: ret").unwrap();
+        let crafted = |return_ptr: u64| {
+            let mut body = MAGIC.to_vec();
+            body.push(VERSION);
+            body.extend_from_slice(&code_hash(&code).to_le_bytes());
+            // Checked arithmetic, at `ret` with no fuel limit, 1 on the stack, no vars or slots.
+            body.extend_from_slice(&[0, 3, 0, 0, 1, INT, 2, 0, 0, 0]);
+            // One frame.
+            body.push(1);
+            write_varint(&mut body, return_ptr);
+            body.extend_from_slice(&[0, 0, 0]);
+            let checksum = crc32(&body);
+            body.extend_from_slice(&checksum.to_le_bytes());
+            body
+        };
+        let mut vm = Vm::new();
+        vm.restore(code.clone(), &crafted(1)).unwrap();
+        assert_eq!(vm.run(), Ok(Value::Int(1)));
+        assert_eq!(vm.restore(code.clone(), &crafted(u64::MAX)), Err(SnapshotError::OffsetOutOfRange(usize::MAX)));
+        assert_eq!(vm.restore(code.clone(), &crafted(4)), Err(SnapshotError::OffsetOutOfRange(4)));
+    }
+}
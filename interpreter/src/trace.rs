@@ -0,0 +1,268 @@
+// Observing programs while they run.
+//
+// `Vm::run_traced` hands a `TraceEvent` to a `Tracer` after every executed instruction. Plain
+// `Vm::run` never looks at a tracer, so tracing costs nothing unless it is asked for. Two
+// tracers come built in: `JsonLines` writes one JSON object per step and `Profiler` counts
+// executions per offset and opcode and finds the hottest loops.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::asm::mnemonic;
+use crate::json::write_json_string;
+use crate::{Instruction, InterpreterError, Value};
+
+pub struct TraceEvent<'a> {
+    pub offset: usize,
+    pub instruction: &'a Instruction,
+    // Both stacks are empty unless the tracer `wants_stack`.
+    pub stack_before: &'a [Value],
+    pub stack_after: &'a [Value],
+    // Where execution continues. `Return` and failed instructions leave it at `offset`.
+    pub next: usize,
+    pub call_depth: usize,
+    pub error: Option<&'a InterpreterError>,
+}
+
+pub trait Tracer {
+    fn step(&mut self, event: &TraceEvent);
+
+    /// Copying the stack before every instruction is the expensive part of tracing, tracers
+    /// that don't look at it can opt out.
+    fn wants_stack(&self) -> bool {
+        true
+    }
+}
+
+fn write_json_value(out: &mut impl Write, value: &Value) -> io::Result<()> {
+    match value {
+        Value::Int(value) => write!(out, "{}", value),
+        Value::Bool(value) => write!(out, "{}", value),
+        Value::Str(text) => write_json_string(out, text),
+        Value::Array(values) => write_json_values(out, values),
+    }
+}
+
+fn write_json_values(out: &mut impl Write, values: &[Value]) -> io::Result<()> {
+    out.write_all(b"[")?;
+    for (index, value) in values.iter().enumerate() {
+        if index > 0 {
+            out.write_all(b",")?;
+        }
+        write_json_value(out, value)?;
+    }
+    out.write_all(b"]")
+}
+
+/// Writes every step as a line like
+/// `{"offset":2,"instruction":"add","before":[1,2],"after":[3],"next":3,"depth":0}`, with an
+/// `"error"` field on the step that failed.
+pub struct JsonLines<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(out: W) -> Self {
+        JsonLines { out, error: None }
+    }
+
+    /// Flushes and hands back the writer, or the first write error. Tracing stops at the
+    /// first error so the program itself is not affected.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.error {
+            Some(error) => Err(error),
+            None => {
+                self.out.flush()?;
+                Ok(self.out)
+            }
+        }
+    }
+
+    fn write_event(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let out = &mut self.out;
+        write!(out, "{{\"offset\":{},\"instruction\":", event.offset)?;
+        write_json_string(out, &event.instruction.to_string())?;
+        out.write_all(b",\"before\":")?;
+        write_json_values(out, event.stack_before)?;
+        out.write_all(b",\"after\":")?;
+        write_json_values(out, event.stack_after)?;
+        write!(out, ",\"next\":{},\"depth\":{}", event.next, event.call_depth)?;
+        if let Some(error) = event.error {
+            out.write_all(b",\"error\":")?;
+            write_json_string(out, &error.to_string())?;
+        }
+        out.write_all(b"}\n")
+    }
+}
+
+impl<W: Write> Tracer for JsonLines<W> {
+    fn step(&mut self, event: &TraceEvent) {
+        if self.error.is_none() {
+            if let Err(error) = self.write_event(event) {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Profiler {
+    by_offset: Vec<u64>,
+    by_opcode: BTreeMap<&'static str, u64>,
+    // Taken backward jumps keyed by (target, jump offset).
+    back_edges: BTreeMap<(usize, usize), u64>,
+}
+
+/// A loop found through a backward jump: the instructions from `start` to `end` inclusive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotLoop {
+    pub start: usize,
+    pub end: usize,
+    pub iterations: u64,
+    // Instructions executed inside the range, including nested loops.
+    pub executed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub total: u64,
+    pub by_offset: Vec<u64>,
+    pub by_opcode: BTreeMap<&'static str, u64>,
+    // Most executed instructions first.
+    pub hot_loops: Vec<HotLoop>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn finish(self) -> Profile {
+        let by_offset = self.by_offset;
+        let mut hot_loops: Vec<HotLoop> = self.back_edges.into_iter().map(|((start, end), iterations)| {
+            let executed = by_offset[start..=end].iter().sum();
+            HotLoop { start, end, iterations, executed }
+        }).collect();
+        hot_loops.sort_by(|a, b| b.executed.cmp(&a.executed).then(a.start.cmp(&b.start)));
+        Profile { total: by_offset.iter().sum(), by_offset, by_opcode: self.by_opcode, hot_loops }
+    }
+}
+
+impl Tracer for Profiler {
+    fn step(&mut self, event: &TraceEvent) {
+        if self.by_offset.len() <= event.offset {
+            self.by_offset.resize(event.offset + 1, 0);
+        }
+        self.by_offset[event.offset] += 1;
+        *self.by_opcode.entry(mnemonic(event.instruction)).or_insert(0) += 1;
+        let jumped = matches!(event.instruction, Instruction::Jump(_) | Instruction::JumpIf(_));
+        if jumped && event.error.is_none() && event.next <= event.offset {
+            *self.back_edges.entry((event.next, event.offset)).or_insert(0) += 1;
+        }
+    }
+
+    fn wants_stack(&self) -> bool {
+        false
+    }
+}
+
+impl Profile {
+    /// The `count` most executed offsets with their execution counts, hottest first.
+    pub fn hottest(&self, count: usize) -> Vec<(usize, u64)> {
+        let mut offsets: Vec<(usize, u64)> = self.by_offset.iter().copied().enumerate()
+            .filter(|(_, executed)| *executed > 0)
+            .collect();
+        offsets.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        offsets.truncate(count);
+        offsets
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} instructions executed", self.total)?;
+        let mut opcodes: Vec<(&str, u64)> = self.by_opcode.iter().map(|(name, count)| (*name, *count)).collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        writeln!(f, "\nby opcode:")?;
+        for (name, count) in opcodes {
+            writeln!(f, "  {:<10}{:>10}", name, count)?;
+        }
+        if !self.hot_loops.is_empty() {
+            writeln!(f, "\nhot loops:")?;
+            for hot in &self.hot_loops {
+                writeln!(f, "  {:>5}..={:<5} {:>10} iterations {:>10} instructions",
+                    hot.start, hot.end, hot.iterations, hot.executed)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, lang, Vm};
+
+    fn vm(source: &str) -> Vm {
+        let mut vm = Vm::new();
+        vm.load_program(lang::compile(source).unwrap());
+        vm
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut vm = Vm::new();
+        vm.load_program(asm::parse("load 1\nload \"a\\\"\"\nnewarray 2\nload 1\nadd\nreturn").unwrap());
+        let mut sink = JsonLines::new(Vec::new());
+        assert!(vm.run_traced(&mut sink).is_err());
+        let out = String::from_utf8(sink.finish().unwrap()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], r#"{"offset":0,"instruction":"load 1","before":[],"after":[1],"next":1,"depth":0}"#);
+        assert_eq!(lines[2], r#"{"offset":2,"instruction":"newarray 2","before":[1,"a\""],"after":[[1,"a\""]],"next":3,"depth":0}"#);
+        assert_eq!(lines[4], r#"{"offset":4,"instruction":"add","before":[[1,"a\""],1],"after":[[1,"a\""],1],"next":4,"depth":0,"error":"type mismatch"}"#);
+    }
+
+    #[test]
+    fn tracing_does_not_change_results() {
+        let source = "n = 10; a = 0; b = 1; while n > 0 { t = b; b = a + b; a = t; n -= 1 } return a";
+        let mut plain = vm(source);
+        let mut traced = vm(source);
+        let mut profiler = Profiler::new();
+        assert_eq!(traced.run_traced(&mut profiler), plain.run());
+        assert_eq!(profiler.finish().total, plain.fuel_consumed());
+    }
+
+    #[test]
+    fn profiles_loops() {
+        let mut vm = vm("
+            i = 0
+            total = 0
+            while i < 10 {
+                j = 0
+                while j < i { total += j; j += 1 }
+                i += 1
+            }
+            return total
+        ");
+        let mut profiler = Profiler::new();
+        assert_eq!(vm.run_traced(&mut profiler).unwrap(), 120);
+        let profile = profiler.finish();
+        assert_eq!(profile.total, vm.fuel_consumed());
+        assert_eq!(profile.by_opcode["write"], 2 + 10 + 45 * 2 + 10);
+
+        // The inner loop is the hottest, the outer one contains it.
+        assert_eq!(profile.hot_loops.len(), 2);
+        let (inner, outer) = (&profile.hot_loops[1], &profile.hot_loops[0]);
+        assert_eq!((inner.iterations, outer.iterations), (45, 10));
+        assert!(outer.start < inner.start && inner.end < outer.end);
+        assert!(outer.executed > inner.executed);
+
+        let (hottest, count) = profile.hottest(1)[0];
+        assert!(hottest >= inner.start && hottest <= inner.end);
+        assert_eq!(count, 55);
+        assert!(profile.to_string().contains("hot loops:"));
+    }
+}
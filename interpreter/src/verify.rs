@@ -0,0 +1,405 @@
+// Static checks run over a program before it is handed to the vm.
+//
+// Every entry point (offset 0 and each `Call` target) is analysed on its own, tracking the
+// stack depth relative to the entry and the variables written on every path so far. Calls are
+// folded in through a per-routine summary: how deep it reaches into the caller's stack, how it
+// changes the depth when it comes back with `Ret`, and whether it can reach `Return`. The
+// summaries are recomputed until they settle so recursive routines are covered too.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::{Instruction, Offset};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    // Programs with warnings still run, e.g. a read of a variable bound by an earlier program.
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiagnosticKind {
+    EmptyProgram,
+    JumpOutOfRange(Offset),
+    FallsOffEnd,
+    NoReachableReturn,
+    StackUnderflow,
+    InconsistentStackDepth { expected: i64, found: i64 },
+    RetOutsideCall,
+    UnwrittenVariable(String),
+    // The same for a `ReadSlot` in a linked program.
+    UnwrittenSlot(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+    pub offset: usize,
+    pub kind: DiagnosticKind,
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        match self.kind {
+            DiagnosticKind::UnwrittenVariable(_) | DiagnosticKind::UnwrittenSlot(_) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity() {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "offset {}: {}: ", self.offset, severity)?;
+        match &self.kind {
+            DiagnosticKind::EmptyProgram => write!(f, "program has no instructions"),
+            DiagnosticKind::JumpOutOfRange(offset) => {
+                write!(f, "offset {} does not resume at an instruction", offset)
+            }
+            DiagnosticKind::FallsOffEnd => write!(f, "execution can run past the last instruction"),
+            DiagnosticKind::NoReachableReturn => write!(f, "no `Return` is reachable"),
+            DiagnosticKind::StackUnderflow => write!(f, "stack can be empty here"),
+            DiagnosticKind::InconsistentStackDepth { expected, found } => {
+                write!(f, "reached with stack depth {} and {}", expected, found)
+            }
+            DiagnosticKind::RetOutsideCall => write!(f, "`Ret` can execute outside of any call"),
+            DiagnosticKind::UnwrittenVariable(name) => {
+                write!(f, "`{}` may be read before it is written", name)
+            }
+            DiagnosticKind::UnwrittenSlot(slot) => write!(f, "slot {} may be read before it is written", slot),
+        }
+    }
+}
+
+// What a caller needs to know about a routine entered through `Call`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Summary {
+    // Values the routine pops from below its entry depth.
+    needs: i64,
+    // Depth change between entry and `Ret`, `None` if it never comes back.
+    effect: Option<i64>,
+    halts: bool,
+}
+
+// A variable by name, or linked to a slot.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Var {
+    Name(String),
+    Slot(usize),
+}
+
+#[derive(Clone)]
+struct State {
+    depth: i64,
+    written: BTreeSet<Var>,
+}
+
+// Stack values popped and pushed by instructions without control flow effects.
+pub(crate) fn stack_effect(instruction: &Instruction) -> (i64, i64) {
+    match instruction {
+        Instruction::Load(_) | Instruction::LoadBool(_) | Instruction::LoadStr(_)
+            | Instruction::Read(_) | Instruction::ReadSlot(_) => (0, 1),
+        Instruction::Write(_) | Instruction::WriteSlot(_) | Instruction::JumpIf(_) | Instruction::Return => (1, 0),
+        Instruction::Jump(_) | Instruction::Call(_) | Instruction::Ret => (0, 0),
+        Instruction::CompareEQ | Instruction::CompareNE | Instruction::CompareGT
+            | Instruction::CompareLT | Instruction::CompareLTE | Instruction::CompareGTE
+            | Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div
+            | Instruction::Mod | Instruction::And | Instruction::Or | Instruction::Xor
+            | Instruction::Shl | Instruction::Shr | Instruction::Concat | Instruction::Index => (2, 1),
+        Instruction::Neg | Instruction::Not | Instruction::Len => (1, 1),
+        Instruction::Dup => (1, 2),
+        Instruction::Swap => (2, 2),
+        Instruction::Pop => (1, 0),
+        Instruction::CallHost(_, argc) => (i64::try_from(*argc).unwrap_or(i64::MAX), 1),
+        Instruction::NewArray(len) => (i64::try_from(*len).unwrap_or(i64::MAX), 1),
+    }
+}
+
+struct Analysis<'a> {
+    code: &'a [Instruction],
+    summaries: &'a BTreeMap<usize, Summary>,
+    is_main: bool,
+    states: BTreeMap<usize, State>,
+    diagnostics: BTreeSet<Diagnostic>,
+    lowest: i64,
+    effect: Option<i64>,
+    halts: bool,
+}
+
+impl<'a> Analysis<'a> {
+    fn run(code: &'a [Instruction], summaries: &'a BTreeMap<usize, Summary>, entry: usize) -> Self {
+        let mut analysis = Analysis {
+            code,
+            summaries,
+            is_main: entry == 0,
+            states: BTreeMap::new(),
+            diagnostics: BTreeSet::new(),
+            lowest: 0,
+            effect: None,
+            halts: false,
+        };
+        analysis.states.insert(entry, State { depth: 0, written: BTreeSet::new() });
+        let mut worklist = vec![entry];
+        while let Some(offset) = worklist.pop() {
+            let state = analysis.states[&offset].clone();
+            for (successor, state) in analysis.transfer(offset, state) {
+                if analysis.merge(offset, successor, state) {
+                    worklist.push(successor);
+                }
+            }
+        }
+        analysis.check_reads();
+        analysis
+    }
+
+    fn report(&mut self, offset: usize, kind: DiagnosticKind) {
+        self.diagnostics.insert(Diagnostic { offset, kind });
+    }
+
+    // Returns whether the state at `successor` changed and needs another visit.
+    fn merge(&mut self, from: usize, successor: usize, state: State) -> bool {
+        if successor >= self.code.len() {
+            self.report(from, DiagnosticKind::FallsOffEnd);
+            return false
+        }
+        let existing = match self.states.get_mut(&successor) {
+            Some(existing) => existing,
+            None => {
+                self.states.insert(successor, state);
+                return true
+            }
+        };
+        if existing.depth != state.depth {
+            let kind = DiagnosticKind::InconsistentStackDepth { expected: existing.depth, found: state.depth };
+            self.diagnostics.insert(Diagnostic { offset: successor, kind });
+        }
+        let before = existing.written.len();
+        existing.written.retain(|name| state.written.contains(name));
+        existing.written.len() != before
+    }
+
+    fn transfer(&mut self, offset: usize, mut state: State) -> Vec<(usize, State)> {
+        let instruction = &self.code[offset];
+        let (pops, pushes) = stack_effect(instruction);
+        // Saturating, `NewArray` and `CallHost` counts go up to `i64::MAX` and depths inside a
+        // routine can already be negative.
+        self.lowest = self.lowest.min(state.depth.saturating_sub(pops));
+        if self.is_main && state.depth < pops {
+            self.report(offset, DiagnosticKind::StackUnderflow);
+            // Carry on as if the values had been there to avoid a cascade of reports.
+            state.depth = pops;
+        }
+        state.depth = state.depth.saturating_sub(pops).saturating_add(pushes);
+
+        let in_range = |target: Offset| target < self.code.len() - 1;
+        match instruction {
+            Instruction::Write(name) => {
+                state.written.insert(Var::Name(name.clone()));
+                vec![(offset + 1, state)]
+            }
+            Instruction::WriteSlot(slot) => {
+                state.written.insert(Var::Slot(*slot));
+                vec![(offset + 1, state)]
+            }
+            Instruction::Jump(target) => {
+                if in_range(*target) { vec![(target + 1, state)] } else { Vec::new() }
+            }
+            Instruction::JumpIf(target) => {
+                let mut successors = vec![(offset + 1, state.clone())];
+                if in_range(*target) {
+                    successors.push((target + 1, state));
+                }
+                successors
+            }
+            Instruction::Call(target) => {
+                let summary = match self.summaries.get(&target.wrapping_add(1)) {
+                    Some(summary) if in_range(*target) => *summary,
+                    _ => return Vec::new(),
+                };
+                self.lowest = self.lowest.min(state.depth.saturating_sub(summary.needs));
+                if self.is_main && state.depth < summary.needs {
+                    self.report(offset, DiagnosticKind::StackUnderflow);
+                    state.depth = summary.needs;
+                }
+                self.halts |= summary.halts;
+                match summary.effect {
+                    Some(effect) => {
+                        state.depth = state.depth.saturating_add(effect);
+                        vec![(offset + 1, state)]
+                    }
+                    None => Vec::new(),
+                }
+            }
+            Instruction::Ret => {
+                if self.is_main {
+                    self.report(offset, DiagnosticKind::RetOutsideCall);
+                }
+                match self.effect {
+                    Some(expected) if expected != state.depth => {
+                        let kind = DiagnosticKind::InconsistentStackDepth { expected, found: state.depth };
+                        self.report(offset, kind);
+                    }
+                    Some(_) => {}
+                    None => self.effect = Some(state.depth),
+                }
+                Vec::new()
+            }
+            Instruction::Return => {
+                self.halts = true;
+                Vec::new()
+            }
+            _ => vec![(offset + 1, state)],
+        }
+    }
+
+    // Runs once the written sets have settled so every read is judged on all incoming paths.
+    fn check_reads(&mut self) {
+        let mut unwritten = Vec::new();
+        for (offset, state) in &self.states {
+            let (var, kind) = match &self.code[*offset] {
+                Instruction::Read(name) => (Var::Name(name.clone()), DiagnosticKind::UnwrittenVariable(name.clone())),
+                Instruction::ReadSlot(slot) => (Var::Slot(*slot), DiagnosticKind::UnwrittenSlot(*slot)),
+                _ => continue,
+            };
+            if !state.written.contains(&var) {
+                unwritten.push((*offset, kind));
+            }
+        }
+        for (offset, kind) in unwritten {
+            self.report(offset, kind);
+        }
+    }
+
+    fn summary(&self) -> Summary {
+        Summary { needs: self.lowest.saturating_neg(), effect: self.effect, halts: self.halts }
+    }
+}
+
+/// Checks jump targets, that a `Return` is reachable and that the stack can't run empty on
+/// any path, returning every problem found ordered by offset.
+pub fn verify(code: &[Instruction]) -> Vec<Diagnostic> {
+    if code.is_empty() {
+        return vec![Diagnostic { offset: 0, kind: DiagnosticKind::EmptyProgram }]
+    }
+
+    let mut diagnostics = BTreeSet::new();
+    let mut entries = BTreeSet::new();
+    entries.insert(0);
+    for (offset, instruction) in code.iter().enumerate() {
+        if let Instruction::Jump(target) | Instruction::JumpIf(target) | Instruction::Call(target) = instruction {
+            if *target >= code.len() - 1 {
+                diagnostics.insert(Diagnostic { offset, kind: DiagnosticKind::JumpOutOfRange(*target) });
+            } else if let Instruction::Call(_) = instruction {
+                entries.insert(target + 1);
+            }
+        }
+    }
+
+    // Routines start out unknown, calls into them are dead ends until their summary settles.
+    let mut summaries = BTreeMap::new();
+    for _ in 0..=entries.len() * 2 {
+        let mut next = BTreeMap::new();
+        for &entry in entries.iter().filter(|entry| **entry != 0) {
+            next.insert(entry, Analysis::run(code, &summaries, entry).summary());
+        }
+        if next == summaries {
+            break
+        }
+        summaries = next;
+    }
+
+    for &entry in &entries {
+        let analysis = Analysis::run(code, &summaries, entry);
+        if entry == 0 && !analysis.halts {
+            diagnostics.insert(Diagnostic { offset: 0, kind: DiagnosticKind::NoReachableReturn });
+        }
+        diagnostics.extend(analysis.diagnostics);
+    }
+    diagnostics.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, Instruction::*, Vm};
+
+    fn kinds(code: &[Instruction]) -> Vec<(usize, DiagnosticKind)> {
+        verify(code).into_iter().map(|diagnostic| (diagnostic.offset, diagnostic.kind)).collect()
+    }
+
+    #[test]
+    fn accepts_well_formed_programs() {
+        assert!(verify(&[Load(0), Write("i".into()), Read("i".into()), Load(3),
+            CompareLT, JumpIf(10), Read("i".into()), Load(1), Add, Write("i".into()),
+            Jump(1), Read("i".into()), Return]).is_empty());
+        assert!(verify(&asm::parse("
+                    load 5
+                    call fact
+                    return
+            fact:   write n
+                    read n
+                    load 1
+                    cmpgt
+                    jumpif base
+                    read n
+                    read n
+                    load 1
+                    sub
+                    call fact
+                    mul
+                    ret
+            base:   load 1
+                    ret
+        ").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_control_flow() {
+        assert_eq!(kinds(&[]), vec![(0, DiagnosticKind::EmptyProgram)]);
+        assert_eq!(kinds(&[Load(1), JumpIf(9), Load(2), Return]),
+            vec![(1, DiagnosticKind::JumpOutOfRange(9))]);
+        assert_eq!(kinds(&[Load(1), Call(usize::MAX), Return]),
+            vec![(0, DiagnosticKind::NoReachableReturn), (1, DiagnosticKind::JumpOutOfRange(usize::MAX))]);
+        assert_eq!(kinds(&[Load(1), Load(2), Add]),
+            vec![(0, DiagnosticKind::NoReachableReturn), (2, DiagnosticKind::FallsOffEnd)]);
+        assert_eq!(kinds(&[Load(1), Jump(0)]), vec![(0, DiagnosticKind::NoReachableReturn)]);
+        assert_eq!(kinds(&[Load(1), Ret, Return]), vec![(0, DiagnosticKind::NoReachableReturn),
+            (1, DiagnosticKind::RetOutsideCall)]);
+    }
+
+    #[test]
+    fn tracks_stack_depth() {
+        assert_eq!(kinds(&[Load(1), Add, Return]), vec![(1, DiagnosticKind::StackUnderflow)]);
+        assert_eq!(kinds(&[Return]), vec![(0, DiagnosticKind::StackUnderflow)]);
+        // One branch pushes an extra value before the paths meet again.
+        assert_eq!(kinds(&[Load(0), Load(1), JumpIf(3), Load(2), Return]),
+            vec![(4, DiagnosticKind::InconsistentStackDepth { expected: 1, found: 2 })]);
+        // The routine pops two arguments but the caller only pushed one.
+        let code = asm::parse("load 1\ncall sum\nreturn\nsum: add\nret").unwrap();
+        assert_eq!(kinds(&code), vec![(1, DiagnosticKind::StackUnderflow)]);
+        // Huge pop counts inside a routine whose depth is already negative.
+        let code = [Load(0), Call(2), Return, Pop, Pop, NewArray(usize::MAX), Ret];
+        assert_eq!(kinds(&code), vec![(1, DiagnosticKind::StackUnderflow), (2, DiagnosticKind::StackUnderflow)]);
+        assert!(Vm::new().load_verified(code.to_vec()).is_err());
+    }
+
+    #[test]
+    fn warns_about_unwritten_reads() {
+        let code = [Load(1), JumpIf(3), Load(5), Write("x".into()), Read("x".into()), Return];
+        let diagnostics = verify(&code);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::UnwrittenVariable("x".into()));
+        assert_eq!(diagnostics[0].severity(), Severity::Warning);
+        assert_eq!(diagnostics[0].to_string(), "offset 4: warning: `x` may be read before it is written");
+
+        // Linking keeps the warning, now about the slot.
+        let linked = crate::link::link(&code);
+        let diagnostics = verify(&linked.code);
+        assert_eq!(diagnostics.iter().map(|diagnostic| &diagnostic.kind).collect::<Vec<_>>(), vec![&DiagnosticKind::UnwrittenSlot(0)]);
+        assert_eq!(diagnostics[0].to_string(), "offset 4: warning: slot 0 may be read before it is written");
+        assert_eq!(verify(&crate::link::link(&[Load(1), Write("x".into()), Read("x".into()), Return]).code), vec![]);
+    }
+}
@@ -0,0 +1,1013 @@
+mod threaded;
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::verify::{verify, Diagnostic, Severity};
+use crate::error::{STACK_SNAPSHOT, TRAIL_LEN};
+use crate::trace::{TraceEvent, Tracer};
+use crate::link::Linked;
+use crate::snapshot::{self, SnapshotError};
+use crate::{ByteCode, Frame, HostRegistry, Instruction, InterpreterError, RuntimeError, Slots, Transfer, Value};
+
+/// Default nesting depth of `Call` after which `InterpreterError::CallStackOverflow` is raised.
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+/// Resource limits enforced while a program runs. `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    // Instructions that may execute between `load_program`/`reset` and the end of the run.
+    pub fuel: Option<u64>,
+    pub max_stack: Option<usize>,
+    // Variables per frame.
+    pub max_vars: Option<usize>,
+    pub max_call_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: None,
+            max_stack: None,
+            max_vars: None,
+            max_call_depth: MAX_CALL_DEPTH,
+        }
+    }
+}
+
+/// What `Add`, `Sub`, `Mul` and `Div` do when the result doesn't fit in an `i64`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ArithmeticMode {
+    // Fail with `InterpreterError::Overflow`.
+    #[default]
+    Checked,
+    Wrapping,
+    Saturating,
+}
+
+/// How `Vm::run` executes a program. `step`, `run_traced` and the debugger always go one
+/// instruction at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Engine {
+    // Every instruction through `Vm::step`.
+    #[default]
+    Basic,
+    // Decodes the program once, fusing common instruction sequences into single ops. Faster on
+    // loops, with the same results, errors and fuel use as `Basic`.
+    Threaded,
+}
+
+macro_rules! handleDiv {
+    {$byte_code:expr, $mode:expr, $checked:ident, $wrapping:ident, $saturating:ident} => {
+    // A zero divisor only counts once both operands are there and are ints, so a missing or
+    // mistyped operand is reported like for any other binary instruction.
+    match top_pair(&$byte_code.stack) {
+        Ok((Value::Int(_), Value::Int(0))) => {
+            return Err(InterpreterError::DivideByZero)
+        },
+        // `i64::MIN / -1` is the only quotient that overflows.
+        _ => handleMath!{$byte_code, $mode, $checked, $wrapping, $saturating}
+    }
+}}
+
+// Operands are only popped once the instruction can't fail any more, so an error leaves the
+// stack as it was for the report.
+macro_rules! handleMath {
+    {$byte_code:expr, $mode:expr, $checked:ident, $wrapping:ident, $saturating:ident} => {
+        match top_pair(&$byte_code.stack) {
+            Ok((Value::Int(lhs), Value::Int(rhs))) => {
+                let result = match $mode {
+                    ArithmeticMode::Checked => lhs.$checked(*rhs),
+                    ArithmeticMode::Wrapping => Some(lhs.$wrapping(*rhs)),
+                    ArithmeticMode::Saturating => Some(lhs.$saturating(*rhs)),
+                };
+                match result {
+                    Some(result) => {
+                        replace_top(&mut $byte_code.stack, 2, Value::Int(result));
+                        Ok(())
+                    },
+                    _ => Err(InterpreterError::Overflow),
+                }
+            },
+            Ok(_) => Err(InterpreterError::TypeMismatch),
+            Err(error) => Err(error),
+        }
+}}
+
+// Bitwise on two ints, logical on two bools.
+macro_rules! handleBitwise {
+    {$byte_code:expr, $operator:tt} => {{
+        let result = match top_pair(&$byte_code.stack) {
+            Ok((Value::Int(lhs), Value::Int(rhs))) => Ok(Value::Int(lhs $operator rhs)),
+            Ok((Value::Bool(lhs), Value::Bool(rhs))) => Ok(Value::Bool(lhs $operator rhs)),
+            Ok(_) => Err(InterpreterError::TypeMismatch),
+            Err(error) => Err(error),
+        };
+        result.map(|result| replace_top(&mut $byte_code.stack, 2, result))
+}}}
+
+// The shift amount must be in `0..64`. `Shr` is arithmetic and keeps the sign.
+macro_rules! handleShift {
+    {$byte_code:expr, $checked:ident} => {
+        match top_pair(&$byte_code.stack) {
+            Ok((Value::Int(lhs), Value::Int(rhs))) => {
+                match u32::try_from(*rhs).ok().and_then(|rhs| lhs.$checked(rhs)) {
+                    Some(result) => {
+                        replace_top(&mut $byte_code.stack, 2, Value::Int(result));
+                        Ok(())
+                    },
+                    _ => Err(InterpreterError::ShiftOutOfRange),
+                }
+            },
+            Ok(_) => Err(InterpreterError::TypeMismatch),
+            Err(error) => Err(error),
+        }
+}}
+
+// Both operands must have the same type. Arrays compare element by element.
+macro_rules! handleCompare {
+    {$byte_code:expr, $operator:tt} => {
+        match top_pair(&$byte_code.stack) {
+            Ok((lhs, rhs)) => {
+                if !lhs.same_type(rhs) {
+                    return Err(InterpreterError::TypeMismatch)
+                }
+                let result = Value::Bool(lhs $operator rhs);
+                replace_top(&mut $byte_code.stack, 2, result);
+                Ok(())
+            },
+            Err(error) => Err(error),
+        }
+}}
+
+// The left and right hand operands, which are the two topmost values.
+fn top_pair(stack: &[Value]) -> Result<(&Value, &Value), InterpreterError> {
+    match stack {
+        [.., lhs, rhs] => Ok((lhs, rhs)),
+        _ => Err(InterpreterError::StackEmpty),
+    }
+}
+
+// Remembers a transfer of control to the instruction after `offset`.
+fn record(trail: &mut VecDeque<Transfer>, from: usize, offset: usize) {
+    if trail.len() == TRAIL_LEN {
+        trail.pop_front();
+    }
+    trail.push_back(Transfer { from, to: offset.saturating_add(1) });
+}
+
+// Hands the outermost slots back to `vars` under their names.
+fn store_slots(byte_code: &mut ByteCode) {
+    let slots = std::mem::take(&mut byte_code.slots);
+    for (name, value) in byte_code.slot_names.iter().zip(slots.values) {
+        if let Some(value) = value {
+            byte_code.vars.insert(name.clone(), value);
+        }
+    }
+}
+
+fn replace_top(stack: &mut Vec<Value>, count: usize, value: Value) {
+    stack.truncate(stack.len() - count);
+    stack.push(value);
+}
+
+/// Outcome of executing a single instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Continue,
+    Return(Value),
+}
+
+/// A reusable interpreter. Variables survive `load_program` so several programs can share
+/// bindings, `reset` clears everything but the loaded program.
+pub struct Vm {
+    byte_code: ByteCode,
+    limits: Limits,
+    arithmetic: ArithmeticMode,
+    engine: Engine,
+    // `Engine::Threaded`'s decoding of the loaded program, made on the first `run`.
+    ops: Option<Vec<threaded::Op>>,
+    host: HostRegistry,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
+        Vm {
+            byte_code: ByteCode {
+                code: Vec::new(),
+                stack: Vec::new(),
+                instruction_ptr: 0,
+                vars: HashMap::new(),
+                slots: Slots::default(),
+                slot_names: Vec::new(),
+                frames: Vec::new(),
+                fuel_used: 0,
+                trail: VecDeque::new(),
+            },
+            limits,
+            arithmetic: ArithmeticMode::default(),
+            engine: Engine::default(),
+            ops: None,
+            host: HostRegistry::new(),
+        }
+    }
+
+    pub fn with_arithmetic(arithmetic: ArithmeticMode) -> Self {
+        let mut vm = Self::new();
+        vm.arithmetic = arithmetic;
+        vm
+    }
+
+    pub fn with_engine(engine: Engine) -> Self {
+        let mut vm = Self::new();
+        vm.engine = engine;
+        vm
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    pub fn arithmetic(&self) -> ArithmeticMode {
+        self.arithmetic
+    }
+
+    pub fn set_arithmetic(&mut self, arithmetic: ArithmeticMode) {
+        self.arithmetic = arithmetic;
+    }
+
+    /// Functions reachable through `CallHost`. They survive `load_program` and `reset`.
+    pub fn host(&self) -> &HostRegistry {
+        &self.host
+    }
+
+    pub fn host_mut(&mut self) -> &mut HostRegistry {
+        &mut self.host
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Takes effect from the next instruction, so raising the fuel after `OutOfFuel` resumes
+    /// the program where it stopped.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Instructions executed since the last `load_program` or `reset`.
+    pub fn fuel_consumed(&self) -> u64 {
+        self.byte_code.fuel_used
+    }
+
+    /// Replaces the program and rewinds to its first instruction, keeping `vars`.
+    pub fn load_program(&mut self, code: Vec<Instruction>) {
+        store_slots(&mut self.byte_code);
+        self.byte_code.slot_names.clear();
+        self.byte_code.code = code;
+        self.ops = None;
+        self.byte_code.stack.clear();
+        self.byte_code.frames.clear();
+        self.byte_code.instruction_ptr = 0;
+        self.byte_code.fuel_used = 0;
+        self.byte_code.trail.clear();
+    }
+
+    /// Loads a program produced by `link::link`. While it runs, `vars` lacks the variables it
+    /// links, they are handed back when it returns or the next program is loaded.
+    pub fn load_linked(&mut self, linked: Linked) {
+        self.load_program(linked.code);
+        let byte_code = &mut self.byte_code;
+        byte_code.slots.values = linked.names.iter().map(|name| byte_code.vars.remove(name)).collect();
+        byte_code.slots.set = byte_code.slots.values.iter().filter(|value| value.is_some()).count();
+        byte_code.slot_names = linked.names;
+    }
+
+    /// Appends `code` to the loaded program and continues at its first instruction, keeping the
+    /// stack and variables but abandoning unfinished calls. Offsets in `code` are relative to
+    /// its own start. This is how an interactive session grows a program entry by entry.
+    pub fn extend_program(&mut self, code: Vec<Instruction>) {
+        let base = self.byte_code.code.len();
+        self.byte_code.code.extend(code.into_iter().map(|mut instruction| {
+            if let Instruction::Jump(offset) | Instruction::JumpIf(offset) | Instruction::Call(offset) = &mut instruction {
+                *offset = offset.saturating_add(base);
+            }
+            instruction
+        }));
+        self.ops = None;
+        self.byte_code.frames.clear();
+        self.byte_code.instruction_ptr = base;
+    }
+
+    /// Saves everything needed to continue later with `restore`: stack, variables, calls,
+    /// instruction pointer, arithmetic mode and remaining fuel. Host functions are not included.
+    pub fn snapshot(&self) -> Vec<u8> {
+        snapshot::save(&self.byte_code, self.limits.fuel, self.arithmetic)
+    }
+
+    /// Replaces the state with one `snapshot` saved while running `code`. The fuel limit is set
+    /// so the same amount remains, other limits and host functions stay as they are.
+    pub fn restore(&mut self, code: Vec<Instruction>, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let restored = snapshot::load(bytes, code)?;
+        self.limits.fuel = restored.fuel_remaining
+            .map(|remaining| restored.byte_code.fuel_used.saturating_add(remaining));
+        self.arithmetic = restored.arithmetic;
+        self.byte_code = restored.byte_code;
+        self.ops = None;
+        Ok(())
+    }
+
+    /// Runs the verifier first and only loads programs without errors. Warnings are handed
+    /// back on success.
+    pub fn load_verified(&mut self, code: Vec<Instruction>) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+        let diagnostics = verify(&code);
+        if diagnostics.iter().any(|diagnostic| diagnostic.severity() == Severity::Error) {
+            return Err(diagnostics)
+        }
+        self.load_program(code);
+        Ok(diagnostics)
+    }
+
+    /// Rewinds the loaded program and forgets the stack and all variables.
+    pub fn reset(&mut self) {
+        self.byte_code.stack.clear();
+        self.byte_code.frames.clear();
+        self.byte_code.instruction_ptr = 0;
+        self.byte_code.fuel_used = 0;
+        self.byte_code.trail.clear();
+        self.byte_code.vars.clear();
+        self.byte_code.slots = Slots::default();
+    }
+
+    pub fn code(&self) -> &[Instruction] {
+        &self.byte_code.code
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.byte_code.stack
+    }
+
+    /// Variables of the outermost frame.
+    pub fn vars(&self) -> &HashMap<String, Value> {
+        &self.byte_code.vars
+    }
+
+    /// Variables visible to the current instruction: those of the innermost call, or `vars`
+    /// outside of any call. Slots of a linked program are listed under their names.
+    pub fn locals(&self) -> HashMap<String, Value> {
+        let (vars, slots) = self.frame_vars();
+        let mut locals = vars.clone();
+        for (name, value) in self.byte_code.slot_names.iter().zip(&slots.values) {
+            if let Some(value) = value {
+                locals.insert(name.clone(), value.clone());
+            }
+        }
+        locals
+    }
+
+    /// One of `locals` without collecting the others.
+    pub fn local(&self, name: &str) -> Option<&Value> {
+        let (vars, slots) = self.frame_vars();
+        match self.byte_code.slot_names.iter().position(|slot_name| slot_name == name) {
+            Some(slot) => slots.values.get(slot)?.as_ref(),
+            None => vars.get(name),
+        }
+    }
+
+    fn frame_vars(&self) -> (&HashMap<String, Value>, &Slots) {
+        match self.byte_code.frames.last() {
+            Some(frame) => (&frame.vars, &frame.slots),
+            None => (&self.byte_code.vars, &self.byte_code.slots),
+        }
+    }
+
+    pub fn call_depth(&self) -> usize {
+        self.byte_code.frames.len()
+    }
+
+    pub fn instruction_ptr(&self) -> usize {
+        self.byte_code.instruction_ptr
+    }
+
+    /// Runs until `Return` and yields the value on top of the stack.
+    pub fn run(&mut self) -> Result<Value, RuntimeError> {
+        if self.engine == Engine::Threaded {
+            return threaded::run(self)
+        }
+        loop {
+            match self.step() {
+                Ok(Step::Return(result)) => return Ok(result),
+                Ok(Step::Continue) => {}
+                Err(kind) => return Err(self.error_report(kind)),
+            }
+        }
+    }
+
+    /// Like `run`, calling `tracer` after every instruction that was executed.
+    pub fn run_traced<T: Tracer + ?Sized>(&mut self, tracer: &mut T) -> Result<Value, RuntimeError> {
+        let wants_stack = tracer.wants_stack();
+        loop {
+            let offset = self.byte_code.instruction_ptr;
+            let call_depth = self.byte_code.frames.len();
+            let before = if wants_stack { self.byte_code.stack.clone() } else { Vec::new() };
+            let result = self.step();
+            // Running out of fuel or off the end of the program executes nothing.
+            let executed = !matches!(result, Err(InterpreterError::OutOfFuel));
+            if let Some(instruction) = self.byte_code.code.get(offset).filter(|_| executed) {
+                tracer.step(&TraceEvent {
+                    offset,
+                    instruction,
+                    stack_before: &before,
+                    stack_after: if wants_stack { &self.byte_code.stack } else { &[] },
+                    next: self.byte_code.instruction_ptr,
+                    call_depth,
+                    error: result.as_ref().err(),
+                });
+            }
+            match result {
+                Ok(Step::Return(result)) => return Ok(result),
+                Ok(Step::Continue) => {}
+                Err(kind) => return Err(self.error_report(kind)),
+            }
+        }
+    }
+
+    /// Describes `kind` as raised by the instruction at `instruction_ptr`. A failed `step`
+    /// leaves the stack as it was before the instruction, so this shows its operands.
+    pub fn error_report(&self, kind: InterpreterError) -> RuntimeError {
+        let byte_code = &self.byte_code;
+        let offset = byte_code.instruction_ptr;
+        let snapshot = byte_code.stack.len().saturating_sub(STACK_SNAPSHOT);
+        RuntimeError {
+            kind,
+            offset,
+            instruction: byte_code.code.get(offset).cloned(),
+            stack: byte_code.stack[snapshot..].to_vec(),
+            stack_depth: byte_code.stack.len(),
+            trail: byte_code.trail.iter().copied().collect(),
+        }
+    }
+
+    /// Executes the instruction at `instruction_ptr`. `Return` leaves the pointer where it is.
+    pub fn step(&mut self) -> Result<Step, InterpreterError> {
+        let limits = self.limits;
+        let arithmetic = self.arithmetic;
+        let host = &mut self.host;
+        let byte_code = &mut self.byte_code;
+        if limits.fuel.is_some_and(|fuel| byte_code.fuel_used >= fuel) {
+            return Err(InterpreterError::OutOfFuel)
+        }
+        let instruction = match byte_code.code.get(byte_code.instruction_ptr) {
+            Some(instruction) => instruction,
+            None => return Err(InterpreterError::BadInstructionOffset),
+        };
+        byte_code.fuel_used += 1;
+        let op = match instruction {
+            Instruction::Load(value) => {
+                byte_code.stack.push(Value::Int(*value));
+                Ok(())
+            }
+            Instruction::LoadBool(value) => {
+                byte_code.stack.push(Value::Bool(*value));
+                Ok(())
+            }
+            Instruction::LoadStr(text) => {
+                byte_code.stack.push(Value::Str(text.clone()));
+                Ok(())
+            }
+            Instruction::Write(var_name) => {
+                if byte_code.stack.is_empty() {
+                    return Err(InterpreterError::StackEmpty)
+                }
+                let (vars, slots) = match byte_code.frames.last_mut() {
+                    Some(frame) => (&mut frame.vars, &frame.slots),
+                    None => (&mut byte_code.vars, &byte_code.slots),
+                };
+                let is_new = !vars.contains_key(var_name);
+                if is_new && limits.max_vars.is_some_and(|max| vars.len() + slots.set >= max) {
+                    return Err(InterpreterError::TooManyVariables)
+                }
+                let val = byte_code.stack.pop().expect("checked above");
+                vars.insert(var_name.clone(), val);
+                Ok(())
+            },
+            Instruction::Read(var_name) => {
+                let vars = match byte_code.frames.last() {
+                    Some(frame) => &frame.vars,
+                    None => &byte_code.vars,
+                };
+                match vars.get(var_name) {
+                    Some(read_val) => {
+                        byte_code.stack.push(read_val.clone());
+                        Ok(())
+                    },
+                    _ => Err(InterpreterError::UndefinedVariable(var_name.clone())),
+                }
+            },
+            Instruction::WriteSlot(slot) => {
+                if byte_code.stack.is_empty() {
+                    return Err(InterpreterError::StackEmpty)
+                }
+                if *slot >= byte_code.slot_names.len() {
+                    return Err(InterpreterError::BadSlot)
+                }
+                let (vars, slots) = match byte_code.frames.last_mut() {
+                    Some(frame) => (&frame.vars, &mut frame.slots),
+                    None => (&byte_code.vars, &mut byte_code.slots),
+                };
+                if slots.values.len() <= *slot {
+                    slots.values.resize(byte_code.slot_names.len(), None);
+                }
+                let is_new = slots.values[*slot].is_none();
+                if is_new && limits.max_vars.is_some_and(|max| vars.len() + slots.set >= max) {
+                    return Err(InterpreterError::TooManyVariables)
+                }
+                slots.set += usize::from(is_new);
+                slots.values[*slot] = byte_code.stack.pop();
+                Ok(())
+            },
+            Instruction::ReadSlot(slot) => {
+                let slots = match byte_code.frames.last() {
+                    Some(frame) => &frame.slots,
+                    None => &byte_code.slots,
+                };
+                match slots.values.get(*slot) {
+                    Some(Some(read_val)) => {
+                        byte_code.stack.push(read_val.clone());
+                        Ok(())
+                    },
+                    _ => match byte_code.slot_names.get(*slot) {
+                        Some(name) => Err(InterpreterError::UndefinedVariable(name.clone())),
+                        None => Err(InterpreterError::BadSlot),
+                    },
+                }
+            },
+            Instruction::Add => handleMath!{byte_code, arithmetic, checked_add, wrapping_add, saturating_add},
+            Instruction::Sub => handleMath!{byte_code, arithmetic, checked_sub, wrapping_sub, saturating_sub},
+            Instruction::Mul => handleMath!{byte_code, arithmetic, checked_mul, wrapping_mul, saturating_mul},
+            Instruction::Div => handleDiv!{byte_code, arithmetic, checked_div, wrapping_div, saturating_div},
+            // The only overflowing remainder, `i64::MIN % -1`, is 0 when saturating too.
+            Instruction::Mod => handleDiv!{byte_code, arithmetic, checked_rem, wrapping_rem, wrapping_rem},
+            Instruction::Neg => {
+                match byte_code.stack.last() {
+                    Some(Value::Int(value)) => {
+                        let result = match arithmetic {
+                            ArithmeticMode::Checked => value.checked_neg(),
+                            ArithmeticMode::Wrapping => Some(value.wrapping_neg()),
+                            ArithmeticMode::Saturating => Some(value.saturating_neg()),
+                        };
+                        match result {
+                            Some(result) => {
+                                replace_top(&mut byte_code.stack, 1, Value::Int(result));
+                                Ok(())
+                            },
+                            _ => Err(InterpreterError::Overflow),
+                        }
+                    },
+                    Some(_) => Err(InterpreterError::TypeMismatch),
+                    _ => Err(InterpreterError::StackEmpty),
+                }
+            },
+            Instruction::And => handleBitwise!{byte_code, &},
+            Instruction::Or => handleBitwise!{byte_code, |},
+            Instruction::Xor => handleBitwise!{byte_code, ^},
+            Instruction::Not => {
+                let result = match byte_code.stack.last() {
+                    Some(Value::Int(value)) => Value::Int(!value),
+                    Some(Value::Bool(value)) => Value::Bool(!value),
+                    Some(_) => return Err(InterpreterError::TypeMismatch),
+                    _ => return Err(InterpreterError::StackEmpty),
+                };
+                replace_top(&mut byte_code.stack, 1, result);
+                Ok(())
+            },
+            Instruction::Shl => handleShift!{byte_code, checked_shl},
+            Instruction::Shr => handleShift!{byte_code, checked_shr},
+            Instruction::Dup => {
+                match byte_code.stack.last() {
+                    Some(top) => {
+                        byte_code.stack.push(top.clone());
+                        Ok(())
+                    },
+                    _ => Err(InterpreterError::StackEmpty),
+                }
+            },
+            Instruction::Swap => {
+                let len = byte_code.stack.len();
+                if len < 2 {
+                    return Err(InterpreterError::StackEmpty)
+                }
+                byte_code.stack.swap(len - 1, len - 2);
+                Ok(())
+            },
+            Instruction::Pop => {
+                match byte_code.stack.pop() {
+                    Some(_) => Ok(()),
+                    _ => Err(InterpreterError::StackEmpty),
+                }
+            },
+            Instruction::CompareEQ => handleCompare!{byte_code, ==},
+            Instruction::CompareNE => handleCompare!{byte_code, !=},
+            Instruction::CompareGT => handleCompare!{byte_code, >},
+            Instruction::CompareLT => handleCompare!{byte_code, <},
+            Instruction::CompareGTE => handleCompare!{byte_code, >=},
+            Instruction::CompareLTE => handleCompare!{byte_code, <=},
+            Instruction::Jump(offset) => {
+                if *offset >= byte_code.code.len() {
+                    return Err(InterpreterError::BadInstructionOffset)
+                }
+                record(&mut byte_code.trail, byte_code.instruction_ptr, *offset);
+                byte_code.instruction_ptr = *offset;
+                Ok(())
+            },
+            Instruction::JumpIf(offset) => {
+                let in_range = *offset < byte_code.code.len();
+                let taken = match byte_code.stack.last() {
+                    Some(Value::Bool(false)) | Some(Value::Int(0)) => true,
+                    Some(Value::Bool(true)) | Some(Value::Int(_)) => false,
+                    Some(_) => return Err(InterpreterError::TypeMismatch),
+                    _ => return Err(InterpreterError::StackEmpty),
+                };
+                if taken && !in_range {
+                    return Err(InterpreterError::BadInstructionOffset)
+                }
+                byte_code.stack.pop();
+                if taken {
+                    record(&mut byte_code.trail, byte_code.instruction_ptr, *offset);
+                    byte_code.instruction_ptr = *offset;
+                }
+                Ok(())
+            },
+            Instruction::Call(offset) => {
+                if *offset >= byte_code.code.len() {
+                    return Err(InterpreterError::BadInstructionOffset)
+                }
+                if byte_code.frames.len() >= limits.max_call_depth {
+                    return Err(InterpreterError::CallStackOverflow)
+                }
+                byte_code.frames.push(Frame {
+                    return_ptr: byte_code.instruction_ptr,
+                    vars: HashMap::new(),
+                    slots: Slots::default(),
+                });
+                record(&mut byte_code.trail, byte_code.instruction_ptr, *offset);
+                byte_code.instruction_ptr = *offset;
+                Ok(())
+            },
+            Instruction::Ret => {
+                match byte_code.frames.pop() {
+                    Some(frame) => {
+                        record(&mut byte_code.trail, byte_code.instruction_ptr, frame.return_ptr);
+                        byte_code.instruction_ptr = frame.return_ptr;
+                        Ok(())
+                    },
+                    _ => Err(InterpreterError::CallStackEmpty),
+                }
+            },
+            Instruction::CallHost(name, argc) => {
+                match host.arity(name) {
+                    None => return Err(InterpreterError::UnknownHostFunction),
+                    Some(arity) if arity != *argc => return Err(InterpreterError::ArityMismatch),
+                    _ => {}
+                }
+                if *argc > byte_code.stack.len() {
+                    return Err(InterpreterError::StackEmpty)
+                }
+                // Arguments stay on the stack until the call succeeds.
+                let base = byte_code.stack.len() - argc;
+                let result = host.call(name, &byte_code.stack[base..])?;
+                byte_code.stack.truncate(base);
+                byte_code.stack.push(result);
+                Ok(())
+            },
+            Instruction::NewArray(len) => {
+                if *len > byte_code.stack.len() {
+                    return Err(InterpreterError::StackEmpty)
+                }
+                let values = byte_code.stack.split_off(byte_code.stack.len() - len);
+                byte_code.stack.push(Value::Array(values));
+                Ok(())
+            },
+            Instruction::Concat => {
+                match top_pair(&byte_code.stack)? {
+                    (Value::Str(_), Value::Str(_)) | (Value::Array(_), Value::Array(_)) => {}
+                    _ => return Err(InterpreterError::TypeMismatch),
+                }
+                let rhs = byte_code.stack.pop().expect("checked above");
+                match (byte_code.stack.last_mut(), rhs) {
+                    (Some(Value::Str(lhs)), Value::Str(rhs)) => lhs.push_str(&rhs),
+                    (Some(Value::Array(lhs)), Value::Array(rhs)) => lhs.extend(rhs),
+                    _ => unreachable!("operand types were checked above"),
+                }
+                Ok(())
+            },
+            Instruction::Index => {
+                let element = match top_pair(&byte_code.stack)? {
+                    (Value::Array(values), Value::Int(index)) => {
+                        usize::try_from(*index).ok().and_then(|index| values.get(index)).cloned()
+                    },
+                    // Strings index by character and yield a one character string.
+                    (Value::Str(text), Value::Int(index)) => {
+                        usize::try_from(*index).ok()
+                            .and_then(|index| text.chars().nth(index))
+                            .map(|ch| Value::Str(ch.into()))
+                    },
+                    _ => return Err(InterpreterError::TypeMismatch),
+                };
+                match element {
+                    Some(element) => {
+                        replace_top(&mut byte_code.stack, 2, element);
+                        Ok(())
+                    },
+                    _ => Err(InterpreterError::IndexOutOfBounds),
+                }
+            },
+            Instruction::Len => {
+                let len = match byte_code.stack.last() {
+                    Some(Value::Str(text)) => text.chars().count(),
+                    Some(Value::Array(values)) => values.len(),
+                    Some(_) => return Err(InterpreterError::TypeMismatch),
+                    _ => return Err(InterpreterError::StackEmpty),
+                };
+                replace_top(&mut byte_code.stack, 1, Value::Int(len as i64));
+                Ok(())
+            },
+            Instruction::Return => {
+                return match byte_code.stack.pop() {
+                    Some(result) => {
+                        store_slots(byte_code);
+                        Ok(Step::Return(result))
+                    },
+                    _ => Err(InterpreterError::StackEmpty),
+                }
+            },
+        };
+
+        op?;
+
+        if limits.max_stack.is_some_and(|max| byte_code.stack.len() > max) {
+            return Err(InterpreterError::StackOverflow)
+        }
+
+        byte_code.instruction_ptr = byte_code.instruction_ptr.checked_add(1).ok_or(InterpreterError::BadInstructionOffset)?;
+        Ok(Step::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, interpret_both as interpret, Instruction::*};
+
+    #[test]
+    fn vars_survive_load_program() {
+        let mut vm = Vm::new();
+        vm.load_program(vec![Load(41), Write("x".into()), Load(0), Return]);
+        assert_eq!(vm.run().unwrap(), 0);
+        vm.load_program(vec![Read("x".into()), Load(1), Add, Return]);
+        assert_eq!(vm.run().unwrap(), 42);
+        assert_eq!(vm.vars().get("x"), Some(&41.into()));
+    }
+
+    #[test]
+    fn step_exposes_state() {
+        let mut vm = Vm::new();
+        vm.load_program(vec![Load(4), Load(2), Jump(3), Load(9), Sub, Return]);
+        assert_eq!(vm.step().unwrap(), Step::Continue);
+        assert_eq!(vm.step().unwrap(), Step::Continue);
+        assert_eq!(vm.stack(), &[4, 2]);
+        assert_eq!(vm.step().unwrap(), Step::Continue);
+        assert_eq!(vm.instruction_ptr(), 4);
+        assert_eq!(vm.step().unwrap(), Step::Continue);
+        assert_eq!(vm.step().unwrap(), Step::Return(2.into()));
+        assert_eq!(vm.instruction_ptr(), 5);
+    }
+
+    #[test]
+    fn reset_clears_vars() {
+        let mut vm = Vm::new();
+        vm.load_program(vec![Load(1), Write("x".into()), Read("x".into()), Return]);
+        assert_eq!(vm.run().unwrap(), 1);
+        vm.reset();
+        assert!(vm.vars().is_empty());
+        assert_eq!(vm.instruction_ptr(), 0);
+        assert_eq!(vm.run().unwrap(), 1);
+    }
+
+    #[test]
+    fn extended_programs_keep_stack_and_vars() {
+        for engine in [Engine::Basic, Engine::Threaded] {
+            let mut vm = Vm::with_engine(engine);
+            vm.extend_program(vec![Load(5)]);
+            vm.step().unwrap();
+            vm.extend_program(crate::lang::compile_entry("x = 0; while x < 4 { x += 1 }").unwrap());
+            assert_eq!(vm.instruction_ptr(), 1);
+            while vm.instruction_ptr() < vm.code().len() {
+                vm.step().unwrap();
+            }
+            assert_eq!((vm.stack(), &vm.vars()["x"]), (&[Value::Int(5)][..], &Value::Int(4)));
+            vm.extend_program(vec![Read("x".into()), Add, Return]);
+            assert_eq!(vm.run().unwrap(), 9);
+        }
+    }
+
+    #[test]
+    fn recursive_factorial() {
+        let program = asm::parse("
+                    load 5
+                    call fact
+                    return
+            fact:   write n
+                    read n
+                    load 1
+                    cmpgt
+                    jumpif base
+                    read n
+                    read n
+                    load 1
+                    sub
+                    call fact
+                    mul
+                    ret
+            base:   load 1
+                    ret
+        ").unwrap();
+        assert_eq!(interpret(program).unwrap(), 120);
+    }
+
+    #[test]
+    fn frames_have_their_own_vars() {
+        let program = asm::parse("
+                    load 48
+                    write a
+                    load 18
+                    load 48
+                    call gcd
+                    read a
+                    add
+                    return
+            gcd:    write a
+                    write b
+            loop:   read b
+                    load 0
+                    cmpne
+                    jumpif done
+                    read b
+                    read a
+                    read a
+                    read b
+                    div
+                    read b
+                    mul
+                    sub
+                    write b
+                    write a
+                    jump loop
+            done:   read a
+                    ret
+        ").unwrap();
+        let mut vm = Vm::new();
+        vm.load_program(program);
+        assert_eq!(vm.run().unwrap(), 6 + 48);
+        assert_eq!(vm.vars().len(), 1);
+        assert_eq!(vm.call_depth(), 0);
+    }
+
+    #[test]
+    fn call_stack_limits() {
+        let program = asm::parse("load 0\nf: call f").unwrap();
+        assert_eq!(interpret(program).unwrap_err().kind, InterpreterError::CallStackOverflow);
+        assert_eq!(interpret(vec![Ret]).unwrap_err().kind, InterpreterError::CallStackEmpty);
+        assert_eq!(interpret(vec![Call(3), Return]).unwrap_err().kind, InterpreterError::BadInstructionOffset);
+    }
+
+    #[test]
+    fn fuel_stops_infinite_loops() {
+        let mut vm = Vm::with_limits(Limits { fuel: Some(1000), ..Limits::default() });
+        vm.load_program(vec![Load(0), Jump(0), Return]);
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::OutOfFuel);
+        assert_eq!(vm.fuel_consumed(), 1000);
+
+        vm.load_program(vec![Load(4), Jump(2), Load(5), Load(7), Add, Return]);
+        assert_eq!(vm.run(), Ok(Value::Int(11)));
+        assert_eq!(vm.fuel_consumed(), 5);
+    }
+
+    #[test]
+    fn raising_fuel_resumes() {
+        let mut vm = Vm::with_limits(Limits { fuel: Some(3), ..Limits::default() });
+        vm.load_program(vec![Load(1), Load(2), Add, Load(3), Mul, Return]);
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::OutOfFuel);
+        assert_eq!(vm.stack(), &[3]);
+        vm.set_limits(Limits { fuel: Some(6), ..vm.limits() });
+        assert_eq!(vm.run(), Ok(Value::Int(9)));
+    }
+
+    #[test]
+    fn stack_and_variable_limits() {
+        let limits = Limits { max_stack: Some(2), max_vars: Some(1), max_call_depth: 4, ..Limits::default() };
+        let mut vm = Vm::with_limits(limits);
+        vm.load_program(vec![Load(1), Load(2), Load(3), Return]);
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::StackOverflow);
+
+        vm.load_program(vec![Load(1), Write("x".into()), Load(2), Write("x".into()),
+            Load(3), Write("y".into()), Return]);
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::TooManyVariables);
+        assert_eq!(vm.instruction_ptr(), 5);
+
+        vm.load_program(asm::parse("load 0\nf: call f").unwrap());
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::CallStackOverflow);
+        assert_eq!(vm.call_depth(), 4);
+    }
+
+    #[test]
+    fn overflow_is_an_error_by_default() {
+        assert_eq!(interpret(vec![Load(i64::MAX), Load(1), Add, Return]).unwrap_err().kind, InterpreterError::Overflow);
+        assert_eq!(interpret(vec![Load(i64::MIN), Load(1), Sub, Return]).unwrap_err().kind, InterpreterError::Overflow);
+        assert_eq!(interpret(vec![Load(i64::MAX), Load(2), Mul, Return]).unwrap_err().kind, InterpreterError::Overflow);
+        assert_eq!(interpret(vec![Load(i64::MIN), Load(-1), Div, Return]).unwrap_err().kind, InterpreterError::Overflow);
+        assert_eq!(interpret(vec![Load(i64::MIN), Load(0), Div, Return]).unwrap_err().kind, InterpreterError::DivideByZero);
+    }
+
+    #[test]
+    fn zero_divisors_need_both_operands() {
+        for op in [Div, Mod] {
+            assert_eq!(interpret(vec![Load(0), op.clone(), Return]).unwrap_err().kind, InterpreterError::StackEmpty);
+            assert_eq!(interpret(vec![LoadBool(true), Load(0), op.clone(), Return]).unwrap_err().kind, InterpreterError::TypeMismatch);
+            assert_eq!(interpret(vec![Load(1), Load(0), op, Return]).unwrap_err().kind, InterpreterError::DivideByZero);
+        }
+    }
+
+    #[test]
+    fn wrapping_and_saturating_modes() {
+        let run = |mode, code| {
+            let mut vm = Vm::with_arithmetic(mode);
+            vm.load_program(code);
+            vm.run()
+        };
+        let wrapping = ArithmeticMode::Wrapping;
+        assert_eq!(run(wrapping, vec![Load(i64::MAX), Load(1), Add, Return]), Ok(Value::Int(i64::MIN)));
+        assert_eq!(run(wrapping, vec![Load(i64::MIN), Load(-1), Div, Return]), Ok(Value::Int(i64::MIN)));
+        assert_eq!(run(wrapping, vec![Load(1), Load(0), Div, Return]).unwrap_err().kind, InterpreterError::DivideByZero);
+
+        let saturating = ArithmeticMode::Saturating;
+        assert_eq!(run(saturating, vec![Load(i64::MAX), Load(1), Add, Return]), Ok(Value::Int(i64::MAX)));
+        assert_eq!(run(saturating, vec![Load(i64::MIN), Load(1), Sub, Return]), Ok(Value::Int(i64::MIN)));
+        assert_eq!(run(saturating, vec![Load(i64::MIN), Load(-1), Div, Return]), Ok(Value::Int(i64::MAX)));
+        assert_eq!(run(saturating, vec![Load(-7), Load(2), Div, Return]), Ok(Value::Int(-3)));
+    }
+
+    #[test]
+    fn strings_and_arrays() {
+        let hello = vec![LoadStr("hello, ".into()), LoadStr("world".into()), Concat, Return];
+        assert_eq!(interpret(hello), Ok(Value::from("hello, world")));
+        let array = vec![Load(1), LoadBool(true), LoadStr("x".into()), NewArray(3), Write("xs".into()),
+            Read("xs".into()), Read("xs".into()), Concat, Len, Read("xs".into()), Load(2), Index, NewArray(2), Return];
+        assert_eq!(interpret(array), Ok(Value::Array(vec![6.into(), "x".into()])));
+        assert_eq!(interpret(vec![LoadStr("héllo".into()), Load(1), Index, Return]), Ok(Value::from("é")));
+        assert_eq!(interpret(vec![Load(1), Load(2), CompareLT, Return]), Ok(Value::Bool(true)));
+        assert_eq!(interpret(vec![LoadStr("b".into()), LoadStr("a".into()), CompareGT, Return]), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn type_mismatches() {
+        assert_eq!(interpret(vec![Load(1), LoadStr("1".into()), Add, Return]).unwrap_err().kind, InterpreterError::TypeMismatch);
+        assert_eq!(interpret(vec![Load(1), LoadBool(true), CompareEQ, Return]).unwrap_err().kind, InterpreterError::TypeMismatch);
+        assert_eq!(interpret(vec![Load(1), Load(2), Concat, Return]).unwrap_err().kind, InterpreterError::TypeMismatch);
+        assert_eq!(interpret(vec![Load(5), Len, Return]).unwrap_err().kind, InterpreterError::TypeMismatch);
+        assert_eq!(interpret(vec![LoadStr("".into()), JumpIf(0), Return]).unwrap_err().kind, InterpreterError::TypeMismatch);
+        assert_eq!(interpret(vec![NewArray(0), LoadBool(false), Index, Return]).unwrap_err().kind, InterpreterError::TypeMismatch);
+        assert_eq!(interpret(vec![NewArray(0), Load(-1), Index, Return]).unwrap_err().kind, InterpreterError::IndexOutOfBounds);
+        assert_eq!(interpret(vec![Load(1), NewArray(2), Return]).unwrap_err().kind, InterpreterError::StackEmpty);
+    }
+
+    #[test]
+    fn load_verified_rejects_errors() {
+        let mut vm = Vm::new();
+        assert!(vm.load_verified(vec![Load(1), JumpIf(7), Return]).is_err());
+        assert!(vm.code().is_empty());
+        assert_eq!(vm.load_verified(vec![Read("x".into()), Return]).unwrap().len(), 1);
+        assert_eq!(vm.code().len(), 2);
+    }
+
+    #[test]
+    fn running_off_the_end_is_an_error() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::BadInstructionOffset);
+        vm.load_program(vec![Load(0), JumpIf(7), Return]);
+        assert_eq!(vm.run().unwrap_err().kind, InterpreterError::BadInstructionOffset);
+    }
+}
@@ -0,0 +1,439 @@
+// `Engine::Threaded`: the program is decoded once into a table with one op per instruction
+// offset, and common sequences are fused into single ops. The compiler's `i += 1` is
+// `Read Load Add Write`, a loop test is `Read Load CompareLT JumpIf`, each a single op here.
+//
+// A fused op either runs all of its instructions or none of them. Everything that could make
+// one of them fail (fuel, stack room, operand types, overflow, undefined variables, variable
+// limits) is checked before anything changes, and if any check fails only the first
+// instruction runs through `Vm::step`. Errors are therefore raised by `step` alone, with the
+// same stack, fuel and trail as the basic engine, and jumping into the middle of a fused
+// sequence simply starts at the op decoded for that offset.
+
+use std::collections::HashMap;
+
+use super::{record, Limits, Step, Vm};
+use crate::{ArithmeticMode, ByteCode, Instruction, RuntimeError, Slots, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Operand {
+    // The value on top of the stack, for the right hand side the one below it.
+    Top,
+    Const(i64),
+    Var(String),
+    Slot(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+// What happens to the result of a `Binary`.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Then {
+    Push,
+    // `Write` or `WriteSlot`.
+    Store(Operand),
+    JumpIf(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Op {
+    // No fast path, always runs through `Vm::step`.
+    Step,
+    // `Load`, `Read` or `ReadSlot`.
+    Push(Operand),
+    // `Write` or `WriteSlot`.
+    Store(Operand),
+    // A push followed by a store.
+    Move { from: Operand, to: Operand },
+    // Jumps whose target is inside the program.
+    Jump(usize),
+    JumpIf(usize),
+    // An operator on two ints, its operands pushed just before it unless they are `Top`.
+    // `len` counts the fused instructions.
+    Binary { lhs: Operand, rhs: Operand, op: BinOp, then: Then, len: usize },
+}
+
+fn source(instruction: Option<&Instruction>) -> Option<Operand> {
+    match instruction? {
+        Instruction::Load(value) => Some(Operand::Const(*value)),
+        Instruction::Read(name) => Some(Operand::Var(name.clone())),
+        Instruction::ReadSlot(slot) => Some(Operand::Slot(*slot)),
+        _ => None,
+    }
+}
+
+fn destination(instruction: Option<&Instruction>) -> Option<Operand> {
+    match instruction? {
+        Instruction::Write(name) => Some(Operand::Var(name.clone())),
+        Instruction::WriteSlot(slot) => Some(Operand::Slot(*slot)),
+        _ => None,
+    }
+}
+
+fn bin_op(instruction: Option<&Instruction>) -> Option<BinOp> {
+    Some(match instruction? {
+        Instruction::Add => BinOp::Add,
+        Instruction::Sub => BinOp::Sub,
+        Instruction::Mul => BinOp::Mul,
+        Instruction::Div => BinOp::Div,
+        Instruction::Mod => BinOp::Mod,
+        Instruction::And => BinOp::And,
+        Instruction::Or => BinOp::Or,
+        Instruction::Xor => BinOp::Xor,
+        Instruction::Shl => BinOp::Shl,
+        Instruction::Shr => BinOp::Shr,
+        Instruction::CompareEQ => BinOp::Eq,
+        Instruction::CompareNE => BinOp::Ne,
+        Instruction::CompareLT => BinOp::Lt,
+        Instruction::CompareGT => BinOp::Gt,
+        Instruction::CompareLTE => BinOp::Le,
+        Instruction::CompareGTE => BinOp::Ge,
+        _ => return None,
+    })
+}
+
+/// One op per instruction offset, the longest fused sequence starting there.
+pub(super) fn decode(code: &[Instruction]) -> Vec<Op> {
+    let in_range = |target: &usize| *target < code.len();
+    (0..code.len()).map(|offset| {
+        let at = |index: usize| code.get(offset + index);
+        let binary = |lhs, rhs, op, len: usize| {
+            let then = match at(len) {
+                Some(Instruction::JumpIf(target)) if in_range(target) => Some(Then::JumpIf(*target)),
+                next => destination(next).map(Then::Store),
+            };
+            match then {
+                Some(then) => Op::Binary { lhs, rhs, op, then, len: len + 1 },
+                None => Op::Binary { lhs, rhs, op, then: Then::Push, len },
+            }
+        };
+        if let (Some(lhs), Some(rhs), Some(op)) = (source(at(0)), source(at(1)), bin_op(at(2))) {
+            return binary(lhs, rhs, op, 3)
+        }
+        if let (Some(rhs), Some(op)) = (source(at(0)), bin_op(at(1))) {
+            return binary(Operand::Top, rhs, op, 2)
+        }
+        if let Some(op) = bin_op(at(0)) {
+            return binary(Operand::Top, Operand::Top, op, 1)
+        }
+        if let (Some(from), Some(to)) = (source(at(0)), destination(at(1))) {
+            return Op::Move { from, to }
+        }
+        match &code[offset] {
+            Instruction::Jump(target) if in_range(target) => Op::Jump(*target),
+            Instruction::JumpIf(target) if in_range(target) => Op::JumpIf(*target),
+            instruction => source(Some(instruction))
+                .map(Op::Push)
+                .or_else(|| destination(Some(instruction)).map(Op::Store))
+                .unwrap_or(Op::Step),
+        }
+    }).collect()
+}
+
+// Mirrors `handleMath!` and friends for two ints, `None` where those would fail.
+fn eval(op: BinOp, lhs: i64, rhs: i64, arithmetic: ArithmeticMode) -> Option<Value> {
+    let math = |checked: fn(i64, i64) -> Option<i64>, wrapping: fn(i64, i64) -> i64, saturating: fn(i64, i64) -> i64| {
+        match arithmetic {
+            ArithmeticMode::Checked => checked(lhs, rhs),
+            ArithmeticMode::Wrapping => Some(wrapping(lhs, rhs)),
+            ArithmeticMode::Saturating => Some(saturating(lhs, rhs)),
+        }
+    };
+    let shift = |checked: fn(i64, u32) -> Option<i64>| u32::try_from(rhs).ok().and_then(|rhs| checked(lhs, rhs));
+    Some(match op {
+        BinOp::Add => Value::Int(math(i64::checked_add, i64::wrapping_add, i64::saturating_add)?),
+        BinOp::Sub => Value::Int(math(i64::checked_sub, i64::wrapping_sub, i64::saturating_sub)?),
+        BinOp::Mul => Value::Int(math(i64::checked_mul, i64::wrapping_mul, i64::saturating_mul)?),
+        BinOp::Div | BinOp::Mod if rhs == 0 => return None,
+        BinOp::Div => Value::Int(math(i64::checked_div, i64::wrapping_div, i64::saturating_div)?),
+        BinOp::Mod => Value::Int(math(i64::checked_rem, i64::wrapping_rem, i64::wrapping_rem)?),
+        BinOp::And => Value::Int(lhs & rhs),
+        BinOp::Or => Value::Int(lhs | rhs),
+        BinOp::Xor => Value::Int(lhs ^ rhs),
+        BinOp::Shl => Value::Int(shift(i64::checked_shl)?),
+        BinOp::Shr => Value::Int(shift(i64::checked_shr)?),
+        BinOp::Eq => Value::Bool(lhs == rhs),
+        BinOp::Ne => Value::Bool(lhs != rhs),
+        BinOp::Lt => Value::Bool(lhs < rhs),
+        BinOp::Gt => Value::Bool(lhs > rhs),
+        BinOp::Le => Value::Bool(lhs <= rhs),
+        BinOp::Ge => Value::Bool(lhs >= rhs),
+    })
+}
+
+fn locals(byte_code: &ByteCode) -> (&HashMap<String, Value>, &Slots) {
+    match byte_code.frames.last() {
+        Some(frame) => (&frame.vars, &frame.slots),
+        None => (&byte_code.vars, &byte_code.slots),
+    }
+}
+
+fn locals_mut(byte_code: &mut ByteCode) -> (&mut HashMap<String, Value>, &mut Slots) {
+    match byte_code.frames.last_mut() {
+        Some(frame) => (&mut frame.vars, &mut frame.slots),
+        None => (&mut byte_code.vars, &mut byte_code.slots),
+    }
+}
+
+// What `Read`, `ReadSlot` or `Load` would push, `None` if they would fail. Not for `Top`.
+fn fetch(byte_code: &ByteCode, operand: &Operand) -> Option<Value> {
+    let (vars, slots) = locals(byte_code);
+    match operand {
+        Operand::Const(value) => Some(Value::Int(*value)),
+        Operand::Var(name) => vars.get(name).cloned(),
+        Operand::Slot(slot) => slots.values.get(*slot)?.clone(),
+        Operand::Top => None,
+    }
+}
+
+// Like `fetch` for an int, reading `Top` from `depth` values below the top of the stack.
+fn int(byte_code: &ByteCode, operand: &Operand, depth: usize) -> Option<i64> {
+    let (vars, slots) = locals(byte_code);
+    let value = match operand {
+        Operand::Const(value) => return Some(*value),
+        Operand::Var(name) => vars.get(name)?,
+        Operand::Slot(slot) => slots.values.get(*slot)?.as_ref()?,
+        Operand::Top => byte_code.stack.len().checked_sub(depth + 1).map(|index| &byte_code.stack[index])?,
+    };
+    match value {
+        Value::Int(value) => Some(*value),
+        _ => None,
+    }
+}
+
+// Whether `Write` or `WriteSlot` into `to` would succeed.
+fn can_store(byte_code: &ByteCode, limits: &Limits, to: &Operand) -> bool {
+    let (vars, slots) = locals(byte_code);
+    let is_new = match to {
+        Operand::Var(name) => !vars.contains_key(name),
+        Operand::Slot(slot) if *slot < byte_code.slot_names.len() => slots.values.get(*slot).is_none_or(Option::is_none),
+        _ => return false,
+    };
+    !is_new || limits.max_vars.is_none_or(|max| vars.len() + slots.set < max)
+}
+
+fn store(byte_code: &mut ByteCode, to: &Operand, value: Value) {
+    let names = byte_code.slot_names.len();
+    let (vars, slots) = locals_mut(byte_code);
+    match to {
+        Operand::Var(name) => match vars.get_mut(name) {
+            Some(old) => *old = value,
+            None => {
+                vars.insert(name.clone(), value);
+            }
+        },
+        Operand::Slot(slot) => {
+            if slots.values.len() <= *slot {
+                slots.values.resize(names, None);
+            }
+            slots.set += usize::from(slots.values[*slot].is_none());
+            slots.values[*slot] = Some(value);
+        }
+        Operand::Const(_) | Operand::Top => unreachable!("not a destination"),
+    }
+}
+
+// Runs `op` if none of its instructions can fail, `false` if it didn't.
+fn execute(op: &Op, byte_code: &mut ByteCode, limits: &Limits, arithmetic: ArithmeticMode) -> bool {
+    let fuel = |count: usize| limits.fuel.is_none_or(|fuel| byte_code.fuel_used + count as u64 <= fuel);
+    let room = |pushed: usize| limits.max_stack.is_none_or(|max| byte_code.stack.len() + pushed <= max);
+    let offset = byte_code.instruction_ptr;
+    // Instructions run and the offset to continue from. Like `step`, a transfer to `target`
+    // resumes at `target + 1`.
+    let (len, next) = match op {
+        Op::Step => return false,
+        Op::Push(from) => {
+            let Some(value) = fetch(byte_code, from).filter(|_| fuel(1) && room(1)) else { return false };
+            byte_code.stack.push(value);
+            (1, offset + 1)
+        }
+        Op::Store(to) => {
+            if byte_code.stack.is_empty() || !fuel(1) || !can_store(byte_code, limits, to) {
+                return false
+            }
+            let value = byte_code.stack.pop().expect("checked above");
+            store(byte_code, to, value);
+            (1, offset + 1)
+        }
+        Op::Move { from, to } => {
+            if !fuel(2) || !room(1) || !can_store(byte_code, limits, to) {
+                return false
+            }
+            let Some(value) = fetch(byte_code, from) else { return false };
+            store(byte_code, to, value);
+            (2, offset + 2)
+        }
+        Op::Jump(target) => {
+            if !fuel(1) {
+                return false
+            }
+            record(&mut byte_code.trail, offset, *target);
+            (1, target + 1)
+        }
+        Op::JumpIf(target) => {
+            let taken = match byte_code.stack.last() {
+                Some(Value::Bool(value)) => !value,
+                Some(Value::Int(value)) => *value == 0,
+                _ => return false,
+            };
+            if !fuel(1) {
+                return false
+            }
+            byte_code.stack.pop();
+            if !taken {
+                return finish(byte_code, 1, offset + 1)
+            }
+            record(&mut byte_code.trail, offset, *target);
+            (1, target + 1)
+        }
+        Op::Binary { lhs, rhs, op, then, len } => {
+            // Operands on the stack, the rest is pushed by the fused instructions first.
+            let popped = usize::from(*lhs == Operand::Top) + usize::from(*rhs == Operand::Top);
+            if !fuel(*len) || !room(2 - popped) {
+                return false
+            }
+            let (Some(left), Some(right)) = (int(byte_code, lhs, popped.saturating_sub(1)), int(byte_code, rhs, 0)) else {
+                return false
+            };
+            let Some(result) = eval(*op, left, right, arithmetic) else { return false };
+            if let Then::Store(to) = then {
+                if !can_store(byte_code, limits, to) {
+                    return false
+                }
+            }
+            let base = byte_code.stack.len() - popped;
+            byte_code.stack.truncate(base);
+            match then {
+                Then::Push => byte_code.stack.push(result),
+                Then::Store(to) => store(byte_code, to, result),
+                Then::JumpIf(target) if matches!(result, Value::Int(0) | Value::Bool(false)) => {
+                    record(&mut byte_code.trail, offset + len - 1, *target);
+                    return finish(byte_code, *len, target + 1)
+                }
+                Then::JumpIf(_) => {}
+            }
+            (*len, offset + len)
+        }
+    };
+    finish(byte_code, len, next)
+}
+
+fn finish(byte_code: &mut ByteCode, len: usize, next: usize) -> bool {
+    byte_code.fuel_used += len as u64;
+    byte_code.instruction_ptr = next;
+    true
+}
+
+/// `Vm::run` for `Engine::Threaded`.
+pub(super) fn run(vm: &mut Vm) -> Result<Value, RuntimeError> {
+    let ops = vm.ops.take().unwrap_or_else(|| decode(&vm.byte_code.code));
+    let result = loop {
+        let offset = vm.byte_code.instruction_ptr;
+        if ops.get(offset).is_some_and(|op| execute(op, &mut vm.byte_code, &vm.limits, vm.arithmetic)) {
+            continue
+        }
+        match vm.step() {
+            Ok(Step::Return(result)) => break Ok(result),
+            Ok(Step::Continue) => {}
+            Err(kind) => break Err(vm.error_report(kind)),
+        }
+    };
+    vm.ops = Some(ops);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::link;
+    use crate::{asm, lang, Engine, Instruction::*};
+
+    const FACT: &str = "
+                load 20
+                call fact
+                return
+        fact:   write n
+                read n
+                load 1
+                cmpgt
+                jumpif base
+                read n
+                read n
+                load 1
+                sub
+                call fact
+                mul
+                ret
+        base:   load 1
+                ret
+    ";
+
+    #[test]
+    fn fuses_loop_bodies_and_tests() {
+        let i = || "i".to_string();
+        let code = vec![Load(0), Write(i()), Read(i()), Load(3), CompareLT, JumpIf(10), Read(i()), Load(1), Add,
+            Write(i()), Jump(1), Read(i()), Return, Jump(14)];
+        assert_eq!(decode(&code), vec![
+            Op::Move { from: Operand::Const(0), to: Operand::Var(i()) },
+            Op::Store(Operand::Var(i())),
+            Op::Binary { lhs: Operand::Var(i()), rhs: Operand::Const(3), op: BinOp::Lt, then: Then::JumpIf(10), len: 4 },
+            Op::Binary { lhs: Operand::Top, rhs: Operand::Const(3), op: BinOp::Lt, then: Then::JumpIf(10), len: 3 },
+            Op::Binary { lhs: Operand::Top, rhs: Operand::Top, op: BinOp::Lt, then: Then::JumpIf(10), len: 2 },
+            Op::JumpIf(10),
+            Op::Binary { lhs: Operand::Var(i()), rhs: Operand::Const(1), op: BinOp::Add, then: Then::Store(Operand::Var(i())), len: 4 },
+            Op::Binary { lhs: Operand::Top, rhs: Operand::Const(1), op: BinOp::Add, then: Then::Store(Operand::Var(i())), len: 3 },
+            Op::Binary { lhs: Operand::Top, rhs: Operand::Top, op: BinOp::Add, then: Then::Store(Operand::Var(i())), len: 2 },
+            Op::Store(Operand::Var(i())),
+            Op::Jump(1),
+            Op::Push(Operand::Var(i())),
+            Op::Step,
+            // Out of range, left for `step` to report.
+            Op::Step,
+        ]);
+    }
+
+    #[test]
+    fn limits_stop_both_engines_at_the_same_instruction() {
+        let mut programs = vec![asm::parse(FACT).unwrap()];
+        for source in ["i = 0; while i < 6 { i += 1; j = i * 2 } return i + j", "a = [1]; b = 2; c = a ++ [b]; return len(c)"] {
+            let code = lang::compile(source).unwrap();
+            programs.push(link(&code).code);
+            programs.push(code);
+        }
+        let mut engines = [Vm::new(), Vm::with_engine(Engine::Threaded)];
+        for code in &programs {
+            for fuel in 0..80 {
+                for max_stack in 0..5 {
+                    for max_vars in 0..5 {
+                        let limits = Limits { fuel: Some(fuel), max_stack: Some(max_stack), max_vars: Some(max_vars), max_call_depth: 8 };
+                        let [basic, threaded] = engines.each_mut().map(|vm| {
+                            vm.set_limits(limits);
+                            vm.set_arithmetic(ArithmeticMode::Wrapping);
+                            vm.load_program(code.clone());
+                            let result = vm.run();
+                            (result, vm.fuel_consumed(), vm.stack().to_vec(), vm.vars().clone(), vm.call_depth())
+                        });
+                        assert_eq!(threaded, basic, "{:?} {:?}", limits, code);
+                    }
+                }
+            }
+        }
+    }
+}